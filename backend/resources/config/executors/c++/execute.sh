@@ -5,7 +5,7 @@ SRC_FILE="$1"
 OUT_DIR="$(dirname "$SRC_FILE")"
 
 if [[ -z "$SRC_FILE" || -z "$OUT_DIR" ]]; then
-    # echo "Usage: $0 <source.cpp>"
+    # echo "Usage: $0 <source.cpp> [args...]"
     exit 1
 fi
 
@@ -16,7 +16,14 @@ BIN_PATH="$OUT_DIR/$BASENAME"
 CPP_FILE="$OUT_DIR/$BASENAME.cpp"
 mv "$SRC_FILE" "$CPP_FILE"
 
-g++ -o "$BIN_PATH" "$CPP_FILE" > /dev/null
+# Other files of the unit, declared with file= and used by the block, are next to it
+SOURCES=("$CPP_FILE")
+while IFS= read -r FILE; do
+    if [[ "$FILE" == *.cpp || "$FILE" == *.cc || "$FILE" == *.cxx ]]; then
+        SOURCES+=("$OUT_DIR/$FILE")
+    fi
+done <<< "$TANGLIT_UNIT_FILES"
 
-cd "$OUT_DIR"
-"./$BASENAME"
+g++ -o "$BIN_PATH" "${SOURCES[@]}" > /dev/null
+
+"$BIN_PATH" "${@:2}"
@@ -5,7 +5,7 @@ SRC_FILE="$1"
 OUT_DIR="$(dirname "$SRC_FILE")"
 
 if [[ -z "$SRC_FILE" || -z "$OUT_DIR" ]]; then
-    # echo "Usage: $0 <source.c>"
+    # echo "Usage: $0 <source.c> [args...]"
     exit 1
 fi
 
@@ -13,9 +13,14 @@ BASENAME=$(basename "$SRC_FILE")
 BASENAME="${BASENAME%.*}"
 BIN_PATH="$OUT_DIR/$BASENAME"
 
-C_FILE="$OUT_DIR/$BASENAME.c"
+# Other files of the unit, declared with file= and used by the block, are next to it
+SOURCES=("$SRC_FILE")
+while IFS= read -r FILE; do
+    if [[ "$FILE" == *.c ]]; then
+        SOURCES+=("$OUT_DIR/$FILE")
+    fi
+done <<< "$TANGLIT_UNIT_FILES"
 
-gcc -std=c99 -pedantic-errors -o "$BIN_PATH" "$C_FILE" > /dev/null
+gcc -std=c99 -pedantic-errors -o "$BIN_PATH" "${SOURCES[@]}" > /dev/null
 
-cd "$OUT_DIR"
-"./$BASENAME"
+"$BIN_PATH" "${@:2}"
@@ -5,7 +5,7 @@ SRC_FILE="$1"
 OUT_DIR="$(dirname "$SRC_FILE")"
 
 if [[ -z "$SRC_FILE" || -z "$OUT_DIR" ]]; then
-    # echo "Usage: $0 <source.hs>"
+    # echo "Usage: $0 <source.hs> [args...]"
     exit 1
 fi
 
@@ -17,7 +17,7 @@ BIN_PATH="$OUT_DIR/$BASENAME"
 HS_FILE="$OUT_DIR/$BASENAME.hs"
 mv "$SRC_FILE" "$HS_FILE"
 
-ghc -outputdir "$OUT_DIR" -o "$BIN_PATH" "$HS_FILE" > /dev/null
+# Modules of the unit, declared with file=, are next to it
+ghc -i"$OUT_DIR" -outputdir "$OUT_DIR" -o "$BIN_PATH" "$HS_FILE" > /dev/null
 
-cd "$OUT_DIR"
-"./$BASENAME"
+"$BIN_PATH" "${@:2}"
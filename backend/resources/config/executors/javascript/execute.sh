@@ -0,0 +1,12 @@
+#!/bin/bash
+set -e
+
+SRC_FILE="$1"
+OUT_DIR="$(dirname "$SRC_FILE")"
+
+if [[ -z "$SRC_FILE" || -z "$OUT_DIR" ]]; then
+    # echo "Usage: $0 <source.js> [args...]"
+    exit 1
+fi
+
+node "$SRC_FILE" "${@:2}"
@@ -8,12 +8,8 @@ SRC_FILE="$1"
 OUT_DIR="$(dirname "$SRC_FILE")"
 
 if [[ -z "$SRC_FILE" || -z "$OUT_DIR" ]]; then
-    # echo "Usage: $0 <source.py>"
+    # echo "Usage: $0 <source.py> [args...]"
     exit 1
 fi
 
-BASENAME=$(basename "$SRC_FILE")
-PYTHON_FILE="$BASENAME"
-
-cd "$OUT_DIR"
-python3 "$PYTHON_FILE"
+python3 "$SRC_FILE" "${@:2}"
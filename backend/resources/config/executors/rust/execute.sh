@@ -5,7 +5,7 @@ SRC_FILE="$1"
 OUT_DIR="$(dirname "$SRC_FILE")"
 
 if [[ -z "$SRC_FILE" || -z "$OUT_DIR" ]]; then
-    # echo "Usage: $0 <source.rs>"
+    # echo "Usage: $0 <source.rs> [args...]"
     exit 1
 fi
 
@@ -17,5 +17,4 @@ RUST_FILE="$OUT_DIR/$BASENAME.rs"
 
 rustc "$RUST_FILE" -o "$BIN_PATH" > /dev/null
 
-cd "$OUT_DIR"
-"./$BASENAME"
+"$BIN_PATH" "${@:2}"
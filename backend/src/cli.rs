@@ -1,4 +1,8 @@
+use crate::execution::Sandbox;
+use crate::utils::parse_duration;
 use clap::{Args, Parser, Subcommand};
+use regex::Regex;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -14,8 +18,22 @@ pub enum Commands {
     Tangle(TangleArgs),
     #[command(about = "Execute a specific code block from a markdown file and read its output")]
     Execute(ExecuteArgs),
+    #[command(
+        about = "Execute every code block of a markdown file in order, like a notebook, optionally writing their outputs into it"
+    )]
+    ExecuteAll(ExecuteAllArgs),
+    #[command(
+        about = "Execute every code block followed by an output block and compare its output with the recorded one"
+    )]
+    Test(TestArgs),
+    #[command(about = "Remove the cached outputs of executions")]
+    CachePrune(CachePruneArgs),
     #[command(about = "Tangle and export all marked code blocks from a markdown file")]
     TangleAll(TangleAllArgs),
+    #[command(
+        about = "Bring the changes made to files tangled with --chunk-markers back into the markdown file"
+    )]
+    Untangle(UntangleArgs),
     #[command(about = "Generates a PDF from an markdown file, skipping the items with % markers")]
     GeneratePDF(GenerateDocArgs),
     #[command(about = "Generates an HTML from an markdown file, skipping the items with % markers")]
@@ -79,6 +97,79 @@ pub struct TangleArgs {
         env = "TARGET_BLOCK"
     )]
     pub target_block: String,
+    #[command(flatten)]
+    pub line_directives: LineDirectivesArg,
+    #[command(flatten)]
+    pub chunk_markers: ChunkMarkersArg,
+    #[arg(
+        long,
+        help = "Also write a JSON source map from every output line to its markdown line, next to the output file.",
+        help_heading = "Tangle Args"
+    )]
+    pub source_map: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct LineDirectivesArg {
+    #[arg(
+        long("line-directives"),
+        help = "Emit the language's line directives pointing back to the markdown file (e.g. `#line 12 \"doc.md\"` for C).",
+        help_heading = "Tangle Args"
+    )]
+    pub enabled: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ChunkMarkersArg {
+    #[arg(
+        id = "chunk_markers",
+        long("chunk-markers"),
+        help = "Surround every chunk with comments naming it (e.g. `// tanglit:begin main:0`), so the output can be untangled.",
+        help_heading = "Tangle Args"
+    )]
+    pub enabled: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = parse_env_arg,
+        help = "Set this variable in the environment of the blocks, over the ones of the document and blocks. Can be repeated.",
+        help_heading = "Run Args"
+    )]
+    pub env: Vec<(String, String)>,
+    #[arg(
+        long("arg"),
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Pass this argument to the programs of the blocks, instead of the ones of the document and blocks. Can be repeated.",
+        help_heading = "Run Args"
+    )]
+    pub args: Vec<String>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Run the blocks from this directory, instead of the one of the document and blocks.",
+        help_heading = "Run Args"
+    )]
+    pub cwd: Option<String>,
+}
+
+fn parse_env_arg(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", text)),
+    }
+}
+
+#[derive(Args)]
+pub struct UntangleArgs {
+    #[command(flatten)]
+    pub input: InputFileArg,
+    #[command(flatten)]
+    pub output: OutputDirArg,
 }
 
 #[derive(Args)]
@@ -94,6 +185,164 @@ pub struct ExecuteArgs {
         env = "TARGET_BLOCK"
     )]
     pub target_block: String,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "File fed to the standard input of the block, instead of its input block or stdin= file.",
+        help_heading = "Execute Args"
+    )]
+    pub stdin: Option<String>,
+    #[arg(
+        long,
+        help = "Keep the scratch directory the block ran in, for debugging.",
+        help_heading = "Execute Args"
+    )]
+    pub keep_temp: bool,
+    #[command(flatten)]
+    pub run: RunArgs,
+    #[arg(
+        long,
+        value_name = "SANDBOX",
+        help = "Sandbox to run the block in, 'none' or 'bubblewrap', instead of the one of its language.",
+        help_heading = "Execute Args"
+    )]
+    pub sandbox: Option<Sandbox>,
+    #[arg(
+        long,
+        help = "Reuse the output of an identical execution of the block if it is cached, instead of running it again.",
+        help_heading = "Execute Args"
+    )]
+    pub cache: bool,
+}
+
+#[derive(Args)]
+pub struct ExecuteAllArgs {
+    #[command(flatten)]
+    pub input: InputFileArg,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Only execute the blocks whose tag matches this regex.",
+        help_heading = "Execute Args"
+    )]
+    pub filter: Option<Regex>,
+    #[arg(
+        long,
+        value_name = "LANGUAGE",
+        help = "Only execute the blocks written in this language.",
+        help_heading = "Execute Args"
+    )]
+    pub language: Option<String>,
+    #[arg(
+        long,
+        help = "Write the output of every block below it in the markdown file, replacing its previous output block.",
+        help_heading = "Execute Args"
+    )]
+    pub write: bool,
+    #[command(flatten)]
+    pub run: RunArgs,
+    #[arg(
+        long,
+        short,
+        value_name = "JOBS",
+        default_value_t = 1,
+        help = "Number of blocks run at the same time. Blocks only wait for the ones they use and for the previous block of their session.",
+        help_heading = "Execute Args"
+    )]
+    pub jobs: usize,
+    #[arg(
+        long,
+        value_name = "SANDBOX",
+        help = "Sandbox to run the blocks in, 'none' or 'bubblewrap', instead of the one of their language.",
+        help_heading = "Execute Args"
+    )]
+    pub sandbox: Option<Sandbox>,
+    #[arg(
+        long,
+        help = "Reuse the outputs of identical executions of the blocks if they are cached, instead of running them again.",
+        help_heading = "Execute Args"
+    )]
+    pub cache: bool,
+}
+
+#[derive(Args)]
+pub struct TestArgs {
+    #[command(flatten)]
+    pub input: InputFileArg,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Only test the blocks whose tag matches this regex.",
+        help_heading = "Test Args"
+    )]
+    pub filter: Option<Regex>,
+    #[arg(
+        long,
+        value_name = "LANGUAGE",
+        help = "Only test the blocks written in this language.",
+        help_heading = "Test Args"
+    )]
+    pub language: Option<String>,
+    #[arg(
+        long,
+        short,
+        value_name = "JOBS",
+        default_value_t = 1,
+        help = "Number of blocks run at the same time. Blocks only wait for the ones they use and for the previous block of their session.",
+        help_heading = "Test Args"
+    )]
+    pub jobs: usize,
+    #[arg(
+        long,
+        help = "Ignore whitespace at the end of lines and empty lines around the outputs.",
+        help_heading = "Test Args"
+    )]
+    pub trim_whitespace: bool,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Ignore the text matching this regex in the outputs, like timestamps. Can be repeated.",
+        help_heading = "Test Args"
+    )]
+    pub ignore: Vec<Regex>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write a JUnit XML report of the tests to this file.",
+        help_heading = "Test Args"
+    )]
+    pub junit: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write a JSON report of the tests to this file.",
+        help_heading = "Test Args"
+    )]
+    pub json: Option<String>,
+    #[command(flatten)]
+    pub run: RunArgs,
+    #[arg(
+        long,
+        value_name = "SANDBOX",
+        help = "Sandbox to run the blocks in, 'none' or 'bubblewrap', instead of the one of their language.",
+        help_heading = "Test Args"
+    )]
+    pub sandbox: Option<Sandbox>,
+}
+
+#[derive(Args)]
+pub struct CachePruneArgs {
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration_arg,
+        help = "Only remove the outputs that weren't used for this long, like '12h' or '7d'. By default every output is removed."
+    )]
+    pub older_than: Option<Duration>,
+}
+
+fn parse_duration_arg(text: &str) -> Result<Duration, String> {
+    parse_duration(text).ok_or_else(|| format!("invalid duration '{}'", text))
 }
 
 #[derive(Args)]
@@ -119,6 +368,10 @@ pub struct TangleAllArgs {
     pub input: InputFileArg,
     #[command(flatten)]
     pub output: OutputDirArg,
+    #[command(flatten)]
+    pub line_directives: LineDirectivesArg,
+    #[command(flatten)]
+    pub chunk_markers: ChunkMarkersArg,
 }
 
 #[derive(Args)]
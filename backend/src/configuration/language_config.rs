@@ -11,16 +11,52 @@ use crate::errors::ConfigError;
 
 pub const PLACEHOLDER_DEFAULT_PATTERN: &str = "#<([^#<>]+)>#";
 const TEMPLATE_FILENAME: &str = "template";
+const SESSION_TEMPLATE_FILENAME: &str = "session_template";
 const EXECUTION_SCRIPT_FILENAME: &str = "execute";
 const EXECUTORS_DIRNAME: &str = "executors";
 const TOML_CONFIG_FILENAME: &str = "config.toml";
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, Default)]
 pub struct LanguageConfig {
     pub extension: Option<String>,
     pub placeholder_regex: Option<String>, // If empty, we'll use the default
+    // Template for the line directives emitted when tangling with source lines,
+    // `{line}` and `{file}` are replaced by the markdown line and file.
+    pub line_directive: Option<String>,
+    // Start of a line comment, used for the chunk markers that allow untangling the code
+    pub line_comment: Option<String>,
+    // Syntax of the references to other blocks, `{name}` stands for the block name.
+    // If empty, we'll use `@[{name}]`. Documents can override it, see `parse_macro_syntax_from_ast`.
+    pub macro_syntax: Option<String>,
+    // Written before the opening delimiter of a reference to keep it literally, like `\` in
+    // `\@[`. If empty, references can't be escaped. Documents can override it along with the syntax.
+    pub macro_escape: Option<String>,
+    // Time limit of an execution, like `30s` or `500ms`. Blocks can override it with `timeout=`.
+    pub timeout: Option<String>,
+    // Sandbox of the executions, `none` or `bubblewrap`. If empty, they aren't sandboxed.
+    pub sandbox: Option<String>,
+    // Limits of the executions: CPU time like `10s`, memory and output sizes like `512M`.
+    // When unset, the output is limited to 16M, and the CPU time and memory only within a sandbox.
+    pub max_cpu_time: Option<String>,
+    pub max_memory: Option<String>,
+    pub max_output: Option<String>,
+    // Command starting the interpreter of the blocks marked with `session=`, as in
+    // `["python3", "-i"]`. `{marker}` is replaced by the line the interpreter must print
+    // to stderr, and to stdout followed by `:<exit status>`, after running each block.
+    // If empty, the language doesn't support sessions.
+    pub session_command: Option<Vec<String>>,
+    // What is written to the interpreter to run a block, `{file}` is replaced by the
+    // tangled code file and `{marker}` as above. If empty, it's the file path and a newline.
+    pub session_input: Option<String>,
+    // Regexes to parse compiler and runtime errors from stderr, see `execution::parse_diagnostics`
+    #[serde(default)]
+    pub diagnostic_regexes: Vec<String>,
     #[serde(skip)]
     pub template: Option<String>,
+    // Template of the blocks marked with `session=`, from a `session_template` file next to the
+    // `template` one, for interpreters that don't run whole programs. If empty, we'll use `template`.
+    #[serde(skip)]
+    pub session_template: Option<String>,
     #[serde(skip)]
     pub execution_script: Option<String>,
 }
@@ -34,6 +70,8 @@ impl LanguageConfig {
             Some(path) => read_to_string(path).ok(),
             None => get_default_template(lang),
         };
+        config.session_template = find_file_in_dir(lang_config_path, SESSION_TEMPLATE_FILENAME)
+            .and_then(|path| read_to_string(path).ok());
         config.execution_script =
             match find_file_in_dir(lang_config_path, EXECUTION_SCRIPT_FILENAME) {
                 Some(path) => read_to_string(path).ok(),
@@ -115,6 +153,21 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_load_session_template() {
+        let config_path = format!("{}/resources/config", env!("CARGO_MANIFEST_DIR"));
+        with_var(CONFIG_DIR_ENVVAR, Some(config_path), || {
+            let haskell = LanguageConfig::load_for_lang("haskell-io").unwrap();
+            assert_eq!(
+                haskell.session_template.as_deref(),
+                Some("#<IMPORTS>#\n\n#<BODY>#\n")
+            );
+            assert!(haskell.session_command.is_some());
+            let python = LanguageConfig::load_for_lang("python").unwrap();
+            assert!(python.session_template.is_none());
+        });
+    }
+
     #[test]
     fn test_load_default_config_rust() {
         // Use a random directory to ensure it doesn't exist
@@ -3,8 +3,11 @@ mod format_blocks;
 mod gen_html;
 mod generate_pdf;
 mod parser;
+mod source_map;
 mod tangle;
+mod untangle;
 
+use crate::configuration::language_config::LanguageConfig;
 use crate::doc::format_blocks::format_code_blocks;
 pub use crate::doc::gen_html::DEFAULT_THEME;
 use crate::doc::gen_html::{
@@ -14,9 +17,15 @@ use crate::doc::gen_html::{
 use crate::doc::generate_pdf::generate_pdf;
 use crate::doc::parser::exclude::FilterTarget;
 use crate::doc::parser::slides::parse_slides_from_ast;
-use crate::doc::parser::{ast_to_markdown, parse_code_blocks_from_ast, parse_from_string};
-use crate::execution::ExecutionOutput;
-use crate::execution::write_code_to_file;
+use crate::doc::parser::{
+    ast_to_markdown, parse_code_blocks_from_ast, parse_from_string, parse_macro_escape_from_ast,
+    parse_macro_syntax_from_ast, parse_run_settings_from_ast,
+};
+use crate::errors::{ConfigError, ExecutionError};
+use crate::execution::{
+    Artifact, BlockRun, BlockTest, ExecuteAllOptions, ExecutionOutput, TestOptions,
+};
+use crate::execution::{export_path, unit_file_path, write_code_to_file};
 use comrak::plugins::syntect::SyntectAdapterBuilder;
 use comrak::{Arena, ComrakOptions, Plugins, parse_document};
 pub use error::DocError;
@@ -24,19 +33,54 @@ use headless_chrome::types::PrintToPdfOptions;
 use log::warn;
 use markdown::mdast::Node;
 pub use parser::ParserError;
-pub use parser::code_block::CodeBlock;
+pub use parser::code_block::{
+    CodeBlock, FILE_LANGUAGE, INPUT_LANGUAGE, OUTPUT_LANGUAGE, RunSettings,
+};
 use parser::exclude::exclude_from_ast;
 pub use parser::slides::SlideByIndex;
 use parser::slides::parse_slides_index_from_ast;
 use serde::Serialize;
-use std::collections::HashMap;
+pub(crate) use source_map::MappedCode;
+pub use source_map::{SourceMap, TangledCode};
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::PathBuf;
 use syntect::highlighting::ThemeSet;
 pub use tangle::CodeBlocks;
 pub use tangle::TangleError;
+pub use tangle::{DEFAULT_MACRO_SYNTAX, DuplicateTag, MacroSyntax};
+use untangle::MARKER_BEGIN;
+pub use untangle::{UntangleError, apply_edits};
+
+// Name used for the markdown source in line directives and source maps
+// when the document wasn't read from a file
+pub(crate) const DEFAULT_SOURCE_NAME: &str = "document.md";
+
+// Comments around the artifacts `format_output` writes below an output block
+const ARTIFACTS_START: &str = "<!-- artifacts -->";
+const ARTIFACTS_END: &str = "<!-- /artifacts -->";
 
 pub struct TanglitDoc {
     raw_markdown: String,
     ast: Node,
+    file_path: Option<String>,
+}
+
+/// A file written by `generate_code_files`, along with the tags of the blocks
+/// it was assembled from, in the order they were written.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedFile {
+    pub path: PathBuf,
+    pub blocks: Vec<String>,
+}
+
+/// A file written next to the code of an execution, see `TanglitDoc::data_files` and
+/// `TanglitDoc::unit_files`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtraFile {
+    /// Path of the file, relative to the directory it's written to.
+    pub path: PathBuf,
+    pub content: String,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -46,22 +90,85 @@ pub struct Edit {
     pub end_line: usize,
 }
 
+/// The output of a block as recorded in the `output` block below it by `format_output`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecordedOutput {
+    pub stdout: String,
+    /// `None` when the output block only holds the standard output, as when written by hand.
+    pub stderr: Option<String>,
+    /// The exit code as written by `format_output`, like `0` or `timed out`, if recorded.
+    pub exit_code: Option<String>,
+    /// Line of the opening fence of the output block, 1-based.
+    pub start_line: usize,
+}
+
+impl RecordedOutput {
+    /// Parses the content of an output block, between its fences.
+    /// Blocks not in the format written by `format_output` are taken as the standard output,
+    /// ending with a newline unless they're empty, since the fences leave out the final one.
+    pub fn parse(content: &str, start_line: usize) -> RecordedOutput {
+        let recorded = content
+            .strip_prefix("Output:\n")
+            .and_then(|rest| rest.rsplit_once("\n\nExit code: "))
+            .and_then(|(rest, exit_code)| {
+                let (stdout, stderr) = rest.rsplit_once("\n\nStderr:\n")?;
+                Some((stdout, stderr, exit_code))
+            });
+        match recorded {
+            Some((stdout, stderr, exit_code)) => RecordedOutput {
+                stdout: stdout.to_string(),
+                stderr: Some(stderr.to_string()),
+                exit_code: Some(exit_code.to_string()),
+                start_line,
+            },
+            None => RecordedOutput {
+                stdout: match content.trim_end_matches('\n') {
+                    "" => String::new(),
+                    stdout => format!("{}\n", stdout),
+                },
+                stderr: None,
+                exit_code: None,
+                start_line,
+            },
+        }
+    }
+}
+
 impl TanglitDoc {
     pub fn new_from_string(raw_markdown: &str) -> Result<TanglitDoc, DocError> {
         let ast = parse_from_string(raw_markdown)?;
         Ok(TanglitDoc {
             raw_markdown: raw_markdown.to_string(),
             ast,
+            file_path: None,
         })
     }
 
     pub fn new_from_file(file_path: &str) -> Result<TanglitDoc, DocError> {
         let input = std::fs::read_to_string(file_path)
             .map_err(|e| ParserError::InvalidInput(format!("Failed to read file: {}", e)))?;
-        Self::new_from_string(&input)
+        let mut doc = Self::new_from_string(&input)?;
+        doc.file_path = Some(file_path.to_string());
+        Ok(doc)
+    }
+
+    /// Returns the path of the markdown file the document was read from, if any.
+    pub fn file_path(&self) -> Option<&str> {
+        self.file_path.as_deref()
+    }
+
+    /// Sets the path of the markdown file of a document read from elsewhere, like the buffer
+    /// of an editor, so it's resolved as if it was read from the file.
+    pub fn set_file_path(&mut self, file_path: &str) {
+        self.file_path = Some(file_path.to_string());
+    }
+
+    /// Returns the name of the markdown source used in line directives and source maps.
+    pub fn source_name(&self) -> &str {
+        self.file_path.as_deref().unwrap_or(DEFAULT_SOURCE_NAME)
     }
 
-    fn parse_blocks(&self) -> Result<HashMap<String, CodeBlock>, DocError> {
+    fn parse_blocks(&self) -> Result<Vec<CodeBlock>, DocError> {
         Ok(parse_code_blocks_from_ast(&self.ast)?)
     }
 
@@ -105,43 +212,27 @@ impl TanglitDoc {
             .get_block(block_id)
             .ok_or_else(|| TangleError::BlockNotFound(block_id.to_string()))?;
 
-        let output_content = format!(
+        let mut output_content = format!(
             "```output\nOutput:\n{}\n\nStderr:\n{}\n\nExit code: {}\n```",
             output.stdout,
             output.stderr,
-            output.status.map_or("None".to_string(), |s| s.to_string())
+            output.exit_code_label()
         );
-
-        let lines: Vec<&str> = self.raw_markdown.lines().collect();
-        let code_end_line = code_block.end_line;
-
-        // Look for an existing output block starting after the code block
-        let mut output_start_line = None;
-        let mut output_end_line = None;
-
-        // Start searching from the line immediately after the code block
-        for (line_idx, line) in lines.iter().enumerate().skip(code_end_line) {
-            let trimmed = line.trim();
-
-            if trimmed == "```output" {
-                output_start_line = Some(line_idx);
-
-                // Find the closing ``` for this output block
-                for (end_idx, end_line) in lines.iter().enumerate().skip(line_idx + 1) {
-                    if end_line.trim() == "```" {
-                        output_end_line = Some(end_idx);
-                        break;
-                    }
-                }
-                break;
-            } else if !trimmed.is_empty() {
-                // Hit non-empty content that's not an output block, stop looking
-                break;
-            }
+        if !output.artifacts.is_empty() {
+            let artifacts: Vec<String> = output.artifacts.iter().map(artifact_markdown).collect();
+            output_content.push_str(&format!(
+                "\n\n{}\n{}\n{}",
+                ARTIFACTS_START,
+                artifacts.join("\n\n"),
+                ARTIFACTS_END
+            ));
         }
 
-        match (output_start_line, output_end_line) {
-            (Some(start), Some(end)) => {
+        let code_end_line = code_block.end_line;
+        match self.find_output_block(code_end_line) {
+            Some((start, end)) => {
+                // The artifacts of the previous output are replaced along with it
+                let end = self.find_artifacts_section(end).unwrap_or(end);
                 // Replace existing output block
                 // Calculate how many lines to replace (inclusive of both start and end lines)
                 let lines_to_replace = end - start + 1;
@@ -162,15 +253,157 @@ impl TanglitDoc {
         }
     }
 
+    /// Returns the output recorded in the `output` block right below the block `block_id`,
+    /// if there is one, see `RecordedOutput::parse`.
+    pub fn recorded_output(&self, block_id: &str) -> Result<Option<RecordedOutput>, DocError> {
+        let binding = self.get_code_blocks()?;
+        let code_block = binding
+            .get_block(block_id)
+            .ok_or_else(|| TangleError::BlockNotFound(block_id.to_string()))?;
+        Ok(self.recorded_output_of(code_block))
+    }
+
+    /// Same as `recorded_output`, for a block already parsed from the document.
+    pub(crate) fn recorded_output_of(&self, code_block: &CodeBlock) -> Option<RecordedOutput> {
+        let (start, end) = self.find_output_block(code_block.end_line)?;
+        let content = self
+            .raw_markdown
+            .lines()
+            .skip(start + 1)
+            .take(end - start - 1)
+            .collect::<Vec<&str>>()
+            .join("\n");
+        Some(RecordedOutput::parse(&content, start + 1))
+    }
+
+    /// Returns the first and last lines, 0-based, of the `output` block following the code
+    /// block ending at `code_end_line`, with only empty lines between them.
+    fn find_output_block(&self, code_end_line: usize) -> Option<(usize, usize)> {
+        let lines: Vec<&str> = self.raw_markdown.lines().collect();
+
+        // Start searching from the line immediately after the code block
+        for (line_idx, line) in lines.iter().enumerate().skip(code_end_line) {
+            let trimmed = line.trim();
+
+            if trimmed == "```output" {
+                // Find the closing ``` for this output block
+                return lines
+                    .iter()
+                    .enumerate()
+                    .skip(line_idx + 1)
+                    .find(|(_, end_line)| end_line.trim() == "```")
+                    .map(|(end_idx, _)| (line_idx, end_idx));
+            } else if !trimmed.is_empty() {
+                // Hit non-empty content that's not an output block, stop looking
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Returns the last line, 0-based, of the artifacts written by `format_output` after the
+    /// `output` block ending at `output_end_line`, with only empty lines between them.
+    fn find_artifacts_section(&self, output_end_line: usize) -> Option<usize> {
+        let mut lines = self
+            .raw_markdown
+            .lines()
+            .enumerate()
+            .skip(output_end_line + 1)
+            .skip_while(|(_, line)| line.trim().is_empty());
+        if lines.next()?.1.trim() != ARTIFACTS_START {
+            return None;
+        }
+        lines
+            .find(|(_, line)| line.trim() == ARTIFACTS_END)
+            .map(|(end_idx, _)| end_idx)
+    }
+
+    /// Compares the blocks of the document with their recorded outputs, see `execution::run_tests`.
+    pub fn run_tests(&self, options: &TestOptions) -> Result<Vec<BlockTest>, ExecutionError> {
+        crate::execution::run_tests(self, options)
+    }
+
+    /// Runs every executable block of the document, see `execution::execute_all`.
+    pub fn execute_all(
+        &self,
+        options: &ExecuteAllOptions,
+    ) -> Result<Vec<BlockRun>, ExecutionError> {
+        crate::execution::execute_all(self, options)
+    }
+
+    /// Returns the markdown with the output of every block that ran in `runs` written
+    /// below it, replacing its previous output as `format_output` does for a single block.
+    pub fn write_outputs(&self, runs: &[BlockRun]) -> Result<String, DocError> {
+        let edits = runs
+            .iter()
+            .filter_map(|run| Some((&run.tag, run.output()?)))
+            .map(|(tag, output)| {
+                let mut edit = self.format_output(tag, output)?;
+                edit.content.push('\n');
+                Ok(edit)
+            })
+            .collect::<Result<Vec<Edit>, DocError>>()?;
+        Ok(apply_edits(&self.raw_markdown, &edits))
+    }
+
     pub fn filter_content_for_doc(&self) -> Result<String, DocError> {
         let ast_with_exclusions =
             exclude_from_ast(&self.ast, FilterTarget::Doc, &self.raw_markdown);
         Ok(ast_to_markdown(&ast_with_exclusions)?)
     }
 
+    /// Returns the environment, arguments and directory the blocks of the document run with
+    /// unless they declare their own, set with `<!-- tanglit: env=[...] args=[...] cwd=... -->`.
+    pub fn run_settings(&self) -> RunSettings {
+        parse_run_settings_from_ast(&self.ast)
+    }
+
+    /// Returns the code blocks of the document, along with the macro syntax of each of them:
+    /// the one set by the document if there is one, otherwise the one of their language.
+    /// A document setting only `macro_escape` uses it with the default syntax.
+    /// Fails if the configuration of one of the languages can't be read.
+    /// Blocks reusing a tag are logged as warnings, see `CodeBlocks::duplicate_tags`.
     pub fn get_code_blocks(&self) -> Result<CodeBlocks, DocError> {
         let blocks = self.parse_blocks()?;
-        Ok(CodeBlocks::from_codeblocks(blocks))
+        let languages: Vec<String> = blocks
+            .iter()
+            .filter_map(|block| block.language.clone())
+            .collect();
+        let mut code_blocks = CodeBlocks::from_pieces(blocks);
+        for duplicate in code_blocks.duplicate_tags() {
+            warn!("{}", duplicate);
+        }
+
+        let template = parse_macro_syntax_from_ast(&self.ast);
+        let escape = parse_macro_escape_from_ast(&self.ast);
+        if template.is_some() || escape.is_some() {
+            let template = template.as_deref().unwrap_or(DEFAULT_MACRO_SYNTAX);
+            code_blocks.set_document_macro_syntax(
+                MacroSyntax::from_template(template)?.with_escape(escape.as_deref())?,
+            );
+            return Ok(code_blocks);
+        }
+        for language in languages {
+            let config = match LanguageConfig::load_for_lang(&language) {
+                Ok(config) => config,
+                // Languages without a configuration, like `output` blocks, use the default syntax
+                Err(ConfigError::ConfigMissingForLanguage(..)) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if config.macro_syntax.is_none() && config.macro_escape.is_none() {
+                continue;
+            }
+            let template = config
+                .macro_syntax
+                .as_deref()
+                .unwrap_or(DEFAULT_MACRO_SYNTAX);
+            code_blocks.set_language_macro_syntax(
+                &language,
+                MacroSyntax::from_template(template)?
+                    .with_escape(config.macro_escape.as_deref())?,
+            );
+        }
+        Ok(code_blocks)
     }
 
     pub fn generate_html(&self, theme: &str) -> Result<String, DocError> {
@@ -286,22 +519,236 @@ impl TanglitDoc {
         Ok(())
     }
 
-    pub fn generate_code_files(&self, output_dir: &str) -> Result<usize, DocError> {
+    /// Tangles every block with an `export=` attribute and writes it to `output_dir`.
+    /// Blocks exporting to the same file are concatenated into it, see `CodeBlocks::get_blocks_by_export`.
+    /// If `line_directives` is set, each file gets the line directives of its language pointing
+    /// back to the document, see `LanguageConfig::line_directive`.
+    /// If `chunk_markers` is set, every chunk is surrounded by comments naming it,
+    /// so the files can later be brought back into the document with `untangle_code_files`.
+    pub fn generate_code_files(
+        &self,
+        output_dir: &str,
+        line_directives: bool,
+        chunk_markers: bool,
+    ) -> Result<Vec<ExportedFile>, DocError> {
+        let blocks = self.get_code_blocks()?;
+        let mut exported_files = vec![];
+        for (_, blocks_to_export) in blocks.get_blocks_by_export() {
+            let lang_config = load_lang_config(blocks_to_export[0]);
+            let line_directive = lang_config
+                .as_ref()
+                .and_then(|cfg| cfg.line_directive.clone())
+                .filter(|_| line_directives);
+            let line_comment = lang_config
+                .and_then(|cfg| cfg.line_comment)
+                .filter(|_| chunk_markers);
+            let code = if blocks_to_export[0].language.as_deref() == Some(FILE_LANGUAGE) {
+                data_file_content(&blocks_to_export)
+            } else {
+                self.tangle_export_group(
+                    &blocks,
+                    &blocks_to_export,
+                    line_directive.as_deref(),
+                    line_comment.as_deref(),
+                )?
+            };
+            let path = write_code_to_file(blocks_to_export[0], code, output_dir)?;
+            exported_files.push(ExportedFile {
+                path,
+                blocks: blocks_to_export
+                    .iter()
+                    .map(|block| block.tag.clone())
+                    .collect(),
+            });
+        }
+        Ok(exported_files)
+    }
+
+    /// Returns the data files declared in the document with ```` ```file name=data.csv ````
+    /// blocks, which are written next to the code of every execution, and exported by
+    /// `generate_code_files`, as they were written. Blocks with the same name are concatenated, in the order they'd be exported.
+    pub fn data_files(&self, blocks: &CodeBlocks) -> Result<Vec<ExtraFile>, DocError> {
+        let mut data_files = vec![];
+        for (_, group) in blocks.get_blocks_by_export() {
+            if group[0].language.as_deref() != Some(FILE_LANGUAGE) {
+                continue;
+            }
+            data_files.push(ExtraFile {
+                path: export_path(group[0])?,
+                content: data_file_content(&group),
+            });
+        }
+        Ok(data_files)
+    }
+
+    /// Returns the other source files of the execution of `block`: the blocks declared with
+    /// `file=` that it uses with `use=[...]`, or that those use in turn, grouped by file like
+    /// exported blocks. The file of `block` itself, if it has one, is left out, since `block`
+    /// is executed as the main file.
+    pub fn unit_files(
+        &self,
+        blocks: &CodeBlocks,
+        block: &CodeBlock,
+    ) -> Result<Vec<ExtraFile>, DocError> {
+        let mut used_files: HashSet<&String> = HashSet::new();
+        let mut to_visit: Vec<&String> = block.imports.iter().collect();
+        while let Some(tag) = to_visit.pop() {
+            let Some(file_block) = blocks.get_block(tag) else {
+                continue;
+            };
+            // Blocks without `file=` are copied into the code using them instead
+            if let Some(file) = &file_block.file {
+                if used_files.insert(file) {
+                    to_visit.extend(&file_block.imports);
+                }
+            }
+        }
+
+        let mut unit_files = vec![];
+        for (file, group) in blocks.get_blocks_by_file() {
+            if !used_files.contains(&file) || block.file.as_ref() == Some(&file) {
+                continue;
+            }
+            unit_files.push(ExtraFile {
+                path: unit_file_path(group[0])?,
+                content: self.tangle_export_group(blocks, &group, None, None)?,
+            });
+        }
+        Ok(unit_files)
+    }
+
+    /// Reads the files written to `output_dir` by `generate_code_files` with chunk markers,
+    /// and returns the edits that bring the changes made to them back into the code blocks
+    /// of the document, sorted by line. Files without chunk markers are skipped.
+    /// Changes that can't be attributed to a single block, or that conflict with each other
+    /// (a block tangled in several places and changed differently), are refused.
+    pub fn untangle_code_files(&self, output_dir: &str) -> Result<Vec<Edit>, DocError> {
         let blocks = self.get_code_blocks()?;
-        let blocks_to_tangle = blocks.get_all_blocks_to_tangle();
-        let count = blocks_to_tangle.len();
-        for block in blocks_to_tangle.iter() {
-            let tangle_result = blocks.tangle_codeblock(block)?;
-            write_code_to_file(block, tangle_result, output_dir)?;
+        let mut found = vec![];
+        let mut expected = vec![];
+        for (_, blocks_to_export) in blocks.get_blocks_by_export() {
+            let path = PathBuf::from(output_dir).join(export_path(blocks_to_export[0])?);
+            let lang_config = load_lang_config(blocks_to_export[0]);
+            let line_directive = lang_config
+                .as_ref()
+                .and_then(|cfg| cfg.line_directive.clone());
+            let Some(line_comment) = lang_config.and_then(|cfg| cfg.line_comment) else {
+                warn!(
+                    "Skipping {}: its language has no line comment",
+                    path.display()
+                );
+                continue;
+            };
+            let code = match read_to_string(&path) {
+                Ok(code) if code.contains(MARKER_BEGIN) => code,
+                Ok(_) => {
+                    warn!("Skipping {}: it has no chunk markers", path.display());
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            let file = path.display().to_string();
+            let macro_syntax = blocks.macro_syntax_for(blocks_to_export[0]);
+            found.extend(untangle::parse_marked_code(
+                &code,
+                &file,
+                &line_comment,
+                line_directive.as_deref(),
+                macro_syntax,
+            )?);
+            let current_code =
+                self.tangle_export_group(&blocks, &blocks_to_export, None, Some(&line_comment))?;
+            expected.extend(untangle::parse_marked_code(
+                &current_code,
+                &file,
+                &line_comment,
+                None,
+                macro_syntax,
+            )?);
         }
-        Ok(count)
+        Ok(untangle::pieces_to_edits(
+            &self.raw_markdown,
+            &blocks,
+            found,
+            expected,
+        )?)
+    }
+
+    // Tangles the blocks exported to the same file, in order
+    fn tangle_export_group(
+        &self,
+        blocks: &CodeBlocks,
+        blocks_to_export: &[&CodeBlock],
+        line_directive: Option<&str>,
+        line_comment: Option<&str>,
+    ) -> Result<String, TangleError> {
+        let tangled_blocks = blocks_to_export
+            .iter()
+            .map(|block| {
+                blocks
+                    .tangle_codeblock_with_source_map(
+                        block,
+                        line_directive,
+                        line_comment,
+                        self.source_name(),
+                    )
+                    .map(|tangled| tangled.code)
+            })
+            .collect::<Result<Vec<String>, TangleError>>()?;
+        Ok(tangled_blocks.join("\n"))
     }
 }
 
+// Markdown of an artifact below an output block: images are shown, other files linked to,
+// embedded or by the relative path of their copy next to the document
+fn artifact_markdown(artifact: &Artifact) -> String {
+    let target = artifact.data_uri().or_else(|| {
+        // Paths with spaces have to be enclosed in angle brackets
+        artifact.path.as_ref().map(|path| {
+            if path.contains(char::is_whitespace) {
+                format!("<{}>", path)
+            } else {
+                path.clone()
+            }
+        })
+    });
+    match target {
+        Some(target) if artifact.is_image() => format!("![{}]({})", artifact.name, target),
+        Some(target) => format!("[{}]({})", artifact.name, target),
+        None => format!(
+            "`{}` ({} bytes, too large to embed)",
+            artifact.name, artifact.size
+        ),
+    }
+}
+
+// Loads the configuration of the language of a block, if it has one
+fn load_lang_config(block: &CodeBlock) -> Option<LanguageConfig> {
+    block
+        .language
+        .as_deref()
+        .and_then(|l| LanguageConfig::load_for_lang(l).ok())
+}
+
+// Content of the data file of `group`, ```` ```file ```` blocks with the same name: their
+// content as it was written, without expanding macros, each line ending with a newline
+fn data_file_content(group: &[&CodeBlock]) -> String {
+    group
+        .iter()
+        .filter(|block| !block.code.is_empty())
+        .map(|block| format!("{}\n", block.code))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::execution::ExecutionOutput;
+    use crate::execution::{BlockRunResult, ExecutionOutput};
+    use temp_env::with_var;
 
     #[test]
     fn test_format_output_insert_new_block() {
@@ -319,6 +766,7 @@ Some other content here.
             stdout: "Hello, world!\n".to_string(),
             stderr: "".to_string(),
             status: Some(0),
+            ..Default::default()
         };
 
         let edit = doc.format_output("hello", &output).unwrap();
@@ -330,6 +778,60 @@ Some other content here.
         assert!(edit.content.contains("Exit code: 0"));
     }
 
+    #[test]
+    fn test_write_outputs() {
+        let markdown = r#"```python first
+print(1)
+```
+
+```output
+Output:
+old
+```
+
+```python second
+print(2)
+```
+
+```python third
+print(3)
+```
+"#;
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let executed = |tag: &str, stdout: &str| BlockRun {
+            tag: tag.to_string(),
+            result: BlockRunResult::Executed {
+                output: ExecutionOutput {
+                    stdout: stdout.to_string(),
+                    status: Some(0),
+                    ..Default::default()
+                },
+            },
+        };
+        let runs = vec![
+            executed("first", "1"),
+            executed("second", "2"),
+            BlockRun {
+                tag: "third".to_string(),
+                result: BlockRunResult::Skipped {
+                    dependency: "second".to_string(),
+                },
+            },
+        ];
+
+        let written = doc.write_outputs(&runs).unwrap();
+        assert!(!written.contains("old"));
+        assert_eq!(written.matches("```output").count(), 2);
+        let first_output = written.find("Output:\n1").unwrap();
+        let second_block = written.find("```python second").unwrap();
+        let second_output = written.find("Output:\n2").unwrap();
+        let third_block = written.find("```python third").unwrap();
+        assert!(first_output < second_block);
+        assert!(second_block < second_output && second_output < third_block);
+        assert!(written.ends_with("print(3)\n```\n"));
+    }
+
     #[test]
     fn test_format_output_replace_existing_block() {
         let markdown = r#"# Test Document
@@ -356,6 +858,7 @@ Some other content here.
             stdout: "New output!\n".to_string(),
             stderr: "Some warning".to_string(),
             status: Some(1),
+            ..Default::default()
         };
 
         let edit = doc.format_output("hello", &output).unwrap();
@@ -367,6 +870,62 @@ Some other content here.
         assert!(edit.content.contains("Exit code: 1"));
     }
 
+    #[test]
+    fn test_format_output_with_artifacts() {
+        let markdown = r#"```python plot
+print("plotted")
+```
+
+```output
+Output:
+plotted
+```
+
+<!-- artifacts -->
+![old.png](data:image/png;base64,AA==)
+<!-- /artifacts -->
+
+Some other content here.
+"#;
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let artifact = |name: &str, mime_type: &str, data: Option<&str>| Artifact {
+            name: name.to_string(),
+            mime_type: mime_type.to_string(),
+            size: 20_000_000,
+            data: data.map(str::to_string),
+            path: None,
+        };
+        let output = ExecutionOutput {
+            stdout: "plotted\n".to_string(),
+            status: Some(0),
+            artifacts: vec![
+                artifact("sine.png", "image/png", Some("iVBORw==")),
+                artifact("points.csv", "text/csv", Some("eCx5Cg==")),
+                artifact("huge.bin", "application/octet-stream", None),
+                Artifact {
+                    path: Some("doc.artifacts/plot/large plot.png".to_string()),
+                    ..artifact("large plot.png", "image/png", None)
+                },
+            ],
+            ..Default::default()
+        };
+
+        let edit = doc.format_output("plot", &output).unwrap();
+
+        // The previous artifacts are replaced along with the output block
+        assert_eq!(edit.start_line, 5);
+        assert_eq!(edit.end_line, 13);
+        assert!(edit.content.ends_with(
+            "<!-- artifacts -->\n\
+             ![sine.png](data:image/png;base64,iVBORw==)\n\n\
+             [points.csv](data:text/csv;base64,eCx5Cg==)\n\n\
+             `huge.bin` (20000000 bytes, too large to embed)\n\n\
+             ![large plot.png](<doc.artifacts/plot/large plot.png>)\n\
+             <!-- /artifacts -->"
+        ));
+    }
+
     #[test]
     fn test_format_output_skip_non_output_blocks() {
         let markdown = r#"# Test Document
@@ -388,6 +947,7 @@ Some other content here.
             stdout: "Hello, world!\n".to_string(),
             stderr: "".to_string(),
             status: Some(0),
+            ..Default::default()
         };
 
         let edit = doc.format_output("hello", &output).unwrap();
@@ -421,6 +981,7 @@ Exit code: 0
             stdout: "New output!\n".to_string(),
             stderr: "".to_string(),
             status: Some(0),
+            ..Default::default()
         };
 
         let edit = doc.format_output("hello", &output).unwrap();
@@ -443,6 +1004,7 @@ for i in 1..=3 {
             stdout: "Line 1\nLine 2\nLine 3\n".to_string(),
             stderr: "".to_string(),
             status: Some(0),
+            ..Default::default()
         };
 
         let edit = doc.format_output("multiline", &output).unwrap();
@@ -473,6 +1035,7 @@ Exit code: 0
             stdout: "42".to_string(),
             stderr: "some warning".to_string(),
             status: Some(0),
+            ..Default::default()
         };
 
         let edit = doc.format_output("counter", &output).unwrap();
@@ -496,12 +1059,29 @@ println!("Hello, world!");
             stdout: "test".to_string(),
             stderr: "".to_string(),
             status: Some(0),
+            ..Default::default()
         };
 
         // This should panic because "nonexistent" block doesn't exist
         doc.format_output("nonexistent", &output).unwrap();
     }
 
+    #[test]
+    fn test_format_output_timed_out() {
+        let markdown = "```python slow\nwhile True: pass\n```\n";
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let output = ExecutionOutput {
+            stdout: "partial".to_string(),
+            timed_out: true,
+            ..Default::default()
+        };
+
+        let edit = doc.format_output("slow", &output).unwrap();
+        assert!(edit.content.contains("partial"));
+        assert!(edit.content.contains("Exit code: timed out"));
+    }
+
     #[test]
     fn test_filter() {
         let markdown = r#"# Foo
@@ -511,13 +1091,6 @@ Hello **world** ble
 "#;
 
         let doc = TanglitDoc::new_from_string(markdown).unwrap();
-        let output = ExecutionOutput {
-            stdout: "test".to_string(),
-            stderr: "".to_string(),
-            status: Some(0),
-        };
-
-        // This should panic because "nonexistent" block doesn't exist
         let r = doc.filter_content_for_doc().unwrap();
         assert!(
             &r == r#"# Foo
@@ -526,4 +1099,259 @@ Hello **world** ble
 "#
         );
     }
+
+    #[test]
+    fn test_language_macro_escape() {
+        let markdown = r#"```python main
+\@[cache][0]
+def f():
+    @[body]
+```
+
+```python body
+return 1
+```
+"#;
+        let config_path = format!("{}/resources/config", env!("CARGO_MANIFEST_DIR"));
+        with_var("TANGLIT_CONFIG_DIR", Some(config_path), || {
+            let doc = TanglitDoc::new_from_string(markdown).unwrap();
+            let blocks = doc.get_code_blocks().unwrap();
+            let tangled = blocks
+                .tangle_codeblock(blocks.get_block("main").unwrap())
+                .unwrap();
+            assert_eq!(tangled, "@[cache][0]\ndef f():\n    return 1");
+        });
+    }
+
+    #[test]
+    fn test_invalid_language_config_is_reported() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let python_dir = config_dir.path().join("executors").join("python");
+        std::fs::create_dir_all(&python_dir).unwrap();
+        std::fs::write(python_dir.join("config.toml"), "macro_syntax = [").unwrap();
+
+        with_var("TANGLIT_CONFIG_DIR", Some(config_dir.path()), || {
+            let doc = TanglitDoc::new_from_string("```python main\nprint(1)\n```\n").unwrap();
+            assert!(matches!(
+                doc.get_code_blocks(),
+                Err(DocError::ConfigError(ConfigError::ParseError(_)))
+            ));
+            // Languages without a configuration use the default syntax
+            let doc = TanglitDoc::new_from_string("```text notes\n@[main]\n```\n").unwrap();
+            assert!(doc.get_code_blocks().is_ok());
+        });
+    }
+
+    #[test]
+    fn test_document_macro_syntax() {
+        let markdown = r#"<!-- tanglit: macro_syntax=<<{name}>> -->
+<!-- tanglit: macro_escape=\ -->
+
+```python main
+@[decorator]
+def f():
+    <<body>>
+    return "\<<not a reference>>"
+```
+
+```python body
+x = 1
+```
+"#;
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let blocks = doc.get_code_blocks().unwrap();
+        let tangled = blocks
+            .tangle_codeblock(blocks.get_block("main").unwrap())
+            .unwrap();
+        assert_eq!(
+            tangled,
+            "@[decorator]\ndef f():\n    x = 1\n    return \"<<not a reference>>\""
+        );
+    }
+
+    #[test]
+    fn test_duplicate_tags_keep_the_first_block() {
+        let markdown = r#"```python hello
+print("first")
+```
+
+```python hello
+print("second")
+```
+
+```python other
+print("other")
+```
+"#;
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let blocks = doc.get_code_blocks().unwrap();
+        let duplicate = DuplicateTag {
+            tag: "hello".to_string(),
+            first: (1, 3),
+            second: (5, 7),
+        };
+        assert_eq!(blocks.duplicate_tags(), [duplicate.clone()]);
+        let hello = blocks.get_block("hello").unwrap();
+        assert_eq!(hello.code, "print(\"first\")");
+        assert_eq!(
+            blocks.tangle_codeblock(hello),
+            Err(TangleError::DuplicateTag(duplicate))
+        );
+        let other = blocks.get_block("other").unwrap();
+        assert_eq!(blocks.tangle_codeblock(other).unwrap(), "print(\"other\")");
+    }
+
+    #[test]
+    fn test_recorded_output() {
+        let markdown = r#"```python hello
+print("hello")
+```
+
+```output
+Output:
+hello
+
+
+Stderr:
+
+
+Exit code: 0
+```
+
+```python by_hand
+print(1)
+```
+```output
+1
+```
+
+```python none
+print(2)
+```
+"#;
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let recorded = doc.recorded_output("hello").unwrap().unwrap();
+        assert_eq!(recorded.stdout, "hello\n");
+        assert_eq!(recorded.stderr.as_deref(), Some(""));
+        assert_eq!(recorded.exit_code.as_deref(), Some("0"));
+        assert_eq!(recorded.start_line, 5);
+
+        // An output written by format_output is read back as it was
+        let output = ExecutionOutput {
+            stdout: "hello\n".to_string(),
+            status: Some(0),
+            ..Default::default()
+        };
+        let edit = doc.format_output("hello", &output).unwrap();
+        let content = edit.content.lines().collect::<Vec<&str>>();
+        let content = content[1..content.len() - 1].join("\n");
+        assert_eq!(RecordedOutput::parse(&content, 5), recorded);
+
+        let by_hand = doc.recorded_output("by_hand").unwrap().unwrap();
+        assert_eq!(by_hand.stdout, "1\n");
+        assert_eq!(by_hand.stderr, None);
+        assert_eq!(by_hand.exit_code, None);
+        assert_eq!(doc.recorded_output("none").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_input_block() {
+        let markdown = r#"```python main
+print(input())
+```
+
+```input for=main
+first
+```
+
+```input for=main
+second
+```
+"#;
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let blocks = doc.get_code_blocks().unwrap();
+        assert_eq!(blocks.get_input_block("main").unwrap().code, "first");
+        assert!(blocks.get_input_block("other").is_none());
+    }
+
+    #[test]
+    fn test_data_files() {
+        let markdown = r#"```file name=data/numbers.csv
+1,2
+```
+
+```python main
+print(open("data/numbers.csv").read())
+```
+
+```file name=data/numbers.csv
+3,4
+```
+
+```file
+not a data file
+```
+"#;
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let blocks = doc.get_code_blocks().unwrap();
+        assert_eq!(
+            doc.data_files(&blocks).unwrap(),
+            vec![ExtraFile {
+                path: PathBuf::from("data/numbers.csv"),
+                content: "1,2\n3,4\n".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unit_files() {
+        let markdown = r#"```c point file=point.h
+struct point { int x, y; };
+```
+
+```c point_impl file=point.c use=[point]
+#include "point.h"
+```
+
+```c main use=[point_impl]
+#include "point.h"
+```
+
+```c other file=other.c
+int other;
+```
+"#;
+
+        let doc = TanglitDoc::new_from_string(markdown).unwrap();
+        let blocks = doc.get_code_blocks().unwrap();
+        let main = blocks.get_block("main").unwrap();
+        // Only the files used by the block, directly or not, are written
+        assert_eq!(
+            doc.unit_files(&blocks, main).unwrap(),
+            vec![
+                ExtraFile {
+                    path: PathBuf::from("point.h"),
+                    content: "struct point { int x, y; };".to_string(),
+                },
+                ExtraFile {
+                    path: PathBuf::from("point.c"),
+                    content: "#include \"point.h\"".to_string(),
+                },
+            ]
+        );
+        // The block executed isn't written twice
+        let point_impl = blocks.get_block("point_impl").unwrap();
+        assert_eq!(
+            doc.unit_files(&blocks, point_impl).unwrap(),
+            vec![ExtraFile {
+                path: PathBuf::from("point.h"),
+                content: "struct point { int x, y; };".to_string(),
+            }]
+        );
+    }
 }
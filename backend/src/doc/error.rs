@@ -1,5 +1,6 @@
 use crate::doc::generate_pdf::GeneratePdfError;
-use crate::doc::{ParserError, TangleError};
+use crate::doc::{ParserError, TangleError, UntangleError};
+use crate::errors::ConfigError;
 use std::fmt;
 
 #[derive(Debug)]
@@ -7,6 +8,8 @@ pub enum DocError {
     ParseError(ParserError),
     TangleError(TangleError),
     GeneratePdfError(GeneratePdfError),
+    UntangleError(UntangleError),
+    ConfigError(ConfigError),
     IOError(String),
 }
 
@@ -16,6 +19,8 @@ impl fmt::Display for DocError {
             DocError::ParseError(e) => write!(f, "Error parsing blocks: {}", e),
             DocError::TangleError(e) => write!(f, "Error tangling block: {}", e),
             DocError::GeneratePdfError(e) => write!(f, "Error generating PDF: {}", e),
+            DocError::UntangleError(e) => write!(f, "Error untangling files: {}", e),
+            DocError::ConfigError(e) => write!(f, "Configuration error: {}", e),
             DocError::IOError(msg) => write!(f, "IO Error: {}", msg),
         }
     }
@@ -39,6 +44,18 @@ impl From<GeneratePdfError> for DocError {
     }
 }
 
+impl From<UntangleError> for DocError {
+    fn from(error: UntangleError) -> Self {
+        DocError::UntangleError(error)
+    }
+}
+
+impl From<ConfigError> for DocError {
+    fn from(error: ConfigError) -> Self {
+        DocError::ConfigError(error)
+    }
+}
+
 impl From<std::io::Error> for DocError {
     fn from(error: std::io::Error) -> Self {
         DocError::IOError(format!("IO Error: {}", error))
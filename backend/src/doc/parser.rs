@@ -2,14 +2,36 @@ pub mod code_block;
 pub mod exclude;
 pub mod slides;
 
-use code_block::CodeBlock;
+use code_block::{CodeBlock, RunSettings};
 use markdown::{
     ParseOptions,
     mdast::{Code, Node},
 };
-use std::collections::HashMap;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use std::fmt;
 
+// Regex to capture the macro syntax set by a document in an HTML comment,
+// as in `<!-- tanglit: macro_syntax=<<{name}>> -->`
+const MACRO_SYNTAX_SETTING_REGEX: &str = r"^<!--\s*tanglit:\s*macro_syntax\s*=\s*(.+?)\s*-->$";
+static MACRO_SYNTAX_SETTING_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(MACRO_SYNTAX_SETTING_REGEX).expect("Failed to compile MACRO_SYNTAX_SETTING_REGEX")
+});
+
+// Regex to capture the escape of the macros set by a document in an HTML comment,
+// as in `<!-- tanglit: macro_escape=\ -->`
+const MACRO_ESCAPE_SETTING_REGEX: &str = r"^<!--\s*tanglit:\s*macro_escape\s*=\s*(.+?)\s*-->$";
+static MACRO_ESCAPE_SETTING_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(MACRO_ESCAPE_SETTING_REGEX).expect("Failed to compile MACRO_ESCAPE_SETTING_REGEX")
+});
+
+// Regex to capture the settings of a document in an HTML comment, as in
+// `<!-- tanglit: env=[DATA=data.csv] cwd=. -->`
+const DOCUMENT_SETTINGS_REGEX: &str = r"^<!--\s*tanglit:\s*(.+?)\s*-->$";
+static DOCUMENT_SETTINGS_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(DOCUMENT_SETTINGS_REGEX).expect("Failed to compile DOCUMENT_SETTINGS_REGEX")
+});
+
 pub enum ParserError {
     InvalidInput(String),
     CodeBlockError(String),
@@ -52,6 +74,51 @@ pub fn parse_from_string(input: &str) -> Result<Node, ParserError> {
         .map_err(|e| ParserError::InvalidInput(format!("Failed to parse input: {}", e)))
 }
 
+/// Returns the macro syntax template set by the document, if any, overriding the ones
+/// of the languages of its blocks (see `LanguageConfig::macro_syntax`).
+pub fn parse_macro_syntax_from_ast(mdast: &Node) -> Option<String> {
+    mdast.children()?.iter().find_map(|node| match node {
+        Node::Html(html) => MACRO_SYNTAX_SETTING_RE
+            .captures(html.value.trim())
+            .map(|caps| caps[1].to_string()),
+        _ => None,
+    })
+}
+
+/// Returns the escape of the macros set by the document, if any, see `MacroSyntax::with_escape`.
+/// Like `macro_syntax`, it overrides the ones of the languages of its blocks.
+pub fn parse_macro_escape_from_ast(mdast: &Node) -> Option<String> {
+    mdast.children()?.iter().find_map(|node| match node {
+        Node::Html(html) => MACRO_ESCAPE_SETTING_RE
+            .captures(html.value.trim())
+            .map(|caps| caps[1].to_string()),
+        _ => None,
+    })
+}
+
+/// Returns how the blocks of the document run unless they declare otherwise, from the
+/// `env=[...]`, `args=[...]` and `cwd=` settings of its `<!-- tanglit: ... -->` comments.
+/// Variables of later comments are added to the earlier ones, and the other settings replace them.
+pub fn parse_run_settings_from_ast(mdast: &Node) -> RunSettings {
+    let mut settings = RunSettings::default();
+    let Some(children) = mdast.children() else {
+        return settings;
+    };
+    for node in children {
+        let Node::Html(html) = node else {
+            continue;
+        };
+        let Some(caps) = DOCUMENT_SETTINGS_RE.captures(html.value.trim()) else {
+            continue;
+        };
+        let comment = RunSettings::parse(&caps[1]);
+        settings.env.extend(comment.env);
+        settings.args = comment.args.or(settings.args);
+        settings.cwd = comment.cwd.or(settings.cwd);
+    }
+    settings
+}
+
 pub fn ast_to_markdown(ast: &Node) -> Result<String, ParserError> {
     let default_options = mdast_util_to_markdown::Options::default();
     let options = mdast_util_to_markdown::Options {
@@ -65,49 +132,74 @@ pub fn ast_to_markdown(ast: &Node) -> Result<String, ParserError> {
 }
 
 /// Parses code blocks from a given input string
-/// Returns a HashMap where the key is the tag of the code block and the value is the CodeBlock struct
+/// Returns every code block in document order
 /// If a code block does not have a tag, a default tag is assigned based on their line number in the input
-pub fn parse_code_blocks_from_ast(mdast: &Node) -> Result<HashMap<String, CodeBlock>, ParserError> {
+/// Blocks reusing the tag of an earlier block are kept, see `CodeBlocks::from_pieces`
+/// for how they're told apart from the ones appending to it.
+pub fn parse_code_blocks_from_ast(mdast: &Node) -> Result<Vec<CodeBlock>, ParserError> {
     // Extract code nodes from the tree
     let code_nodes = get_code_nodes_from_mdast(mdast)?;
 
     // Convert code nodes to CodeBlocks
     let code_blocks: Vec<CodeBlock> = code_nodes
         .into_iter()
-        .map(CodeBlock::from_code_node)
-        .collect::<Result<_, _>>()?;
-
-    // Create a HashMap from the code blocks
-    let code_block_map = code_blocks
-        .into_iter()
-        .map(|cb| (cb.tag.clone(), cb))
-        .collect();
+        .map(|(code_node, heading_path)| {
+            let mut code_block = CodeBlock::from_code_node(code_node)?;
+            code_block.heading_path = heading_path;
+            Ok(code_block)
+        })
+        .collect::<Result<_, ParserError>>()?;
 
-    Ok(code_block_map)
+    Ok(code_blocks)
 }
 
-fn get_code_nodes_from_mdast(mdast: &Node) -> Result<Vec<Code>, ParserError> {
+/// Collects every code node in the tree in document order, including the ones nested inside
+/// lists, blockquotes and other containers, along with the path of headings enclosing each one.
+fn get_code_nodes_from_mdast(mdast: &Node) -> Result<Vec<(Code, Vec<String>)>, ParserError> {
     let mut code_nodes = Vec::new();
-    let Some(children) = mdast.children() else {
-        return Ok(code_nodes);
+    collect_code_nodes(mdast, &mut Vec::new(), &mut code_nodes);
+    Ok(code_nodes)
+}
+
+/// Walks the children of `node`, keeping track of the current heading path as `(depth, title)`
+/// pairs. Headings found inside a container only apply until the end of that container.
+fn collect_code_nodes(
+    node: &Node,
+    headings: &mut Vec<(u8, String)>,
+    code_nodes: &mut Vec<(Code, Vec<String>)>,
+) {
+    let Some(children) = node.children() else {
+        return;
     };
     for child in children {
-        if let Node::Code(code_block) = child {
-            code_nodes.push(code_block.clone());
+        match child {
+            Node::Heading(heading) => {
+                headings.retain(|(depth, _)| *depth < heading.depth);
+                headings.push((heading.depth, child.to_string()));
+            }
+            Node::Code(code_block) => {
+                let heading_path = headings.iter().map(|(_, title)| title.clone()).collect();
+                code_nodes.push((code_block.clone(), heading_path));
+            }
+            _ => collect_code_nodes(child, &mut headings.clone(), code_nodes),
         }
     }
-    Ok(code_nodes)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     fn parse_code_blocks_from_string(
         input: &str,
     ) -> Result<HashMap<String, CodeBlock>, ParserError> {
         let mdast = parse_from_string(input)?;
-        parse_code_blocks_from_ast(&mdast)
+        let mut blocks = HashMap::new();
+        for block in parse_code_blocks_from_ast(&mdast)? {
+            blocks.entry(block.tag.clone()).or_insert(block);
+        }
+        Ok(blocks)
     }
 
     #[test]
@@ -264,4 +356,191 @@ println!("Hello, world!");
             r#"println!("Hello, world!");"#
         );
     }
+
+    #[test]
+    fn test_parse_code_blocks_nested_in_containers() {
+        let input = r#"1. Create the file:
+
+   ```python step_1
+   print("Step 1")
+   ```
+
+2. Then run it
+
+> ```python quoted
+> print("Quoted")
+> ```
+
+- outer
+  - inner
+
+    ```python deep
+    print("Deep")
+    ```
+"#;
+        let blocks = parse_code_blocks_from_string(input).unwrap();
+
+        assert_eq!(blocks.len(), 3);
+        let step = blocks.get("step_1").unwrap();
+        assert_eq!(step.code, r#"print("Step 1")"#);
+        assert_eq!(step.start_line, 3);
+        assert_eq!(step.end_line, 5);
+        let quoted = blocks.get("quoted").unwrap();
+        assert_eq!(quoted.code, r#"print("Quoted")"#);
+        assert_eq!(quoted.start_line, 9);
+        assert_eq!(quoted.end_line, 11);
+        let deep = blocks.get("deep").unwrap();
+        assert_eq!(deep.code, r#"print("Deep")"#);
+        assert_eq!(deep.start_line, 16);
+        assert_eq!(deep.end_line, 18);
+    }
+
+    #[test]
+    fn test_parse_code_blocks_heading_path() {
+        let input = r#"```python top
+print("top")
+```
+# Intro
+## Setup
+- item
+
+  ```python setup
+  print("setup")
+  ```
+## Usage
+```python usage
+print("usage")
+```
+# Appendix
+```python appendix
+print("appendix")
+```"#;
+        let blocks = parse_code_blocks_from_string(input).unwrap();
+
+        assert!(blocks.get("top").unwrap().heading_path.is_empty());
+        assert_eq!(
+            blocks.get("setup").unwrap().heading_path,
+            vec!["Intro".to_string(), "Setup".to_string()]
+        );
+        assert_eq!(
+            blocks.get("usage").unwrap().heading_path,
+            vec!["Intro".to_string(), "Usage".to_string()]
+        );
+        assert_eq!(
+            blocks.get("appendix").unwrap().heading_path,
+            vec!["Appendix".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_parse_code_blocks_duplicate_tag() {
+        let input = r#"```python hello
+print("first")
+```
+
+```python hello
+print("second")
+```"#;
+        let blocks = parse_code_blocks_from_ast(&parse_from_string(input).unwrap()).unwrap();
+
+        assert_eq!(blocks.len(), 2);
+        assert!(
+            blocks
+                .iter()
+                .all(|block| block.tag == "hello" && !block.append)
+        );
+    }
+
+    #[test]
+    fn test_parse_macro_syntax_from_ast() {
+        let with_setting = "# Doc\n\n<!-- tanglit: macro_syntax = <<{name}>> -->\n\ntext\n";
+        let without_setting = "# Doc\n\n<!-- some other comment -->\n";
+
+        assert_eq!(
+            parse_macro_syntax_from_ast(&parse_from_string(with_setting).unwrap()),
+            Some("<<{name}>>".to_string())
+        );
+        assert_eq!(
+            parse_macro_syntax_from_ast(&parse_from_string(without_setting).unwrap()),
+            None
+        );
+
+        let with_escape = "<!-- tanglit: macro_escape=\\ -->\n";
+        assert_eq!(
+            parse_macro_escape_from_ast(&parse_from_string(with_escape).unwrap()),
+            Some("\\".to_string())
+        );
+        assert_eq!(
+            parse_macro_escape_from_ast(&parse_from_string(without_setting).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_run_settings_from_ast() {
+        let markdown = "<!-- tanglit: env=[A=1] cwd=data -->\n\n# Doc\n\n<!-- tanglit: env=[B=2] args=[-v] -->\n";
+        let settings = parse_run_settings_from_ast(&parse_from_string(markdown).unwrap());
+        assert_eq!(
+            settings.env,
+            vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "2".to_string())
+            ]
+        );
+        assert_eq!(settings.args, Some(vec!["-v".to_string()]));
+        assert_eq!(settings.cwd, Some("data".to_string()));
+
+        let without_settings = "<!-- tanglit: macro_syntax=<<{name}>> -->\n";
+        assert!(
+            parse_run_settings_from_ast(&parse_from_string(without_settings).unwrap()).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_code_blocks_append_tag() {
+        let input = r#"```python hello
+print("first")
+```
+
+```python other
+print("other")
+```
+
+```python hello+=
+print("second")
+```
+
+```python append hello
+print("third")
+```
+
+```cpp c++
+int main() {}
+```
+
+```python append
+print("append")
+```"#;
+        let mdast = parse_from_string(input).unwrap();
+        let blocks = parse_code_blocks_from_ast(&mdast).unwrap();
+
+        let hello: Vec<(&str, bool)> = blocks
+            .iter()
+            .filter(|b| b.tag == "hello")
+            .map(|b| (b.code.as_str(), b.append))
+            .collect();
+        assert_eq!(
+            hello,
+            vec![
+                (r#"print("first")"#, false),
+                (r#"print("second")"#, true),
+                (r#"print("third")"#, true),
+            ]
+        );
+        // Tags ending with `+` or named `append` are ordinary tags
+        let cpp = blocks.iter().find(|b| b.tag == "c++").unwrap();
+        assert!(!cpp.append);
+        let append = blocks.iter().find(|b| b.tag == "append").unwrap();
+        assert!(!append.append);
+    }
 }
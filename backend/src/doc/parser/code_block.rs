@@ -1,8 +1,10 @@
 use super::ParserError;
+use crate::utils::{DURATION_UNITS, parse_duration};
 use markdown::mdast::Code;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::Serialize;
+use std::time::Duration;
 
 // Regex to capture `use=[...]`
 pub(crate) const USE_REGEX: &str = r"use=\[([^\]]*)\]";
@@ -14,6 +16,141 @@ const EXPORT_REGEX: &str = r"export\s*=\s*([^\s]+)";
 static EXPORT_RE: Lazy<Regex> =
     Lazy::new(|| Regex::new(EXPORT_REGEX).expect("Failed to compile EXPORT_REGEX"));
 
+// Regex to capture `params=[...]`
+const PARAMS_REGEX: &str = r"params=\[([^\]]*)\]";
+static PARAMS_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(PARAMS_REGEX).expect("Failed to compile PARAMS_REGEX"));
+
+// Regex to capture `timeout=`, as in `timeout=5s` or `timeout=500ms`, with the units of
+// `parse_duration`
+static TIMEOUT_RE: Lazy<Regex> = Lazy::new(|| {
+    let units: Vec<&str> = DURATION_UNITS.iter().map(|(unit, _)| *unit).collect();
+    let timeout_regex = format!(
+        r"(?:^|\s)timeout\s*=\s*([0-9]+(?:{})?)(?:\s|$)",
+        units.join("|")
+    );
+    Regex::new(&timeout_regex).expect("Failed to compile TIMEOUT_REGEX")
+});
+
+// Regex to capture `stdin=`, a file whose content is fed to the block when executed
+const STDIN_REGEX: &str = r"(?:^|\s)stdin\s*=\s*([^\s]+)";
+static STDIN_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(STDIN_REGEX).expect("Failed to compile STDIN_REGEX"));
+
+// Regex to capture `for=`, the block an `input` block is fed to when executed
+const INPUT_FOR_REGEX: &str = r"(?:^|\s)for\s*=\s*([^\s]+)";
+static INPUT_FOR_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(INPUT_FOR_REGEX).expect("Failed to compile INPUT_FOR_REGEX"));
+
+// Regex to capture `session=`, the interpreter session a block runs in
+const SESSION_REGEX: &str = r"(?:^|\s)session\s*=\s*([^\s]+)";
+static SESSION_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(SESSION_REGEX).expect("Failed to compile SESSION_REGEX"));
+
+// Regex to capture `env=[KEY=VALUE, ...]`, variables added to the environment of the block
+const ENV_REGEX: &str = r"env=\[([^\]]*)\]";
+static ENV_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(ENV_REGEX).expect("Failed to compile ENV_REGEX"));
+
+// Regex to capture `args=[...]`, the command line arguments of the block
+const ARGS_REGEX: &str = r"args=\[([^\]]*)\]";
+static ARGS_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(ARGS_REGEX).expect("Failed to compile ARGS_REGEX"));
+
+// Regex to capture `cwd=`, the directory the block runs from
+const CWD_REGEX: &str = r"(?:^|\s)cwd\s*=\s*([^\s]+)";
+static CWD_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(CWD_REGEX).expect("Failed to compile CWD_REGEX"));
+
+// Regex to capture `name=`, the file a `file` block holds the content of
+const NAME_REGEX: &str = r"(?:^|\s)name\s*=\s*([^\s]+)";
+static NAME_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(NAME_REGEX).expect("Failed to compile NAME_REGEX"));
+
+// Regex to capture `file=`, the source file a block is written to next to the code it's executed with
+const FILE_REGEX: &str = r"(?:^|\s)file\s*=\s*([^\s]+)";
+static FILE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(FILE_REGEX).expect("Failed to compile FILE_REGEX"));
+
+/// Language of the blocks holding the standard input of another block, as in ```` ```input for=tag ````.
+pub const INPUT_LANGUAGE: &str = "input";
+
+/// Language of the blocks holding a data file, as in ```` ```file name=data.csv ````, which is
+/// written next to the code of every execution and exported to its name.
+pub const FILE_LANGUAGE: &str = "file";
+
+/// Language of the blocks holding the output of the block above them, see `TanglitDoc::format_output`.
+pub const OUTPUT_LANGUAGE: &str = "output";
+
+// Suffix marking a block tag as a continuation of an earlier chunk with the same tag, as in
+// `tag+=`. A lone `+` isn't one, since it ends tags like `c++`.
+pub(crate) const APPEND_SUFFIX: &str = "+=";
+
+// Regex to capture `order=`
+const ORDER_REGEX: &str = r"(?:^|\s)order\s*=\s*(-?[0-9]+)";
+static ORDER_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(ORDER_REGEX).expect("Failed to compile ORDER_REGEX"));
+
+// Regex to capture the standalone `append` keyword, an alternative to the suffix above
+const APPEND_KEYWORD_REGEX: &str = r"(^|\s)append(\s|$)";
+static APPEND_KEYWORD_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(APPEND_KEYWORD_REGEX).expect("Failed to compile APPEND_KEYWORD_REGEX"));
+
+/// How a block is run, declared with `env=[KEY=VALUE, ...]`, `args=[...]` and `cwd=`,
+/// either by the block or for the whole document (see `TanglitDoc::run_settings`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunSettings {
+    /// Variables added to the environment of the execution.
+    pub env: Vec<(String, String)>,
+    /// Command line arguments of the program, `None` when not declared.
+    pub args: Option<Vec<String>>,
+    /// Directory the execution runs from, relative to the markdown file. The data files of the
+    /// document stay in the scratch directory, see `execution::WORK_DIR_ENV_VAR`.
+    pub cwd: Option<String>,
+}
+
+impl RunSettings {
+    /// Parses the `env=[...]`, `args=[...]` and `cwd=` attributes of `metadata`.
+    /// Entries of `env` without `=` are ignored.
+    pub fn parse(metadata: &str) -> RunSettings {
+        let env = CodeBlock::parse_list(&ENV_RE, metadata)
+            .iter()
+            .filter_map(|entry| {
+                let (key, value) = entry.split_once('=')?;
+                Some((key.trim().to_string(), value.trim().to_string()))
+            })
+            .filter(|(key, _)| !key.is_empty())
+            .collect();
+        let args = ARGS_RE
+            .is_match(metadata)
+            .then(|| CodeBlock::parse_list(&ARGS_RE, metadata));
+        let cwd = CWD_RE.captures(metadata).map(|caps| caps[1].to_string());
+        RunSettings { env, args, cwd }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.env.is_empty() && self.args.is_none() && self.cwd.is_none()
+    }
+}
+
+/// Attributes declared in the info string of a code block, after its language.
+#[derive(Debug, Default)]
+struct BlockMetadata {
+    tag: Option<String>,
+    imports: Vec<String>,
+    export: Option<String>,
+    order: Option<i64>,
+    append: bool,
+    params: Vec<String>,
+    timeout: Option<Duration>,
+    stdin_file: Option<String>,
+    input_for: Option<String>,
+    session: Option<String>,
+    run: RunSettings,
+    name: Option<String>,
+    file: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct CodeBlock {
     pub language: Option<String>,
@@ -21,8 +158,34 @@ pub struct CodeBlock {
     pub tag: String,
     pub imports: Vec<String>,
     pub export: Option<String>,
+    /// Position of the block within its export file, blocks without it count as `order=0`.
+    pub order: Option<i64>,
     pub start_line: usize,
     pub end_line: usize,
+    /// Titles of the headings enclosing the block, outermost first.
+    pub heading_path: Vec<String>,
+    /// Whether the block was declared as `tag+=` or with the `append` keyword,
+    /// extending an earlier chunk with the same tag.
+    pub append: bool,
+    /// Parameters declared with `params=[x,y]`. References to the block must pass
+    /// as many arguments, as in `@[swap(a, b)]`, which replace `@[x]` and `@[y]` in its code.
+    pub params: Vec<String>,
+    /// Time limit to execute the block, overriding the one of its language.
+    pub timeout: Option<Duration>,
+    /// File declared with `stdin=`, fed to the block when it's executed.
+    pub stdin_file: Option<String>,
+    /// For `input` blocks, the tag of the block they are the standard input of, set with `for=`.
+    pub input_for: Option<String>,
+    /// Interpreter session declared with `session=`. Blocks of the same language and session
+    /// run one after the other in the same process, keeping its state between them.
+    pub session: Option<String>,
+    /// Environment, arguments and directory declared with `env=[...]`, `args=[...]` and `cwd=`,
+    /// overriding the ones of the document.
+    pub run: RunSettings,
+    /// Source file declared with `file=`, like a header or a module. The block isn't executed
+    /// on its own, it's written to this file next to the code of the blocks using it with
+    /// `use=[...]`, instead of being copied into their code.
+    pub file: Option<String>,
 }
 
 impl CodeBlock {
@@ -41,8 +204,18 @@ impl CodeBlock {
             tag,
             imports,
             export,
+            order: None,
             start_line,
             end_line,
+            heading_path: Vec::new(),
+            append: false,
+            params: Vec::new(),
+            timeout: None,
+            stdin_file: None,
+            input_for: None,
+            session: None,
+            run: RunSettings::default(),
+            file: None,
         }
     }
 
@@ -50,12 +223,24 @@ impl CodeBlock {
         Self::new(None, code, "".to_string(), Vec::new(), None, 0, 0)
     }
 
+    /// Returns the markdown line where the code of the block starts.
+    /// That's the line after the opening fence, except for indented code blocks, which have no fences.
+    pub fn content_start_line(&self) -> usize {
+        let block_lines = self.end_line.saturating_sub(self.start_line) + 1;
+        if block_lines == self.code.lines().count() {
+            self.start_line
+        } else {
+            self.start_line + 1
+        }
+    }
+
     /// Creates a CodeBlock from a Code node, extracting the language, code, tag, and imports.
     /// If the tag is not specified in the code block, it defaults to the line number of the code block.
+    /// A tag written as `tag+=`, or the `append` keyword followed by a tag, marks the block as
+    /// appending to an earlier chunk named `tag`.
     pub fn from_code_node(code_block: Code) -> Result<Self, ParserError> {
         let language = code_block.lang;
-        let (tag, imports, export) =
-            Self::parse_metadata(code_block.meta.unwrap_or_default().as_str());
+        let metadata = Self::parse_metadata(code_block.meta.unwrap_or_default().as_str());
         let position = code_block.position.as_ref();
         let start_line = position
             .ok_or_else(|| ParserError::CodeBlockError("Block position not found".to_string()))?
@@ -65,50 +250,137 @@ impl CodeBlock {
             .ok_or_else(|| ParserError::CodeBlockError("Block position not found".to_string()))?
             .end
             .line;
-        let tag = match tag {
-            Some(t) => t,
-            None => start_line.to_string(),
+        let (tag, append) = match metadata.tag {
+            Some(t) => match t.strip_suffix(APPEND_SUFFIX) {
+                Some(name) if !name.is_empty() => (name.to_string(), true),
+                _ => (t, metadata.append),
+            },
+            None => (start_line.to_string(), false),
         };
 
-        Ok(Self::new(
+        let mut block = Self::new(
             language,
             code_block.value,
             tag,
-            imports,
-            export,
+            metadata.imports,
+            metadata.export,
             start_line,
             end_line,
-        ))
+        );
+        block.order = metadata.order;
+        block.append = append;
+        block.params = metadata.params;
+        block.timeout = metadata.timeout;
+        block.stdin_file = metadata.stdin_file;
+        block.input_for = metadata.input_for;
+        block.session = metadata.session;
+        block.run = metadata.run;
+        block.file = metadata.file;
+        // A data file is exported like code, only under its own name
+        if block.language.as_deref() == Some(FILE_LANGUAGE) && metadata.name.is_some() {
+            block.export = metadata.name;
+        }
+        Ok(block)
     }
 
-    fn parse_metadata(metadata: &str) -> (Option<String>, Vec<String>, Option<String>) {
+    fn parse_metadata(metadata: &str) -> BlockMetadata {
         // Extract imports
-        let imports: Vec<String> = USE_RE
-            .captures(metadata)
-            .map(|caps| {
-                caps[1]
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect()
-            })
-            .unwrap_or_default();
+        let imports = Self::parse_list(&USE_RE, metadata);
+
+        // Extract params
+        let params = Self::parse_list(&PARAMS_RE, metadata);
 
         // Extract export
 
         let export = EXPORT_RE.captures(metadata).map(|caps| caps[1].to_string());
 
-        // Remove the `use=[...]` and `export=` part to get the block tag
-        let metadata_without_use = USE_RE.replace(metadata, "");
-        let metadata_clean = EXPORT_RE.replace(&metadata_without_use, "");
+        // Extract order
+        let order = ORDER_RE
+            .captures(metadata)
+            .and_then(|caps| caps[1].parse().ok());
+
+        // Extract timeout
+        let timeout = TIMEOUT_RE
+            .captures(metadata)
+            .and_then(|caps| parse_duration(&caps[1]));
+
+        // Extract the standard input
+        let stdin_file = STDIN_RE.captures(metadata).map(|caps| caps[1].to_string());
+        let input_for = INPUT_FOR_RE
+            .captures(metadata)
+            .map(|caps| caps[1].to_string());
 
-        // Take the first word that is not part of `use=` and `export=` as the tag
-        let tag = metadata_clean
+        // Extract the session
+        let session = SESSION_RE
+            .captures(metadata)
+            .map(|caps| caps[1].to_string());
+
+        // Extract the environment, arguments and directory
+        let run = RunSettings::parse(metadata);
+
+        // Extract the name of a data file, and the file of a block of a multi-file unit
+        let name = NAME_RE.captures(metadata).map(|caps| caps[1].to_string());
+        let file = FILE_RE.captures(metadata).map(|caps| caps[1].to_string());
+
+        let mut append = APPEND_KEYWORD_RE.is_match(metadata);
+
+        // Remove the `name=`, `file=`, `env=[...]`, `args=[...]`, `cwd=`, `use=[...]`, `params=[...]`, `export=`,
+        // `order=`, `timeout=`, `stdin=`, `for=`, `session=` and `append` parts to get the block tag
+        let metadata_without_name = NAME_RE.replace(metadata, " ");
+        let metadata_without_file = FILE_RE.replace(&metadata_without_name, " ");
+        let metadata_without_env = ENV_RE.replace(&metadata_without_file, "");
+        let metadata_without_args = ARGS_RE.replace(&metadata_without_env, "");
+        let metadata_without_cwd = CWD_RE.replace(&metadata_without_args, " ");
+        let metadata_without_params = PARAMS_RE.replace(&metadata_without_cwd, "");
+        let metadata_without_use = USE_RE.replace(&metadata_without_params, "");
+        let metadata_without_export = EXPORT_RE.replace(&metadata_without_use, "");
+        let metadata_without_order = ORDER_RE.replace(&metadata_without_export, " ");
+        let metadata_without_timeout = TIMEOUT_RE.replace(&metadata_without_order, " ");
+        let metadata_without_stdin = STDIN_RE.replace(&metadata_without_timeout, " ");
+        let metadata_without_input_for = INPUT_FOR_RE.replace(&metadata_without_stdin, " ");
+        let metadata_without_session = SESSION_RE.replace(&metadata_without_input_for, " ");
+        let metadata_clean = APPEND_KEYWORD_RE.replace_all(&metadata_without_session, " ");
+
+        // Take the first word that is not part of the attributes above as the tag
+        let mut tag = metadata_clean
             .split_whitespace()
             .next()
             .map(|s| s.to_string());
+        // Without another tag, `append` is the tag of the block rather than the keyword
+        if tag.is_none() && append {
+            tag = Some("append".to_string());
+            append = false;
+        }
 
-        (tag, imports, export)
+        BlockMetadata {
+            tag,
+            imports,
+            export,
+            order,
+            append,
+            params,
+            timeout,
+            stdin_file,
+            input_for,
+            session,
+            run,
+            name,
+            file,
+        }
+    }
+
+    // Parses a comma separated list like `[a, b]` captured by `regex`
+    fn parse_list(regex: &Regex, metadata: &str) -> Vec<String> {
+        regex
+            .captures(metadata)
+            .map(|caps| {
+                caps[1]
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 }
 
@@ -119,7 +391,12 @@ mod tests {
     #[test]
     fn test_parse_metadata_with_use() {
         let metadata = "use=[block1,block2] tag1";
-        let (tag, imports, export) = CodeBlock::parse_metadata(metadata);
+        let BlockMetadata {
+            tag,
+            imports,
+            export,
+            ..
+        } = CodeBlock::parse_metadata(metadata);
         assert_eq!(tag, Some("tag1".to_string()));
         assert_eq!(imports, vec!["block1".to_string(), "block2".to_string()]);
         assert!(export.is_none());
@@ -128,7 +405,12 @@ mod tests {
     #[test]
     fn test_parse_metadata_with_only_tag() {
         let metadata = "tag2";
-        let (tag, imports, export) = CodeBlock::parse_metadata(metadata);
+        let BlockMetadata {
+            tag,
+            imports,
+            export,
+            ..
+        } = CodeBlock::parse_metadata(metadata);
         assert_eq!(tag, Some("tag2".to_string()));
         assert!(imports.is_empty());
         assert!(export.is_none());
@@ -137,7 +419,12 @@ mod tests {
     #[test]
     fn test_parse_metadata_empty() {
         let metadata = "";
-        let (tag, imports, export) = CodeBlock::parse_metadata(metadata);
+        let BlockMetadata {
+            tag,
+            imports,
+            export,
+            ..
+        } = CodeBlock::parse_metadata(metadata);
         assert!(tag.is_none());
         assert!(imports.is_empty());
         assert!(export.is_none());
@@ -146,16 +433,134 @@ mod tests {
     #[test]
     fn test_parse_metadata_with_export_and_tag() {
         let metadata = "export=main.c tag3";
-        let (tag, imports, export) = CodeBlock::parse_metadata(metadata);
+        let BlockMetadata {
+            tag,
+            imports,
+            export,
+            ..
+        } = CodeBlock::parse_metadata(metadata);
         assert_eq!(tag, Some("tag3".to_string()));
         assert!(imports.is_empty());
         assert_eq!(export, Some("main.c".to_string()));
     }
 
+    #[test]
+    fn test_parse_metadata_with_params() {
+        let metadata = "swap params=[x, y] use=[tmp]";
+        let BlockMetadata {
+            tag,
+            imports,
+            params,
+            ..
+        } = CodeBlock::parse_metadata(metadata);
+        assert_eq!(tag, Some("swap".to_string()));
+        assert_eq!(imports, vec!["tmp".to_string()]);
+        assert_eq!(params, vec!["x".to_string(), "y".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_metadata_with_timeout() {
+        let cases = [
+            ("slow timeout=5s", Some(Duration::from_secs(5))),
+            ("timeout=250ms slow", Some(Duration::from_millis(250))),
+            ("slow timeout=2m", Some(Duration::from_secs(120))),
+            ("slow timeout=3", Some(Duration::from_secs(3))),
+            ("slow timeout=1d", Some(Duration::from_secs(24 * 3600))),
+        ];
+        for (metadata, expected) in cases {
+            let BlockMetadata { tag, timeout, .. } = CodeBlock::parse_metadata(metadata);
+            assert_eq!(tag, Some("slow".to_string()));
+            assert_eq!(timeout, expected);
+        }
+
+        let BlockMetadata { tag, timeout, .. } = CodeBlock::parse_metadata("mytimeout=5s");
+        assert_eq!(tag, Some("mytimeout=5s".to_string()));
+        assert!(timeout.is_none());
+    }
+
+    #[test]
+    fn test_parse_metadata_with_stdin() {
+        let BlockMetadata {
+            tag, stdin_file, ..
+        } = CodeBlock::parse_metadata("main stdin=data/numbers.txt");
+        assert_eq!(tag, Some("main".to_string()));
+        assert_eq!(stdin_file, Some("data/numbers.txt".to_string()));
+
+        let BlockMetadata {
+            tag, stdin_file, ..
+        } = CodeBlock::parse_metadata("mystdin=x");
+        assert_eq!(tag, Some("mystdin=x".to_string()));
+        assert!(stdin_file.is_none());
+
+        let BlockMetadata { tag, input_for, .. } = CodeBlock::parse_metadata("for=main");
+        assert!(tag.is_none());
+        assert_eq!(input_for, Some("main".to_string()));
+    }
+
+    #[test]
+    fn test_parse_metadata_with_session() {
+        let BlockMetadata { tag, session, .. } =
+            CodeBlock::parse_metadata("load_data session=analysis");
+        assert_eq!(tag, Some("load_data".to_string()));
+        assert_eq!(session, Some("analysis".to_string()));
+
+        let BlockMetadata { session, .. } = CodeBlock::parse_metadata("load_data");
+        assert!(session.is_none());
+
+        let BlockMetadata { tag, session, .. } = CodeBlock::parse_metadata("nosession=y");
+        assert_eq!(tag, Some("nosession=y".to_string()));
+        assert!(session.is_none());
+    }
+
+    #[test]
+    fn test_parse_metadata_with_run_settings() {
+        let BlockMetadata { tag, run, .. } = CodeBlock::parse_metadata(
+            "report env=[DATA=data/input.csv, DEBUG=1, bad] args=[--rows, 10] cwd=examples",
+        );
+        assert_eq!(tag, Some("report".to_string()));
+        assert_eq!(
+            run.env,
+            vec![
+                ("DATA".to_string(), "data/input.csv".to_string()),
+                ("DEBUG".to_string(), "1".to_string())
+            ]
+        );
+        assert_eq!(run.args, Some(vec!["--rows".to_string(), "10".to_string()]));
+        assert_eq!(run.cwd, Some("examples".to_string()));
+
+        let BlockMetadata { run, .. } = CodeBlock::parse_metadata("report args=[]");
+        assert_eq!(run.args, Some(Vec::new()));
+        assert!(CodeBlock::parse_metadata("report").run.is_empty());
+
+        let BlockMetadata { tag, run, .. } = CodeBlock::parse_metadata("mycwd=src");
+        assert_eq!(tag, Some("mycwd=src".to_string()));
+        assert!(run.cwd.is_none());
+    }
+
+    #[test]
+    fn test_parse_metadata_with_name() {
+        let BlockMetadata { tag, name, .. } = CodeBlock::parse_metadata("name=data/input.csv");
+        assert!(tag.is_none());
+        assert_eq!(name, Some("data/input.csv".to_string()));
+
+        let BlockMetadata { tag, name, .. } = CodeBlock::parse_metadata("rename=x");
+        assert_eq!(tag, Some("rename=x".to_string()));
+        assert!(name.is_none());
+
+        let BlockMetadata { tag, file, .. } = CodeBlock::parse_metadata("point file=point.h");
+        assert_eq!(tag, Some("point".to_string()));
+        assert_eq!(file, Some("point.h".to_string()));
+    }
+
     #[test]
     fn test_parse_metadata_with_use_and_export() {
         let metadata = "use=[block1, block2] export=main.c";
-        let (tag, imports, export) = CodeBlock::parse_metadata(metadata);
+        let BlockMetadata {
+            tag,
+            imports,
+            export,
+            ..
+        } = CodeBlock::parse_metadata(metadata);
         assert!(tag.is_none());
         assert_eq!(imports, vec!["block1".to_string(), "block2".to_string()]);
         assert_eq!(export, Some("main.c".to_string()));
@@ -164,9 +569,35 @@ mod tests {
     #[test]
     fn test_parse_metadata_with_use_export_and_tag() {
         let metadata = "use=[block1] export=main.c tag4";
-        let (tag, imports, export) = CodeBlock::parse_metadata(metadata);
+        let BlockMetadata {
+            tag,
+            imports,
+            export,
+            ..
+        } = CodeBlock::parse_metadata(metadata);
         assert_eq!(tag, Some("tag4".to_string()));
         assert_eq!(imports, vec!["block1".to_string()]);
         assert_eq!(export, Some("main.c".to_string()));
     }
+
+    #[test]
+    fn test_parse_metadata_with_order_and_append() {
+        let metadata = "order=-2 export=main.c append tag5";
+        let parsed = CodeBlock::parse_metadata(metadata);
+        assert_eq!(parsed.tag, Some("tag5".to_string()));
+        assert_eq!(parsed.export, Some("main.c".to_string()));
+        assert_eq!(parsed.order, Some(-2));
+        assert!(parsed.append);
+
+        let parsed = CodeBlock::parse_metadata("border=3");
+        assert_eq!(parsed.tag, Some("border=3".to_string()));
+        assert_eq!(parsed.order, None);
+    }
+
+    #[test]
+    fn test_parse_metadata_with_append_as_tag() {
+        let parsed = CodeBlock::parse_metadata("append export=main.c");
+        assert_eq!(parsed.tag, Some("append".to_string()));
+        assert!(!parsed.append);
+    }
 }
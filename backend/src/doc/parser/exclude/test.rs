@@ -17,9 +17,9 @@ mod tests {
         let in_file = dir.join(format!("{file}-in.md"));
         let out_file = dir.join(format!("{file}-out.md"));
         let input = std::fs::read_to_string(&in_file)
-            .expect(format!("Failed to read file {}", in_file.display()).as_str());
+            .unwrap_or_else(|_| panic!("Failed to read file {}", in_file.display()));
         let expected_output = std::fs::read_to_string(&out_file)
-            .expect(format!("Failed to read {}", out_file.display()).as_str());
+            .unwrap_or_else(|_| panic!("Failed to read {}", out_file.display()));
 
         let ast_with_exclusions = exclude_from_markdown(input.as_str(), target);
         let actual_output = mdast_util_to_markdown::to_markdown(&ast_with_exclusions)
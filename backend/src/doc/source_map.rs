@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+
+// Placeholders that can be used in a language's `line_directive` template
+const LINE_PLACEHOLDER: &str = "{line}";
+const FILE_PLACEHOLDER: &str = "{file}";
+
+/// Maps every line of a tangled output back to the markdown line it was written in.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceMap {
+    /// Path of the markdown file the lines refer to.
+    pub file: String,
+    /// `lines[i]` is the markdown line (1-based) of output line `i + 1`,
+    /// or `None` for lines that don't come from the document, such as line directives.
+    pub lines: Vec<Option<usize>>,
+}
+
+impl SourceMap {
+    /// Returns the markdown line for a given 1-based output line, if there is one.
+    pub fn source_line(&self, output_line: usize) -> Option<usize> {
+        output_line
+            .checked_sub(1)
+            .and_then(|i| self.lines.get(i).copied().flatten())
+    }
+}
+
+/// The result of tangling a block, along with the source map of the tangled code.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TangledCode {
+    pub code: String,
+    pub source_map: SourceMap,
+}
+
+/// Code under construction that keeps the markdown line of each of its lines.
+/// The lines are the ones yielded by `code.split('\n')`, so `lines` is never empty.
+#[derive(Debug, Clone)]
+pub(crate) struct MappedCode {
+    pub code: String,
+    pub lines: Vec<Option<usize>>,
+}
+
+impl MappedCode {
+    /// Starts an empty output whose first line comes from `first_line`.
+    pub fn new(first_line: Option<usize>) -> Self {
+        Self {
+            code: String::new(),
+            lines: vec![first_line],
+        }
+    }
+
+    /// Appends text from the markdown document, where `first_line` is the markdown line
+    /// of the first character of `text`.
+    pub fn push_source(&mut self, text: &str, first_line: usize) {
+        self.push_text(text, Some(first_line));
+    }
+
+    /// Appends text that doesn't come from the document, such as template code.
+    pub fn push_generated(&mut self, text: &str) {
+        self.push_text(text, None);
+    }
+
+    fn push_text(&mut self, text: &str, first_line: Option<usize>) {
+        let mut line = first_line;
+        for (i, segment) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.code.push('\n');
+                line = line.map(|l| l + 1);
+                self.lines.push(line);
+            }
+            self.code.push_str(segment);
+        }
+    }
+
+    /// Appends another mapped output, continuing the current line.
+    /// If the current line only holds indentation, it takes the origin of the appended code.
+    pub fn push_mapped(&mut self, other: MappedCode) {
+        if self.current_line().trim().is_empty() {
+            if let Some(last) = self.lines.last_mut() {
+                *last = other.lines[0];
+            }
+        }
+        self.code.push_str(&other.code);
+        self.lines.extend(other.lines.into_iter().skip(1));
+    }
+
+    /// Joins several outputs with newlines between them.
+    pub fn join(parts: Vec<MappedCode>) -> MappedCode {
+        let mut parts = parts.into_iter();
+        let Some(mut joined) = parts.next() else {
+            return MappedCode::new(None);
+        };
+        for part in parts {
+            joined.code.push('\n');
+            joined.lines.extend(part.lines);
+            joined.code.push_str(&part.code);
+        }
+        joined
+    }
+
+    /// Puts `before` and `after` on their own lines around the code.
+    pub fn surround_with(self, before: &str, after: &str) -> MappedCode {
+        let mut result = MappedCode::new(None);
+        result.push_generated(&format!("{}\n", before));
+        result.push_mapped(self);
+        result.push_generated(&format!("\n{}", after));
+        result
+    }
+
+    /// Replaces the code with `code`, which must be the result of editing the lines
+    /// of the current code in place, such as changing their indentation.
+    pub fn replace_code(&mut self, code: String) {
+        self.lines.truncate(code.split('\n').count());
+        self.code = code;
+    }
+
+    /// Inserts a line directive before every line that doesn't follow the previous one
+    /// in the markdown document, so compilers report positions in the document itself.
+    pub fn with_line_directives(self, directive_template: &str, file: &str) -> MappedCode {
+        let mut result = MappedCode {
+            code: String::new(),
+            lines: vec![],
+        };
+        let mut previous: Option<usize> = None;
+        for (line, origin) in self.code.split('\n').zip(self.lines) {
+            if let Some(origin) = origin {
+                if previous.map(|p| p + 1) != Some(origin) {
+                    let indentation = &line[..line.len() - line.trim_start().len()];
+                    let directive = directive_template
+                        .replace(LINE_PLACEHOLDER, &origin.to_string())
+                        .replace(FILE_PLACEHOLDER, file);
+                    result
+                        .code
+                        .push_str(&format!("{}{}\n", indentation, directive));
+                    result.lines.push(None);
+                }
+            }
+            result.code.push_str(line);
+            result.code.push('\n');
+            result.lines.push(origin);
+            previous = origin;
+        }
+        // Every line was followed by a newline, remove the one after the last line
+        result.code.pop();
+        result
+    }
+
+    pub fn into_tangled_code(self, file: &str) -> TangledCode {
+        TangledCode {
+            code: self.code,
+            source_map: SourceMap {
+                file: file.to_string(),
+                lines: self.lines,
+            },
+        }
+    }
+
+    fn current_line(&self) -> &str {
+        let start = self.code.rfind('\n').map_or(0, |i| i + 1);
+        &self.code[start..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_source_and_mapped() {
+        let mut code = MappedCode::new(Some(10));
+        code.push_source("fn main() {\n    ", 10);
+
+        let mut inner = MappedCode::new(Some(20));
+        inner.push_source("let x = 1;\nlet y = 2;", 20);
+        code.push_mapped(inner);
+        code.push_source("\n}", 11);
+
+        assert_eq!(code.code, "fn main() {\n    let x = 1;\nlet y = 2;\n}");
+        assert_eq!(code.lines, vec![Some(10), Some(20), Some(21), Some(12)]);
+    }
+
+    #[test]
+    fn test_inline_macro_keeps_host_line() {
+        let mut code = MappedCode::new(Some(3));
+        code.push_source("x = ", 3);
+        let mut inner = MappedCode::new(Some(8));
+        inner.push_source("42", 8);
+        code.push_mapped(inner);
+
+        assert_eq!(code.code, "x = 42");
+        assert_eq!(code.lines, vec![Some(3)]);
+    }
+
+    #[test]
+    fn test_push_generated() {
+        let mut code = MappedCode::new(None);
+        code.push_generated("int main(void){\n    ");
+        let mut body = MappedCode::new(Some(5));
+        body.push_source("return 0;", 5);
+        code.push_mapped(body);
+        code.push_generated("\n}");
+
+        assert_eq!(code.code, "int main(void){\n    return 0;\n}");
+        assert_eq!(code.lines, vec![None, Some(5), None]);
+    }
+
+    #[test]
+    fn test_with_line_directives() {
+        let code = MappedCode {
+            code: "int x;\n    x = 1;\nreturn x;".to_string(),
+            lines: vec![Some(2), Some(7), Some(8)],
+        };
+
+        let tangled = code
+            .with_line_directives("#line {line} \"{file}\"", "doc.md")
+            .into_tangled_code("doc.md");
+
+        assert_eq!(
+            tangled.code,
+            "#line 2 \"doc.md\"\nint x;\n    #line 7 \"doc.md\"\n    x = 1;\nreturn x;"
+        );
+        assert_eq!(
+            tangled.source_map.lines,
+            vec![None, Some(2), None, Some(7), Some(8)]
+        );
+        assert_eq!(tangled.source_map.source_line(4), Some(7));
+        assert_eq!(tangled.source_map.source_line(1), None);
+        assert_eq!(tangled.source_map.source_line(0), None);
+    }
+}
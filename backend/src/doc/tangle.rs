@@ -1,17 +1,36 @@
-use crate::doc::CodeBlock;
+mod macro_syntax;
+
+use crate::doc::parser::code_block::APPEND_SUFFIX;
+use crate::doc::source_map::{MappedCode, TangledCode};
+use crate::doc::untangle::{MARKER_BEGIN, MARKER_END, format_marker};
+use crate::doc::{CodeBlock, INPUT_LANGUAGE};
+use crate::execution::export_path;
 use crate::utils::{get_indentation_at_offset, set_indentation};
 use indexmap::IndexSet;
-use regex::Regex;
+pub use macro_syntax::{DEFAULT_MACRO_SYNTAX, MacroSyntax};
+use macro_syntax::{DEFAULT_SYNTAX, MacroMatch};
 use std::collections::HashMap;
 use std::fmt;
 
-const MACROS_REGEX: &str = r"@\[([a-zA-Z0-9_]+)\]";
+// Values of the parameters of the block being expanded, by parameter name
+type Bindings = HashMap<String, String>;
 
 #[derive(PartialEq)]
 pub enum TangleError {
     BlockNotFound(String),
     InternalError(String),
     CycleDetected(Vec<String>),
+    MissingArguments {
+        block: String,
+        params: Vec<String>,
+    },
+    ArityMismatch {
+        block: String,
+        expected: usize,
+        found: usize,
+    },
+    InvalidMacroSyntax(String),
+    DuplicateTag(DuplicateTag),
 }
 
 impl fmt::Display for TangleError {
@@ -22,6 +41,23 @@ impl fmt::Display for TangleError {
             TangleError::CycleDetected(cycle) => {
                 write!(f, "Cycle detected: {}", cycle.join(" -> "))
             }
+            TangleError::MissingArguments { block, params } => write!(
+                f,
+                "Block '{}' takes parameters ({}) but was used without arguments",
+                block,
+                params.join(", ")
+            ),
+            TangleError::ArityMismatch {
+                block,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Block '{}' takes {} arguments but {} were given",
+                block, expected, found
+            ),
+            TangleError::InvalidMacroSyntax(msg) => write!(f, "Invalid macro syntax: {}", msg),
+            TangleError::DuplicateTag(duplicate) => write!(f, "{}", duplicate),
         }
     }
 }
@@ -34,12 +70,66 @@ impl fmt::Debug for TangleError {
             TangleError::CycleDetected(cycle) => {
                 write!(f, "Cycle detected: {}", cycle.join(" -> "))
             }
+            TangleError::MissingArguments { block, params } => write!(
+                f,
+                "Block '{}' takes parameters ({}) but was used without arguments",
+                block,
+                params.join(", ")
+            ),
+            TangleError::ArityMismatch {
+                block,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Block '{}' takes {} arguments but {} were given",
+                block, expected, found
+            ),
+            TangleError::InvalidMacroSyntax(msg) => write!(f, "Invalid macro syntax: {}", msg),
+            TangleError::DuplicateTag(duplicate) => write!(f, "{}", duplicate),
         }
     }
 }
 
+/// A block reusing the tag of an earlier block without being marked as appending to it.
+/// The earlier block keeps the tag, and tangling it fails with `TangleError::DuplicateTag`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateTag {
+    pub tag: String,
+    /// First and last lines of the block that kept the tag.
+    pub first: (usize, usize),
+    /// First and last lines of the block reusing it.
+    pub second: (usize, usize),
+}
+
+impl fmt::Display for DuplicateTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Duplicate block tag '{}' at lines {}-{} and {}-{} (use '{}{}' to append to it)",
+            self.tag,
+            self.first.0,
+            self.first.1,
+            self.second.0,
+            self.second.1,
+            self.tag,
+            APPEND_SUFFIX
+        )
+    }
+}
+
 pub struct CodeBlocks {
     pub blocks: HashMap<String, CodeBlock>,
+    /// Additional pieces of each chunk, declared with `tag+=` or `append`, in document order.
+    /// The first piece of every chunk is the one stored in `blocks`.
+    pub appended: HashMap<String, Vec<CodeBlock>>,
+    /// Blocks reusing the tag of an earlier block without appending to it, in document order.
+    duplicates: Vec<DuplicateTag>,
+    /// Macro syntax of the blocks of each language, see `LanguageConfig::macro_syntax`.
+    /// Languages without one use `DEFAULT_MACRO_SYNTAX`.
+    language_macro_syntaxes: HashMap<String, MacroSyntax>,
+    /// Macro syntax set by the document for all of its blocks, if any.
+    document_macro_syntax: Option<MacroSyntax>,
 }
 
 impl CodeBlocks {
@@ -47,67 +137,289 @@ impl CodeBlocks {
     /// User code should either use from_codeblocks (if available) or
     /// obtain one from a TanglitDoc instance via `tangle()` method
     pub fn from_codeblocks(blocks: std::collections::HashMap<String, CodeBlock>) -> Self {
-        Self { blocks }
+        Self {
+            blocks,
+            appended: HashMap::new(),
+            duplicates: Vec::new(),
+            language_macro_syntaxes: HashMap::new(),
+            document_macro_syntax: None,
+        }
+    }
+
+    /// Builds the collection from every code block of a document, in document order.
+    /// Blocks appending to the tag of an earlier block become additional pieces of its chunk,
+    /// and their imports are merged into the first piece. Other blocks reusing a tag are left
+    /// out and reported by `duplicate_tags`.
+    pub fn from_pieces(pieces: Vec<CodeBlock>) -> Self {
+        let mut blocks: HashMap<String, CodeBlock> = HashMap::new();
+        let mut appended: HashMap<String, Vec<CodeBlock>> = HashMap::new();
+        let mut duplicates = Vec::new();
+        for piece in pieces {
+            let Some(first_piece) = blocks.get_mut(&piece.tag) else {
+                blocks.insert(piece.tag.clone(), piece);
+                continue;
+            };
+            if !piece.append {
+                duplicates.push(DuplicateTag {
+                    tag: piece.tag.clone(),
+                    first: (first_piece.start_line, first_piece.end_line),
+                    second: (piece.start_line, piece.end_line),
+                });
+                continue;
+            }
+            for import in &piece.imports {
+                if !first_piece.imports.contains(import) {
+                    first_piece.imports.push(import.clone());
+                }
+            }
+            appended.entry(piece.tag.clone()).or_default().push(piece);
+        }
+        Self {
+            blocks,
+            appended,
+            duplicates,
+            language_macro_syntaxes: HashMap::new(),
+            document_macro_syntax: None,
+        }
+    }
+
+    /// Returns the blocks reusing the tag of an earlier block without appending to it,
+    /// in document order. The document stays usable, only tangling these tags fails.
+    pub fn duplicate_tags(&self) -> &[DuplicateTag] {
+        &self.duplicates
+    }
+
+    /// Sets the macro syntax of the blocks written in `language`.
+    pub fn set_language_macro_syntax(&mut self, language: &str, syntax: MacroSyntax) {
+        self.language_macro_syntaxes
+            .insert(language.to_string(), syntax);
+    }
+
+    /// Sets the macro syntax of every block, overriding the ones of their languages.
+    pub fn set_document_macro_syntax(&mut self, syntax: MacroSyntax) {
+        self.document_macro_syntax = Some(syntax);
+    }
+
+    /// Returns the macro syntax used in the code of `block`.
+    pub fn macro_syntax_for(&self, block: &CodeBlock) -> &MacroSyntax {
+        self.document_macro_syntax
+            .as_ref()
+            .or_else(|| {
+                block
+                    .language
+                    .as_ref()
+                    .and_then(|l| self.language_macro_syntaxes.get(l))
+            })
+            .unwrap_or(&DEFAULT_SYNTAX)
     }
 
     /// Tangles a code block by resolving its macros and producing a
     /// string with all referenced blocks inlined.
+    /// Chunks made of several pieces are tangled as the concatenation of all of them.
     pub fn tangle_codeblock(&self, target_codeblock: &CodeBlock) -> Result<String, TangleError> {
-        let mut visited = IndexSet::new();
-        let regex = &Regex::new(MACROS_REGEX)
-            .map_err(|e| TangleError::InternalError(format!("Failed to compile regex: {}", e)))?;
+        Ok(self.tangle_mapped(target_codeblock)?.code)
+    }
 
-        self.expand_block(target_codeblock.tag.clone(), &mut visited, regex)
+    /// Tangles a code block like `tangle_codeblock`, along with a source map from every line
+    /// of the output to the line of `source_file` it was written in.
+    /// If a `line_directive` template is given (see `LanguageConfig::line_directive`), a directive
+    /// is inserted wherever the output jumps to a different place in the document.
+    /// If a `chunk_markers` comment prefix is given (see `LanguageConfig::line_comment`), every
+    /// chunk expanded on its own lines is surrounded by comments naming it, so the output
+    /// can be untangled back into the document.
+    pub fn tangle_codeblock_with_source_map(
+        &self,
+        target_codeblock: &CodeBlock,
+        line_directive: Option<&str>,
+        chunk_markers: Option<&str>,
+        source_file: &str,
+    ) -> Result<TangledCode, TangleError> {
+        let mut mapped_code = self.tangle_with_markers(target_codeblock, chunk_markers)?;
+        if let Some(directive) = line_directive {
+            mapped_code = mapped_code.with_line_directives(directive, source_file);
+        }
+        Ok(mapped_code.into_tangled_code(source_file))
+    }
+
+    pub(crate) fn tangle_mapped(
+        &self,
+        target_codeblock: &CodeBlock,
+    ) -> Result<MappedCode, TangleError> {
+        self.tangle_with_markers(target_codeblock, None)
+    }
+
+    fn tangle_with_markers(
+        &self,
+        target_codeblock: &CodeBlock,
+        chunk_markers: Option<&str>,
+    ) -> Result<MappedCode, TangleError> {
+        let mut visited = IndexSet::new();
+        self.expand_block(
+            target_codeblock.tag.clone(),
+            None,
+            &mut visited,
+            chunk_markers,
+        )
     }
 
     /// Recursively expands a code block by resolving its macros.
     /// It keeps track of visited blocks to detect cycles and avoid infinite recursion.
     /// It also adjusts the indentation of inserted blocks to match the context.
+    /// The `arguments` of the reference, if it has any, are bound to the parameters of the chunk.
+    /// With `chunk_markers`, each piece of the chunk is surrounded by begin and end markers.
     fn expand_block(
         &self,
         target_codeblock_name: String,
+        arguments: Option<Vec<String>>,
         visited: &mut IndexSet<String>,
-        regex: &Regex,
-    ) -> Result<String, TangleError> {
-        let target_block = self.get_code_block(&target_codeblock_name)?;
+        chunk_markers: Option<&str>,
+    ) -> Result<MappedCode, TangleError> {
+        let pieces = self.get_chunk_pieces(&target_codeblock_name)?;
 
         Self::assert_no_cycle(visited, &target_codeblock_name)?;
 
+        let bindings = Self::bind_arguments(pieces[0], arguments)?;
+
         visited.insert(target_codeblock_name.clone());
 
-        let mut expanded_block_code = String::new();
-        let mut final_index = 0;
+        let expanded_pieces = pieces
+            .iter()
+            .enumerate()
+            .map(|(index, piece)| {
+                let expanded = self.expand_macros(piece, &bindings, visited, chunk_markers)?;
+                Ok(match chunk_markers {
+                    Some(comment) => expanded.surround_with(
+                        &format_marker(comment, MARKER_BEGIN, &piece.tag, index),
+                        &format_marker(comment, MARKER_END, &piece.tag, index),
+                    ),
+                    None => expanded,
+                })
+            })
+            .collect::<Result<Vec<MappedCode>, TangleError>>()?;
 
-        for macro_references in regex.captures_iter(&target_block.code) {
-            let macro_reference = macro_references.get(0).unwrap(); // @[A]
-            let block_called = &macro_references[1]; // "A"
+        visited.pop();
 
-            expanded_block_code.push_str(&target_block.code[final_index..macro_reference.start()]);
+        Ok(MappedCode::join(expanded_pieces))
+    }
 
-            let mut macro_block_code =
-                self.expand_block(block_called.to_string(), visited, regex)?;
+    /// Replaces every macro reference in the code of `piece` with the expansion of the
+    /// referenced chunk, indented to the column where the reference appears.
+    /// Opening delimiters escaped as the syntax allows, see `MacroSyntax::with_escape`,
+    /// are written without their escape.
+    /// References to a parameter of the chunk are replaced with its value in `bindings`.
+    /// Chunk markers are only kept for references written on their own line without arguments,
+    /// since a marker inside an inline expansion would break the line it's in, and the code
+    /// of a chunk expanded with arguments can't be untangled.
+    fn expand_macros(
+        &self,
+        piece: &CodeBlock,
+        bindings: &Bindings,
+        visited: &mut IndexSet<String>,
+        chunk_markers: Option<&str>,
+    ) -> Result<MappedCode, TangleError> {
+        let syntax = self.macro_syntax_for(piece);
+        let code = &piece.code;
+        let first_line = piece.content_start_line();
+        let line_at = |offset: usize| first_line + code[..offset].matches('\n').count();
+
+        let mut expanded_block_code = MappedCode::new(Some(first_line));
+        let mut final_index = 0;
 
-            let placeholder_offset = macro_reference.start();
-            let indent_size = get_indentation_at_offset(&target_block.code, placeholder_offset);
+        for found in syntax.find_references(code) {
+            let macro_reference = match found {
+                MacroMatch::Reference(reference) => reference,
+                MacroMatch::Escape { start, end } => {
+                    expanded_block_code
+                        .push_source(&code[final_index..start], line_at(final_index));
+                    // The delimiter after the escape is written along with the code after it
+                    final_index = end;
+                    continue;
+                }
+            };
+            let block_called = macro_reference.name;
+            let arguments = macro_reference.arguments.map(|args| {
+                split_arguments(args)
+                    .into_iter()
+                    .map(|arg| substitute_bindings(&arg, bindings, syntax))
+                    .collect::<Vec<String>>()
+            });
+
+            expanded_block_code.push_source(
+                &code[final_index..macro_reference.start],
+                line_at(final_index),
+            );
+
+            if let (None, Some(value)) = (&arguments, bindings.get(block_called)) {
+                expanded_block_code.push_source(value, line_at(macro_reference.start));
+                final_index = macro_reference.end;
+                continue;
+            }
+
+            let macro_markers = chunk_markers.filter(|_| {
+                arguments.is_none()
+                    && is_on_its_own_line(code, macro_reference.start, macro_reference.end)
+            });
+            let mut macro_block =
+                self.expand_block(block_called.to_string(), arguments, visited, macro_markers)?;
+
+            let placeholder_offset = macro_reference.start;
+            let indent_size = get_indentation_at_offset(code, placeholder_offset);
+            let mut macro_block_code = macro_block.code.clone();
             set_indentation(&mut macro_block_code, Some(indent_size), Some(' '));
+            macro_block.replace_code(macro_block_code);
 
-            expanded_block_code.push_str(&macro_block_code);
+            expanded_block_code.push_mapped(macro_block);
 
-            final_index = macro_reference.end();
+            final_index = macro_reference.end;
         }
-        expanded_block_code.push_str(&target_block.code[final_index..]);
-
-        visited.pop();
+        expanded_block_code.push_source(&code[final_index..], line_at(final_index));
 
         Ok(expanded_block_code)
     }
 
+    // Binds the arguments of a reference to the parameters declared by the chunk
+    fn bind_arguments(
+        block: &CodeBlock,
+        arguments: Option<Vec<String>>,
+    ) -> Result<Bindings, TangleError> {
+        let arguments = match arguments {
+            Some(arguments) => arguments,
+            None if block.params.is_empty() => return Ok(Bindings::new()),
+            None => {
+                return Err(TangleError::MissingArguments {
+                    block: block.tag.clone(),
+                    params: block.params.clone(),
+                });
+            }
+        };
+        if arguments.len() != block.params.len() {
+            return Err(TangleError::ArityMismatch {
+                block: block.tag.clone(),
+                expected: block.params.len(),
+                found: arguments.len(),
+            });
+        }
+        Ok(block.params.iter().cloned().zip(arguments).collect())
+    }
+
     /// Find and return the specified code block by name.
     /// Returns `None` if the block can't be found within its collection.
     pub fn get_block(&self, name: &str) -> Option<&CodeBlock> {
         self.blocks.get(name)
     }
 
+    /// Returns the first `input` block, in document order, declared as the standard input
+    /// of the block `tag` with `for=tag`.
+    pub fn get_input_block(&self, tag: &str) -> Option<&CodeBlock> {
+        self.blocks
+            .values()
+            .filter(|block| {
+                block.language.as_deref() == Some(INPUT_LANGUAGE)
+                    && block.input_for.as_deref() == Some(tag)
+            })
+            .min_by_key(|block| block.start_line)
+    }
+
     pub fn get_all_blocks_to_tangle(&self) -> Vec<&CodeBlock> {
         self.blocks
             .values()
@@ -115,10 +427,76 @@ impl CodeBlocks {
             .collect()
     }
 
-    fn get_code_block(&self, code_name: &str) -> Result<&CodeBlock, TangleError> {
-        self.blocks
-            .get(code_name)
-            .ok_or_else(|| TangleError::BlockNotFound(code_name.to_string()))
+    /// Groups the blocks to tangle by the file they're exported to, see `export_path`, so
+    /// `export=main.c` and `export=./main.c` end up in the same group, named `main.c`.
+    /// Blocks within a group are sorted by their `order` and then by document order,
+    /// and groups are sorted by the position of their first block in the document.
+    pub fn get_blocks_by_export(&self) -> Vec<(String, Vec<&CodeBlock>)> {
+        group_by_file(self.get_all_blocks_to_tangle(), |block| {
+            let export = block.export.as_ref()?;
+            // Invalid paths keep their own group, and are rejected when the group is written
+            Some(match export_path(block) {
+                Ok(path) => path.to_string_lossy().into_owned(),
+                Err(_) => export.clone(),
+            })
+        })
+    }
+
+    /// Groups the blocks declared with `file=` by their file, like `get_blocks_by_export`.
+    /// These are the other files of the executions of the blocks using them.
+    pub fn get_blocks_by_file(&self) -> Vec<(String, Vec<&CodeBlock>)> {
+        group_by_file(self.blocks.values().collect(), |block| block.file.clone())
+    }
+
+    /// Returns every piece of the chunk named `name`, in document order.
+    /// Returns an empty vector if there is no chunk with that name.
+    pub fn get_chunk(&self, name: &str) -> Vec<&CodeBlock> {
+        let Some(first_piece) = self.blocks.get(name) else {
+            return Vec::new();
+        };
+        let mut pieces = vec![first_piece];
+        if let Some(appended) = self.appended.get(name) {
+            pieces.extend(appended);
+        }
+        pieces
+    }
+
+    /// Returns the names of the chunks referenced with macros in the chunk named `name`,
+    /// in order of appearance and without repeating them. References to its own parameters
+    /// aren't chunks, so they are left out.
+    pub fn get_references(&self, name: &str) -> Vec<String> {
+        let pieces = self.get_chunk(name);
+        let Some(first_piece) = pieces.first() else {
+            return Vec::new();
+        };
+        let mut references: Vec<String> = Vec::new();
+        for piece in &pieces {
+            for found in self.macro_syntax_for(piece).find_references(&piece.code) {
+                let MacroMatch::Reference(reference) = found else {
+                    continue;
+                };
+                let is_param = reference.arguments.is_none()
+                    && first_piece
+                        .params
+                        .iter()
+                        .any(|param| param == reference.name);
+                if !is_param && !references.iter().any(|name| name == reference.name) {
+                    references.push(reference.name.to_string());
+                }
+            }
+        }
+        references
+    }
+
+    fn get_chunk_pieces(&self, code_name: &str) -> Result<Vec<&CodeBlock>, TangleError> {
+        if let Some(duplicate) = self.duplicates.iter().find(|d| d.tag == code_name) {
+            return Err(TangleError::DuplicateTag(duplicate.clone()));
+        }
+        let pieces = self.get_chunk(code_name);
+        if pieces.is_empty() {
+            return Err(TangleError::BlockNotFound(code_name.to_string()));
+        }
+        Ok(pieces)
     }
 
     fn assert_no_cycle(visited: &IndexSet<String>, node: &str) -> Result<(), TangleError> {
@@ -132,6 +510,87 @@ impl CodeBlocks {
     }
 }
 
+// Groups `blocks` by the file `file_of` returns for them. Blocks within a group are sorted by
+// their `order` and then by document order, and groups by the position of their first block.
+fn group_by_file(
+    mut blocks: Vec<&CodeBlock>,
+    file_of: impl Fn(&CodeBlock) -> Option<String>,
+) -> Vec<(String, Vec<&CodeBlock>)> {
+    blocks.sort_by_key(|block| block.start_line);
+
+    let mut groups: Vec<(String, Vec<&CodeBlock>)> = Vec::new();
+    for block in blocks {
+        let Some(file) = file_of(block) else {
+            continue;
+        };
+        match groups.iter_mut().find(|(name, _)| *name == file) {
+            Some((_, group)) => group.push(block),
+            None => groups.push((file, vec![block])),
+        }
+    }
+    for (_, group) in groups.iter_mut() {
+        group.sort_by_key(|block| (block.order.unwrap_or(0), block.start_line));
+    }
+    groups
+}
+
+// Splits the arguments of a reference on the commas that aren't nested in brackets,
+// so `f(a, b), c` is two arguments
+fn split_arguments(arguments: &str) -> Vec<String> {
+    if arguments.trim().is_empty() {
+        return vec![];
+    }
+    let mut result = vec![];
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in arguments.chars() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    result.push(current.trim().to_string());
+    result
+}
+
+// Replaces the references to parameters in an argument, so arguments can be forwarded
+// from one parameterized block to another
+fn substitute_bindings(argument: &str, bindings: &Bindings, syntax: &MacroSyntax) -> String {
+    let mut result = String::new();
+    let mut final_index = 0;
+    for found in syntax.find_references(argument) {
+        match found {
+            MacroMatch::Reference(reference) => {
+                if let (None, Some(value)) = (reference.arguments, bindings.get(reference.name)) {
+                    result.push_str(&argument[final_index..reference.start]);
+                    result.push_str(value);
+                    final_index = reference.end;
+                }
+            }
+            MacroMatch::Escape { start, end } => {
+                result.push_str(&argument[final_index..start]);
+                final_index = end;
+            }
+        }
+    }
+    result.push_str(&argument[final_index..]);
+    result
+}
+
+// Whether the text around `start..end` in `code` is only whitespace up to the line boundaries
+fn is_on_its_own_line(code: &str, start: usize, end: usize) -> bool {
+    let line_start = code[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = code[end..].find('\n').map_or(code.len(), |i| end + i);
+    code[line_start..start].trim().is_empty() && code[end..line_end].trim().is_empty()
+}
+
 fn format_cycle_path(node: &str, mut cycle_path: Vec<String>) -> Vec<String> {
     // to complete the cycle
     cycle_path.push(node.to_string());
@@ -815,4 +1274,337 @@ mod tests {
         assert_eq!(result.len(), 1);
         assert_eq!(result[0].tag, "main");
     }
+
+    #[test]
+    fn test_tangle_chunk_pieces_in_document_order() {
+        let first = CodeBlock::new(
+            Option::from("python".to_string()),
+            "a = 1".to_string(),
+            "setup".to_string(),
+            vec!["os".to_string()],
+            None,
+            1,
+            3,
+        );
+        let mut second = CodeBlock::new(
+            Option::from("python".to_string()),
+            "b = 2\nc = 3".to_string(),
+            "setup".to_string(),
+            vec!["sys".to_string()],
+            None,
+            9,
+            12,
+        );
+        second.append = true;
+        let main = CodeBlock::new(
+            Option::from("python".to_string()),
+            "def main():\n    @[setup]\n    print(a + b + c)".to_string(),
+            "main".to_string(),
+            vec![],
+            None,
+            5,
+            7,
+        );
+
+        let codeblocks = CodeBlocks::from_pieces(vec![first, main, second]);
+
+        let setup = codeblocks.get_block("setup").unwrap();
+        assert_eq!(setup.imports, vec!["os".to_string(), "sys".to_string()]);
+        assert_eq!(codeblocks.get_chunk("setup").len(), 2);
+        assert_eq!(
+            codeblocks.tangle_codeblock(setup).unwrap(),
+            "a = 1\nb = 2\nc = 3"
+        );
+
+        let block = codeblocks.get_block("main").unwrap();
+        let tangle = codeblocks.tangle_codeblock(block).unwrap();
+        assert_eq!(
+            tangle,
+            "def main():\n    a = 1\n    b = 2\n    c = 3\n    print(a + b + c)"
+        );
+    }
+
+    #[test]
+    fn test_get_references() {
+        let mut main = CodeBlock::new(
+            Option::from("python".to_string()),
+            "@[setup]\n@[swap(a, b)]\nprint(@[x])\n@[setup]".to_string(),
+            "main".to_string(),
+            vec!["helper".to_string()],
+            None,
+            1,
+            6,
+        );
+        main.params = vec!["x".to_string()];
+        let codeblocks = CodeBlocks::from_pieces(vec![main]);
+
+        assert_eq!(codeblocks.get_references("main"), vec!["setup", "swap"]);
+        assert!(codeblocks.get_references("missing").is_empty());
+    }
+
+    #[test]
+    fn test_get_chunk_missing() {
+        let codeblocks = CodeBlocks::from_codeblocks(HashMap::new());
+        assert!(codeblocks.get_chunk("missing").is_empty());
+    }
+
+    #[test]
+    fn get_blocks_by_export_groups_blocks_in_order() {
+        let block = |tag: &str, export: Option<&str>, order: Option<i64>, line: usize| {
+            let mut block = CodeBlock::new(
+                Option::from("c".to_string()),
+                format!("// {}", tag),
+                tag.to_string(),
+                vec![],
+                export.map(|e| e.to_string()),
+                line,
+                line + 2,
+            );
+            block.order = order;
+            block
+        };
+        let codeblocks = CodeBlocks::from_pieces(vec![
+            block("main", Some("main.c"), None, 1),
+            block("helper", Some("util.c"), None, 5),
+            block("includes", Some("main.c"), Some(-1), 10),
+            block("scratch", None, None, 15),
+            block("footer", Some("main.c"), None, 20),
+            // Names of the same file are grouped together
+            block("helper_tail", Some("./util.c"), None, 25),
+        ]);
+
+        let groups: Vec<(String, Vec<&str>)> = codeblocks
+            .get_blocks_by_export()
+            .into_iter()
+            .map(|(export, blocks)| (export, blocks.iter().map(|b| b.tag.as_str()).collect()))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                ("main.c".to_string(), vec!["includes", "main", "footer"]),
+                ("util.c".to_string(), vec!["helper", "helper_tail"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tangle_source_map_through_nested_macros() {
+        // Blocks as they would be parsed from a document: the fence is at `start_line`
+        // and the code starts on the next line.
+        let codeblocks = CodeBlocks::from_pieces(vec![
+            CodeBlock::new(
+                Option::from("c".to_string()),
+                "int main(void) {\n    @[body]\n}".to_string(),
+                "main".to_string(),
+                vec![],
+                None,
+                1,
+                5,
+            ),
+            CodeBlock::new(
+                Option::from("c".to_string()),
+                "int x = 1;\n@[print]".to_string(),
+                "body".to_string(),
+                vec![],
+                None,
+                7,
+                10,
+            ),
+            CodeBlock::new(
+                Option::from("c".to_string()),
+                "printf(\"%d\", x);".to_string(),
+                "print".to_string(),
+                vec![],
+                None,
+                12,
+                14,
+            ),
+        ]);
+
+        let block = codeblocks.get_block("main").unwrap();
+        let tangled = codeblocks
+            .tangle_codeblock_with_source_map(block, None, None, "doc.md")
+            .unwrap();
+        assert_eq!(
+            tangled.code,
+            "int main(void) {\n    int x = 1;\n    printf(\"%d\", x);\n}"
+        );
+        assert_eq!(tangled.source_map.file, "doc.md");
+        assert_eq!(
+            tangled.source_map.lines,
+            vec![Some(2), Some(8), Some(13), Some(4)]
+        );
+
+        let with_directives = codeblocks
+            .tangle_codeblock_with_source_map(
+                block,
+                Some("#line {line} \"{file}\""),
+                None,
+                "doc.md",
+            )
+            .unwrap();
+        assert_eq!(
+            with_directives.code,
+            "#line 2 \"doc.md\"\nint main(void) {\n    #line 8 \"doc.md\"\n    int x = 1;\n    #line 13 \"doc.md\"\n    printf(\"%d\", x);\n#line 4 \"doc.md\"\n}"
+        );
+        assert_eq!(
+            with_directives.source_map.source_line(4),
+            Some(8),
+            "line 4 of the output is `int x = 1;`"
+        );
+    }
+
+    #[test]
+    fn test_tangle_with_chunk_markers() {
+        let codeblocks = CodeBlocks::from_pieces(vec![
+            CodeBlock::new(
+                Option::from("python".to_string()),
+                "def main():\n    @[body]\n    print(@[answer])".to_string(),
+                "main".to_string(),
+                vec![],
+                None,
+                1,
+                5,
+            ),
+            CodeBlock::new(
+                Option::from("python".to_string()),
+                "x = 1".to_string(),
+                "body".to_string(),
+                vec![],
+                None,
+                7,
+                9,
+            ),
+            CodeBlock::new(
+                Option::from("python".to_string()),
+                "x + 1".to_string(),
+                "answer".to_string(),
+                vec![],
+                None,
+                11,
+                13,
+            ),
+        ]);
+
+        let block = codeblocks.get_block("main").unwrap();
+        let tangled = codeblocks
+            .tangle_codeblock_with_source_map(block, None, Some("#"), "doc.md")
+            .unwrap();
+        // The inline reference to `answer` can't be surrounded by markers
+        assert_eq!(
+            tangled.code,
+            "# tanglit:begin main:0\ndef main():\n    # tanglit:begin body:0\n    x = 1\n    # tanglit:end body:0\n    print(x + 1)\n# tanglit:end main:0"
+        );
+        assert_eq!(
+            tangled.source_map.lines,
+            vec![None, Some(2), None, Some(8), None, Some(4), None]
+        );
+    }
+
+    fn block_with_params(tag: &str, code: &str, params: &[&str]) -> CodeBlock {
+        let mut block = CodeBlock::new_with_code(code.to_string());
+        block.tag = tag.to_string();
+        block.params = params.iter().map(|p| p.to_string()).collect();
+        block
+    }
+
+    #[test]
+    fn test_tangle_parameterized_macros() {
+        let codeblocks = CodeBlocks::from_codeblocks(HashMap::from([
+            (
+                "main".to_string(),
+                block_with_params(
+                    "main",
+                    "@[swap(a, b)]\n@[swap(v[max(i, j)], v[0])]\n@[twice(c)]",
+                    &[],
+                ),
+            ),
+            (
+                "swap".to_string(),
+                block_with_params("swap", "tmp = @[x]\n@[x] = @[y]\n@[y] = tmp", &["x", "y"]),
+            ),
+            (
+                "twice".to_string(),
+                block_with_params("twice", "@[swap(@[z], @[z])]", &["z"]),
+            ),
+        ]));
+
+        let main = codeblocks.get_block("main").unwrap();
+        assert_eq!(
+            codeblocks.tangle_codeblock(main).unwrap(),
+            "tmp = a\na = b\nb = tmp\n\
+             tmp = v[max(i, j)]\nv[max(i, j)] = v[0]\nv[0] = tmp\n\
+             tmp = c\nc = c\nc = tmp"
+        );
+    }
+
+    #[test]
+    fn test_tangle_parameterized_macros_arity_errors() {
+        let swap = block_with_params("swap", "@[x], @[y] = @[y], @[x]", &["x", "y"]);
+        let codeblocks = CodeBlocks::from_codeblocks(HashMap::from([
+            ("swap".to_string(), swap.clone()),
+            (
+                "missing".to_string(),
+                block_with_params("missing", "@[swap]", &[]),
+            ),
+            (
+                "too_many".to_string(),
+                block_with_params("too_many", "@[swap(a, b, c)]", &[]),
+            ),
+        ]));
+
+        assert_eq!(
+            codeblocks.tangle_codeblock(codeblocks.get_block("missing").unwrap()),
+            Err(TangleError::MissingArguments {
+                block: "swap".to_string(),
+                params: vec!["x".to_string(), "y".to_string()],
+            })
+        );
+        assert_eq!(
+            codeblocks.tangle_codeblock(codeblocks.get_block("too_many").unwrap()),
+            Err(TangleError::ArityMismatch {
+                block: "swap".to_string(),
+                expected: 2,
+                found: 3,
+            })
+        );
+        assert!(matches!(
+            codeblocks.tangle_codeblock(&swap),
+            Err(TangleError::MissingArguments { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tangle_without_macro_escape() {
+        // Without an escape, backslashes before delimiters are kept, as in existing documents
+        let main = block_with_params("main", r#"print("\@[", "\@[body]")"#, &[]);
+        let body = block_with_params("body", "x", &[]);
+        let codeblocks = CodeBlocks::from_codeblocks(HashMap::from([
+            ("main".to_string(), main.clone()),
+            ("body".to_string(), body),
+        ]));
+
+        assert_eq!(
+            codeblocks.tangle_codeblock(&main).unwrap(),
+            r#"print("\@[", "\x")"#
+        );
+    }
+
+    #[test]
+    fn test_tangle_with_language_macro_syntax() {
+        let mut main = block_with_params("main", "#[derive(Debug)]\n<<body>>\n@[body]", &[]);
+        main.language = Some("rust".to_string());
+        let body = block_with_params("body", "struct S;", &[]);
+        let mut codeblocks = CodeBlocks::from_codeblocks(HashMap::from([
+            ("main".to_string(), main.clone()),
+            ("body".to_string(), body),
+        ]));
+        codeblocks
+            .set_language_macro_syntax("rust", MacroSyntax::from_template("<<{name}>>").unwrap());
+
+        assert_eq!(
+            codeblocks.tangle_codeblock(&main).unwrap(),
+            "#[derive(Debug)]\nstruct S;\n@[body]"
+        );
+    }
 }
@@ -0,0 +1,233 @@
+use super::TangleError;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+// Placeholder for the block name in a macro syntax template
+const NAME_PLACEHOLDER: &str = "{name}";
+pub const DEFAULT_MACRO_SYNTAX: &str = "@[{name}]";
+
+pub(crate) static DEFAULT_SYNTAX: Lazy<MacroSyntax> = Lazy::new(|| {
+    MacroSyntax::from_template(DEFAULT_MACRO_SYNTAX).expect("The default macro syntax is valid")
+});
+
+/// The delimiters of the references to other blocks, such as `@[` and `]` in `@[name]`.
+#[derive(Debug, Clone)]
+pub struct MacroSyntax {
+    open: String,
+    close: String,
+    // Written before the opening delimiter to keep it literally, as in `\@[`
+    escape: Option<String>,
+    // Matches an escaped opening delimiter, or the start of a reference up to
+    // its closing delimiter or the parenthesis opening its arguments
+    regex: Regex,
+}
+
+/// A reference to a chunk in some code, like `@[name]` or `@[name(a, b)]`.
+pub(crate) struct MacroReference<'a> {
+    pub start: usize,
+    pub end: usize,
+    pub name: &'a str,
+    pub arguments: Option<&'a str>,
+}
+
+/// Something found by `MacroSyntax::find_references`.
+pub(crate) enum MacroMatch<'a> {
+    Reference(MacroReference<'a>),
+    /// The escape at `start..end` of an opening delimiter, which must be dropped
+    /// and the delimiter after it written as it is.
+    Escape {
+        start: usize,
+        end: usize,
+    },
+}
+
+impl MacroSyntax {
+    /// Parses a template like `@[{name}]` or `<<{name}>>`, where `{name}` stands for the block
+    /// name, and the text around it are the opening and closing delimiters.
+    pub fn from_template(template: &str) -> Result<Self, TangleError> {
+        let invalid =
+            |reason: &str| TangleError::InvalidMacroSyntax(format!("'{}' {}", template, reason));
+        let (open, close) = template
+            .split_once(NAME_PLACEHOLDER)
+            .ok_or_else(|| invalid(&format!("doesn't contain {}", NAME_PLACEHOLDER)))?;
+        if open.trim().is_empty() || close.trim().is_empty() {
+            return Err(invalid("needs delimiters before and after the name"));
+        }
+
+        Ok(Self {
+            open: open.to_string(),
+            close: close.to_string(),
+            escape: None,
+            regex: build_regex(open, close, None).map_err(|e| invalid(&e.to_string()))?,
+        })
+    }
+
+    /// Returns the syntax with `escape`, like `\`, written before an opening delimiter to keep
+    /// it literally, as in `\@[not a reference]`. Without one, the code of the blocks is only
+    /// changed where it references other blocks.
+    pub fn with_escape(self, escape: Option<&str>) -> Result<Self, TangleError> {
+        let Some(escape) = escape else {
+            return Ok(self);
+        };
+        if escape.trim().is_empty() {
+            return Err(TangleError::InvalidMacroSyntax(
+                "the escape of the macros can't be empty".to_string(),
+            ));
+        }
+        let regex = build_regex(&self.open, &self.close, Some(escape))
+            .map_err(|e| TangleError::InvalidMacroSyntax(e.to_string()))?;
+        Ok(Self {
+            escape: Some(escape.to_string()),
+            regex,
+            ..self
+        })
+    }
+
+    /// Writes a reference to the block `name`.
+    pub fn format_reference(&self, name: &str) -> String {
+        format!("{}{}{}", self.open, name, self.close)
+    }
+
+    /// Finds the references and escaped delimiters in `code`, in order. The arguments of a
+    /// reference end at the parenthesis matching the opening one, so they can contain calls
+    /// and indexing like `v[f(i)]`.
+    pub(crate) fn find_references<'a>(&self, code: &'a str) -> Vec<MacroMatch<'a>> {
+        let mut matches = vec![];
+        let mut search_from = 0;
+        while let Some(caps) = self.regex.captures_at(code, search_from) {
+            let start_match = caps.get(0).unwrap();
+            search_from = start_match.end();
+            let Some(name) = caps.get(1) else {
+                let escape_length = self.escape.as_ref().map_or(0, String::len);
+                matches.push(MacroMatch::Escape {
+                    start: start_match.start(),
+                    end: start_match.start() + escape_length,
+                });
+                continue;
+            };
+
+            if caps[2] == self.close {
+                matches.push(MacroMatch::Reference(MacroReference {
+                    start: start_match.start(),
+                    end: start_match.end(),
+                    name: name.as_str(),
+                    arguments: None,
+                }));
+                continue;
+            }
+            let Some(close) = find_closing_parenthesis(code, start_match.end()) else {
+                continue;
+            };
+            if code[close + 1..].starts_with(&self.close) {
+                let end = close + 1 + self.close.len();
+                matches.push(MacroMatch::Reference(MacroReference {
+                    start: start_match.start(),
+                    end,
+                    name: name.as_str(),
+                    arguments: Some(&code[start_match.end()..close]),
+                }));
+                search_from = end;
+            }
+        }
+        matches
+    }
+}
+
+impl Default for MacroSyntax {
+    fn default() -> Self {
+        DEFAULT_SYNTAX.clone()
+    }
+}
+
+// Builds the regex of `MacroSyntax::regex`
+fn build_regex(open: &str, close: &str, escape: Option<&str>) -> Result<Regex, regex::Error> {
+    let reference = format!(
+        r"{open}([a-zA-Z0-9_]+)({close}|\()",
+        open = regex::escape(open),
+        close = regex::escape(close),
+    );
+    match escape {
+        Some(escape) => Regex::new(&format!(
+            "{}{}|{}",
+            regex::escape(escape),
+            regex::escape(open),
+            reference
+        )),
+        None => Regex::new(&reference),
+    }
+}
+
+// Returns the offset of the parenthesis closing the one opened right before `from`,
+// which must be on the same line
+fn find_closing_parenthesis(code: &str, from: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in code[from..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(from + i),
+            ')' => depth -= 1,
+            '\n' => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn describe(syntax: &MacroSyntax, code: &str) -> Vec<String> {
+        syntax
+            .find_references(code)
+            .into_iter()
+            .map(|found| match found {
+                MacroMatch::Reference(r) => format!("{}{:?}", r.name, r.arguments),
+                MacroMatch::Escape { start, end } => format!("escape {}", &code[start..end]),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_references_with_custom_syntax() {
+        let syntax = MacroSyntax::from_template("<<{name}>>")
+            .unwrap()
+            .with_escape(Some("\\"))
+            .unwrap();
+
+        assert_eq!(
+            describe(&syntax, "<<a>> @[b] <<swap(v[f(i)], y)>> \\<<c>> <<d(>>"),
+            vec![
+                "aNone".to_string(),
+                "swapSome(\"v[f(i)], y\")".to_string(),
+                "escape \\".to_string(),
+            ]
+        );
+        assert_eq!(syntax.format_reference("a"), "<<a>>");
+    }
+
+    #[test]
+    fn test_find_references_without_escape() {
+        let syntax = MacroSyntax::default();
+        assert_eq!(
+            describe(&syntax, r#"print("\@[a]") @[b]"#),
+            vec!["aNone".to_string(), "bNone".to_string()]
+        );
+        let syntax = syntax.with_escape(Some("!!")).unwrap();
+        assert_eq!(
+            describe(&syntax, r#"print("!!@[a]") @[b]"#),
+            vec!["escape !!".to_string(), "bNone".to_string()]
+        );
+        assert!(MacroSyntax::default().with_escape(Some(" ")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for template in ["@[name]", "{name}]", "@[{name}", ""] {
+            assert!(matches!(
+                MacroSyntax::from_template(template),
+                Err(TangleError::InvalidMacroSyntax(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,456 @@
+use crate::doc::{CodeBlocks, Edit, MacroSyntax};
+use indexmap::IndexMap;
+use regex::Regex;
+use std::fmt;
+
+// Keywords of the comments that surround every chunk in code tangled with chunk markers,
+// e.g. `// tanglit:begin parse_args:0` and `// tanglit:end parse_args:0`
+pub(crate) const MARKER_BEGIN: &str = "tanglit:begin";
+pub(crate) const MARKER_END: &str = "tanglit:end";
+
+/// Formats the marker comment of the `piece`-th piece of the chunk `tag`.
+pub(crate) fn format_marker(line_comment: &str, kind: &str, tag: &str, piece: usize) -> String {
+    format!("{} {} {}:{}", line_comment, kind, tag, piece)
+}
+
+#[derive(PartialEq)]
+pub enum UntangleError {
+    InvalidMarkers {
+        file: String,
+        line: usize,
+        reason: String,
+    },
+    AmbiguousChange {
+        tag: String,
+        reason: String,
+    },
+    ConflictingChanges {
+        tag: String,
+        locations: Vec<String>,
+    },
+}
+
+impl fmt::Display for UntangleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UntangleError::InvalidMarkers { file, line, reason } => {
+                write!(f, "Invalid chunk markers at {}:{}: {}", file, line, reason)
+            }
+            UntangleError::AmbiguousChange { tag, reason } => {
+                write!(f, "Cannot untangle changes to block '{}': {}", tag, reason)
+            }
+            UntangleError::ConflictingChanges { tag, locations } => write!(
+                f,
+                "Conflicting changes to block '{}' at {}",
+                tag,
+                locations.join(", ")
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for UntangleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The code of one piece of a chunk found between markers in a tangled file,
+/// with the chunks expanded in it replaced back by their references.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct UntangledPiece {
+    pub tag: String,
+    pub piece: usize,
+    pub code: String,
+    pub file: String,
+    /// 1-based line of the begin marker in `file`.
+    pub line: usize,
+}
+
+// A piece whose end marker hasn't been found yet
+struct OpenPiece {
+    tag: String,
+    piece: usize,
+    indentation: String,
+    lines: Vec<String>,
+    line: usize,
+}
+
+/// Parses code tangled with chunk markers into the pieces it was assembled from.
+/// Lines matching the `line_directive` template are dropped, since they were generated too,
+/// and chunks are replaced by references written in `macro_syntax`.
+pub(crate) fn parse_marked_code(
+    code: &str,
+    file: &str,
+    line_comment: &str,
+    line_directive: Option<&str>,
+    macro_syntax: &MacroSyntax,
+) -> Result<Vec<UntangledPiece>, UntangleError> {
+    let directive_regex = line_directive.map(directive_to_regex);
+    let invalid = |line: usize, reason: String| UntangleError::InvalidMarkers {
+        file: file.to_string(),
+        line,
+        reason,
+    };
+
+    let mut pieces = vec![];
+    let mut open: Vec<OpenPiece> = vec![];
+    for (index, line) in code.lines().enumerate() {
+        let line_number = index + 1;
+        let content = line.trim_start();
+        let indentation = &line[..line.len() - content.len()];
+
+        if let Some(id) = parse_marker(content, line_comment, MARKER_BEGIN) {
+            let (tag, piece) = parse_chunk_id(id)
+                .ok_or_else(|| invalid(line_number, format!("invalid chunk '{}'", id)))?;
+            if let Some(parent) = open.last_mut() {
+                let relative_indentation = indentation
+                    .strip_prefix(parent.indentation.as_str())
+                    .ok_or_else(|| invalid(line_number, format!("chunk '{}' is outdented", tag)))?;
+                // The pieces of a chunk are tangled one after the other where it's referenced
+                if piece == 0 {
+                    parent.lines.push(format!(
+                        "{}{}",
+                        relative_indentation,
+                        macro_syntax.format_reference(tag)
+                    ));
+                }
+            }
+            open.push(OpenPiece {
+                tag: tag.to_string(),
+                piece,
+                indentation: indentation.to_string(),
+                lines: vec![],
+                line: line_number,
+            });
+        } else if let Some(id) = parse_marker(content, line_comment, MARKER_END) {
+            let closed = open
+                .pop()
+                .ok_or_else(|| invalid(line_number, format!("'{}' was never opened", id)))?;
+            if parse_chunk_id(id) != Some((closed.tag.as_str(), closed.piece)) {
+                return Err(invalid(
+                    line_number,
+                    format!(
+                        "expected the end of '{}:{}', found '{}'",
+                        closed.tag, closed.piece, id
+                    ),
+                ));
+            }
+            pieces.push(UntangledPiece {
+                tag: closed.tag,
+                piece: closed.piece,
+                code: closed.lines.join("\n"),
+                file: file.to_string(),
+                line: closed.line,
+            });
+        } else if directive_regex
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(content))
+        {
+            continue;
+        } else if let Some(current) = open.last_mut() {
+            match line.strip_prefix(current.indentation.as_str()) {
+                Some(stripped) => current.lines.push(stripped.to_string()),
+                None if content.is_empty() => current.lines.push(String::new()),
+                None => {
+                    return Err(invalid(
+                        line_number,
+                        format!("line is outdented from chunk '{}'", current.tag),
+                    ));
+                }
+            }
+        } else if !content.is_empty() {
+            return Err(invalid(
+                line_number,
+                "code outside of any chunk can't be traced back to the document".to_string(),
+            ));
+        }
+    }
+
+    if let Some(unclosed) = open.pop() {
+        return Err(invalid(
+            unclosed.line,
+            format!("'{}:{}' is never closed", unclosed.tag, unclosed.piece),
+        ));
+    }
+    Ok(pieces)
+}
+
+/// Turns the pieces found in tangled files into edits of the code blocks of the document.
+/// `expected` holds the pieces as the document tangles them right now, so only the pieces
+/// that differ from them are considered changed. A piece changed in different ways in
+/// several places, or that can't be written back as a whole code block, is refused.
+pub(crate) fn pieces_to_edits(
+    raw_markdown: &str,
+    blocks: &CodeBlocks,
+    found: Vec<UntangledPiece>,
+    expected: Vec<UntangledPiece>,
+) -> Result<Vec<Edit>, UntangleError> {
+    let mut versions: IndexMap<(String, usize), Vec<UntangledPiece>> = IndexMap::new();
+    for piece in found {
+        versions
+            .entry((piece.tag.clone(), piece.piece))
+            .or_default()
+            .push(piece);
+    }
+
+    let markdown_lines: Vec<&str> = raw_markdown.lines().collect();
+    let mut edits = vec![];
+    for ((tag, piece_index), versions) in versions {
+        let Some(expected_piece) = expected
+            .iter()
+            .find(|p| p.tag == tag && p.piece == piece_index)
+        else {
+            let first = &versions[0];
+            return Err(UntangleError::InvalidMarkers {
+                file: first.file.clone(),
+                line: first.line,
+                reason: format!("there is no chunk '{}:{}' to untangle", tag, piece_index),
+            });
+        };
+
+        let mut changed: Vec<&UntangledPiece> = vec![];
+        for version in versions.iter() {
+            if version.code != expected_piece.code
+                && !changed.iter().any(|c| c.code == version.code)
+            {
+                changed.push(version);
+            }
+        }
+        let new_code = match changed.as_slice() {
+            [] => continue,
+            [version] => &version.code,
+            _ => {
+                return Err(UntangleError::ConflictingChanges {
+                    tag,
+                    locations: changed
+                        .iter()
+                        .map(|c| format!("{}:{}", c.file, c.line))
+                        .collect(),
+                });
+            }
+        };
+
+        let block = blocks.get_chunk(&tag)[piece_index];
+        if expected_piece.code != block.code {
+            return Err(UntangleError::AmbiguousChange {
+                tag,
+                reason: "it expands other blocks inline, edit it in the document instead"
+                    .to_string(),
+            });
+        }
+
+        let start_line = block.content_start_line();
+        let line_count = if block.code.is_empty() {
+            0
+        } else {
+            block.code.split('\n').count()
+        };
+        let written_code = markdown_lines
+            .get(start_line - 1..start_line - 1 + line_count)
+            .map(|lines| lines.join("\n"));
+        if written_code.as_deref() != Some(block.code.as_str()) {
+            return Err(UntangleError::AmbiguousChange {
+                tag,
+                reason: "its code isn't written verbatim in the document (is it nested in a list or quote?)"
+                    .to_string(),
+            });
+        }
+
+        edits.push(Edit {
+            content: if new_code.is_empty() {
+                String::new()
+            } else {
+                format!("{}\n", new_code)
+            },
+            start_line,
+            end_line: start_line + line_count,
+        });
+    }
+    edits.sort_by_key(|edit| edit.start_line);
+    Ok(edits)
+}
+
+/// Applies edits to `markdown`, replacing the lines from `start_line` up to, but not
+/// including, `end_line` (both 1-based) of each edit with its content.
+/// The edits must not overlap.
+pub fn apply_edits(markdown: &str, edits: &[Edit]) -> String {
+    let lines: Vec<&str> = markdown.split_inclusive('\n').collect();
+    let mut sorted_edits: Vec<&Edit> = edits.iter().collect();
+    sorted_edits.sort_by_key(|edit| edit.start_line);
+
+    let mut result = String::new();
+    let mut next_line = 0;
+    for edit in sorted_edits {
+        let start = (edit.start_line - 1).clamp(next_line, lines.len());
+        result.push_str(&lines[next_line..start].concat());
+        result.push_str(&edit.content);
+        next_line = (edit.end_line - 1).clamp(start, lines.len());
+    }
+    result.push_str(&lines[next_line..].concat());
+    result
+}
+
+fn parse_marker<'a>(content: &'a str, line_comment: &str, kind: &str) -> Option<&'a str> {
+    content
+        .strip_prefix(line_comment)?
+        .trim_start()
+        .strip_prefix(kind)?
+        .strip_prefix(' ')
+        .map(str::trim)
+}
+
+// Parses `tag:piece`
+fn parse_chunk_id(id: &str) -> Option<(&str, usize)> {
+    let (tag, piece) = id.rsplit_once(':')?;
+    Some((tag, piece.parse().ok()?))
+}
+
+// Builds a regex matching the lines generated from a `LanguageConfig::line_directive` template
+fn directive_to_regex(template: &str) -> Regex {
+    let pattern = regex::escape(template)
+        .replace(r"\{line\}", r"\d+")
+        .replace(r"\{file\}", ".*");
+    Regex::new(&format!("^{}$", pattern)).expect("an escaped template is a valid regex")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::CodeBlock;
+    use std::collections::HashMap;
+
+    fn piece(tag: &str, index: usize, code: &str) -> UntangledPiece {
+        UntangledPiece {
+            tag: tag.to_string(),
+            piece: index,
+            code: code.to_string(),
+            file: "main.rs".to_string(),
+            line: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_marked_code_restores_references() {
+        let code = r#"// tanglit:begin main:0
+// line 3 "doc.md"
+fn main() {
+    // tanglit:begin body:0
+    // line 9 "doc.md"
+    let x = 1;
+
+    println!("{}", x);
+    // tanglit:end body:0
+    // tanglit:begin body:1
+    x += 1;
+    // tanglit:end body:1
+}
+// tanglit:end main:0"#;
+
+        let pieces = parse_marked_code(
+            code,
+            "main.rs",
+            "//",
+            Some("// line {line} \"{file}\""),
+            &MacroSyntax::default(),
+        )
+        .unwrap();
+
+        let mut body_0 = piece("body", 0, "let x = 1;\n\nprintln!(\"{}\", x);");
+        body_0.line = 4;
+        let mut body_1 = piece("body", 1, "x += 1;");
+        body_1.line = 10;
+        assert_eq!(
+            pieces,
+            vec![
+                body_0,
+                body_1,
+                piece("main", 0, "fn main() {\n    @[body]\n}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_marked_code_rejects_malformed_markers() {
+        let unclosed = "# tanglit:begin main:0\nprint(1)";
+        let mismatched = "# tanglit:begin main:0\n# tanglit:end other:0";
+        let outside = "# tanglit:begin main:0\n# tanglit:end main:0\nprint(1)";
+        let outdented = "# tanglit:begin main:0\n    # tanglit:begin inner:0\n  x\n    # tanglit:end inner:0\n# tanglit:end main:0";
+
+        for (code, line) in [(unclosed, 1), (mismatched, 2), (outside, 3), (outdented, 3)] {
+            match parse_marked_code(code, "main.py", "#", None, &MacroSyntax::default()) {
+                Err(UntangleError::InvalidMarkers { line: l, .. }) => assert_eq!(l, line),
+                other => panic!("expected invalid markers, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pieces_to_edits() {
+        let markdown = "# Doc\n\n```rust main\nfn main() {\n    @[body]\n}\n```\n\n```rust body\nlet x = 1;\n```\n";
+        let blocks = CodeBlocks::from_codeblocks(HashMap::from([
+            (
+                "main".to_string(),
+                CodeBlock::new(
+                    Some("rust".to_string()),
+                    "fn main() {\n    @[body]\n}".to_string(),
+                    "main".to_string(),
+                    vec![],
+                    None,
+                    3,
+                    7,
+                ),
+            ),
+            (
+                "body".to_string(),
+                CodeBlock::new(
+                    Some("rust".to_string()),
+                    "let x = 1;".to_string(),
+                    "body".to_string(),
+                    vec![],
+                    None,
+                    9,
+                    11,
+                ),
+            ),
+        ]));
+        let expected = vec![
+            piece("body", 0, "let x = 1;"),
+            piece("main", 0, "fn main() {\n    @[body]\n}"),
+        ];
+
+        let found = vec![
+            piece("body", 0, "let x = 2;\nlet y = 3;"),
+            piece("main", 0, "fn main() {\n    @[body]\n}"),
+        ];
+        let edits = pieces_to_edits(markdown, &blocks, found, expected.clone()).unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].start_line, 10);
+        assert_eq!(edits[0].end_line, 11);
+        assert_eq!(
+            apply_edits(markdown, &edits),
+            "# Doc\n\n```rust main\nfn main() {\n    @[body]\n}\n```\n\n```rust body\nlet x = 2;\nlet y = 3;\n```\n"
+        );
+
+        let conflicting = vec![
+            piece("body", 0, "let x = 2;"),
+            piece("body", 0, "let x = 3;"),
+        ];
+        assert!(matches!(
+            pieces_to_edits(markdown, &blocks, conflicting, expected.clone()),
+            Err(UntangleError::ConflictingChanges { .. })
+        ));
+
+        let same_change_twice = vec![
+            piece("body", 0, "let x = 2;"),
+            piece("body", 0, "let x = 2;"),
+            piece("body", 0, "let x = 1;"),
+        ];
+        assert_eq!(
+            pieces_to_edits(markdown, &blocks, same_change_twice, expected)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -11,6 +11,9 @@ pub enum ExecutionError {
     ExecutionScriptNotFound,
     TemplateNotFound,
     IOError(String),
+    SandboxError(String),
+    SessionError(String),
+    TestFailure(String),
 }
 
 impl fmt::Display for ExecutionError {
@@ -29,6 +32,9 @@ impl fmt::Display for ExecutionError {
                 write!(f, "Template file not found in language configuration")
             }
             ExecutionError::IOError(msg) => write!(f, "IO Error: {}", msg),
+            ExecutionError::SandboxError(msg) => write!(f, "Sandbox error: {}", msg),
+            ExecutionError::SessionError(msg) => write!(f, "Session error: {}", msg),
+            ExecutionError::TestFailure(msg) => write!(f, "Test failure: {}", msg),
         }
     }
 }
@@ -49,6 +55,9 @@ impl fmt::Debug for ExecutionError {
                 write!(f, "Template file not found in language configuration")
             }
             ExecutionError::IOError(msg) => write!(f, "IO Error: {}", msg),
+            ExecutionError::SandboxError(msg) => write!(f, "Sandbox error: {}", msg),
+            ExecutionError::SessionError(msg) => write!(f, "Session error: {}", msg),
+            ExecutionError::TestFailure(msg) => write!(f, "Test failure: {}", msg),
         }
     }
 }
@@ -1,33 +1,177 @@
+mod artifacts;
+mod cache;
+mod diagnostics;
+mod literate_tests;
+mod notebook;
+mod process;
 mod render_engine;
+mod sandbox;
+mod session;
+mod streaming;
 mod wrappers;
 
 use crate::configuration::get_temp_dir;
 use crate::configuration::language_config::LanguageConfig;
-use crate::doc::TangleError;
 use crate::doc::TanglitDoc;
-use crate::errors::ExecutionError;
-use log::debug;
+use crate::doc::{CodeBlock, CodeBlocks, RunSettings, TangleError};
+use crate::errors::{ConfigError, ExecutionError};
+use crate::utils::{parse_duration, parse_size};
+pub use artifacts::{
+    ARTIFACTS_ENV_VAR, Artifact, MAX_INLINE_ARTIFACT_SIZE, MAX_INLINE_ARTIFACTS_SIZE,
+};
+use artifacts::{artifacts_dir, collect_artifacts, prepare_artifacts_dir, save_artifacts};
+use cache::{CacheEntry, cache_key};
+pub use cache::{PruneStats, cache_dir, prune_cache};
+pub use diagnostics::{Diagnostic, Severity, parse_diagnostics};
+use indexmap::IndexMap;
+pub use literate_tests::{
+    BlockTest, Compared, Mismatch, Normalization, TestOptions, TestResult, TestSummary, compare,
+    diff, json_report, junit_report, run_tests, run_tests_streaming,
+};
+use log::{debug, warn};
+pub use notebook::{
+    BlockRun, BlockRunResult, ExecuteAllOptions, NotebookEvent, execute_all, execute_all_streaming,
+};
+use process::run_with_limits;
+pub use process::{OutputStream, ResourceLimits, ResourceUsage};
+pub use sandbox::Sandbox;
+use sandbox::{check_available, sandbox_limits, sandboxed_command, spawn_error};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::process::{Command, Stdio};
-pub use wrappers::{make_executable_code, write_code_to_file, write_file};
+use session::run_in_session;
+pub use session::{SessionKey, kill_all_sessions, kill_session, restart_session, running_sessions};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use streaming::ChunkDecoder;
+pub use streaming::OutputChunk;
+use tempfile::TempDir;
+use wrappers::checked_path;
+// Time limit of an execution when neither the block nor its language set one
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+// Output kept from an execution when its language doesn't set a limit
+pub const DEFAULT_MAX_OUTPUT_BYTES: u64 = 16 * 1024 * 1024;
+/// Environment variable holding the scratch directory of an execution, where its code and
+/// the data files of the document are written. Blocks run from another directory with `cwd=`
+/// find the data files there, since they aren't written into that directory.
+pub const WORK_DIR_ENV_VAR: &str = "TANGLIT_WORK_DIR";
+/// Environment variable listing the source files of the execution other than the code of the
+/// block, one per line and relative to the directory of that code, so execution scripts of
+/// compiled languages know which files to build. See `TanglitDoc::unit_files`.
+pub const UNIT_FILES_ENV_VAR: &str = "TANGLIT_UNIT_FILES";
+
+pub use wrappers::{
+    export_path, full_filename, make_executable_code, make_executable_code_with_source_map,
+    unit_file_path, write_code_to_file, write_extra_files, write_file,
+};
 
 /// Executes a code block by tangling it and adding necessary wrappers to make it executable.
 /// Prints both the resulting stdout and sterr from the execution and returns the stdout as a String.
 /// # Arguments
 /// * `doc` - A reference to the TanglitDoc containing the code blocks
 /// * `target_block` - The name of the target code block to execute
+/// * `options` - Settings of the execution, see `ExecutionOptions`
 /// # Returns
 /// * Result containing the stdout of the execution or an error if something goes wrong
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ExecutionOutput {
     pub status: Option<i32>,
     pub stdout: String,
     pub stderr: String,
+    /// Errors and warnings parsed from stderr, pointing to the markdown lines when possible.
+    #[serde(default)]
+    pub diagnostics: Vec<Diagnostic>,
+    /// Whether the execution was killed for exceeding its time limit, in which case `status`
+    /// is `None` and `stdout` and `stderr` hold what was printed until then.
+    #[serde(default)]
+    pub timed_out: bool,
+    /// Whether the execution was killed for exceeding its output limit, in which case `status`
+    /// is `None` and `stdout` and `stderr` are cut at the limit.
+    #[serde(default)]
+    pub output_truncated: bool,
+    /// Signal that ended the execution, if any, like `9` when it was killed.
+    #[serde(default)]
+    pub signal: Option<i32>,
+    /// Time, CPU and memory used by the execution.
+    #[serde(default)]
+    pub usage: ResourceUsage,
+    /// Scratch directory the block ran in. It only exists after the execution
+    /// if it was kept with `ExecutionOptions::keep_temp`.
+    #[serde(default)]
+    pub work_dir: Option<String>,
+    /// Sandbox the block ran in.
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    /// Whether the output was taken from the cache of an earlier identical execution,
+    /// instead of running the block.
+    #[serde(default)]
+    pub cached: bool,
+    /// Files the block wrote to the directory of `ARTIFACTS_ENV_VAR`, like plots.
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+}
+
+impl ExecutionOutput {
+    /// The exit code as written in output blocks: the status, `timed out` or `None`.
+    pub fn exit_code_label(&self) -> String {
+        if self.timed_out {
+            "timed out".to_string()
+        } else {
+            self.status.map_or("None".to_string(), |s| s.to_string())
+        }
+    }
+}
+
+/// Settings of an execution other than the block to run.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionOptions {
+    /// Input for the execution, overriding the one declared in the document.
+    pub stdin: Option<String>,
+    /// Keeps the scratch directory of the execution instead of removing it when it finishes.
+    pub keep_temp: bool,
+    /// Sandbox to run the block in, overriding the one of its language configuration.
+    pub sandbox: Option<Sandbox>,
+    /// Reuses the output of an identical execution if it is cached, see `cache_dir`, instead
+    /// of running the block, and caches the output otherwise.
+    pub cache: bool,
+    /// Environment, arguments and directory overriding the ones of the block, with the
+    /// directory relative to the current one instead of the markdown file.
+    pub run: RunSettings,
+}
+
+/// How the program of a block is started, besides its code.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Invocation {
+    /// Arguments passed to the execution script after the block file, which it passes on
+    /// to the program.
+    pub args: Vec<String>,
+    /// Variables added to the environment the execution inherits.
+    pub env: Vec<(String, String)>,
+    /// Directory the execution runs from, its scratch directory if `None`.
+    pub cwd: Option<PathBuf>,
+    /// Standard input of the execution.
+    pub stdin: Option<String>,
 }
 
-pub fn execute(doc: &TanglitDoc, target_block: &str) -> Result<ExecutionOutput, ExecutionError> {
+pub fn execute(
+    doc: &TanglitDoc,
+    target_block: &str,
+    options: &ExecutionOptions,
+) -> Result<ExecutionOutput, ExecutionError> {
+    execute_streaming(doc, target_block, options, &mut |_| {})
+}
+
+/// Same as `execute`, but calls `on_output` with every chunk of stdout and stderr
+/// as soon as the block prints it.
+pub fn execute_streaming(
+    doc: &TanglitDoc,
+    target_block: &str,
+    options: &ExecutionOptions,
+    on_output: &mut dyn FnMut(OutputChunk),
+) -> Result<ExecutionOutput, ExecutionError> {
     let blocks = doc.get_code_blocks()?;
 
     let block = blocks
@@ -43,15 +187,140 @@ pub fn execute(doc: &TanglitDoc, target_block: &str) -> Result<ExecutionOutput,
 
     let lang_config = LanguageConfig::load_for_lang(lang)?;
 
-    // create the executable source code
-    let output = make_executable_code(block, &blocks, &lang_config)?;
+    // create the executable source code, with the template of the sessions for blocks run in one
+    let output = match (&block.session, &lang_config.session_template) {
+        (Some(_), Some(template)) => {
+            let session_config = LanguageConfig {
+                template: Some(template.clone()),
+                ..lang_config.clone()
+            };
+            make_executable_code_with_source_map(
+                block,
+                &blocks,
+                &session_config,
+                doc.source_name(),
+            )?
+        }
+        _ => make_executable_code_with_source_map(block, &blocks, &lang_config, doc.source_name())?,
+    };
+
+    let limits = resolve_limits(block.timeout, &lang_config, lang)?;
+    let sandbox = resolve_sandbox(options.sandbox, &lang_config, lang)?;
+    let stdin = match &options.stdin {
+        Some(stdin) => Some(stdin.clone()),
+        None => read_block_stdin(doc, block, &blocks)?,
+    };
+    let mut invocation = resolve_invocation(doc, block, options, stdin)?;
+    let unit_files = doc.unit_files(&blocks, block)?;
+    let unit_file_list: Vec<String> = unit_files
+        .iter()
+        .map(|file| file.path.to_string_lossy().into_owned())
+        .collect();
+    let mut extra_files = doc.data_files(&blocks)?;
+    extra_files.extend(unit_files);
+    // The code of the block would be overwritten by the file
+    let main_file = full_filename(target_block, lang_config.extension.as_deref());
+    if let Some(file) = extra_files
+        .iter()
+        .find(|file| file.path == Path::new(&main_file))
+    {
+        return Err(ExecutionError::WriteError(format!(
+            "{} would overwrite the code of block {}, declare it under another name",
+            file.path.display(),
+            target_block
+        )));
+    }
+
+    if let Some(session) = &block.session {
+        if invocation.stdin.is_some() {
+            return Err(ExecutionError::SessionError(format!(
+                "Block {} runs in session {}, whose blocks can't be fed standard input",
+                target_block, session
+            )));
+        }
+        // The interpreter outlives the block, so only the settings of the whole document apply
+        if !block.run.is_empty() {
+            return Err(ExecutionError::SessionError(format!(
+                "Block {} runs in session {}, whose blocks can't set env=, args= or cwd=, \
+                 set them for the whole document instead",
+                target_block, session
+            )));
+        }
+        let (mut execution_output, block_file_path) = decoding(on_output, |on_bytes| {
+            run_in_session(
+                SessionKey::new(doc.file_path(), lang, session),
+                &lang_config,
+                sandbox,
+                &limits,
+                &invocation,
+                |dir| {
+                    write_extra_files(&extra_files, dir)?;
+                    write_file(
+                        output.code,
+                        dir,
+                        target_block,
+                        lang_config.extension.as_deref(),
+                    )
+                },
+                |artifacts, dir| save_large_artifacts(doc, block, artifacts, dir),
+                on_bytes,
+            )
+        })?;
+        execution_output.diagnostics = parse_diagnostics(
+            &execution_output.stderr,
+            &lang_config.diagnostic_regexes,
+            &block_file_path,
+            &output.source_map,
+        );
+        return Ok(execution_output);
+    }
+
+    invocation
+        .env
+        .push((UNIT_FILES_ENV_VAR.to_string(), unit_file_list.join("\n")));
+
+    // Executions whose scratch directory is kept are never cached, since it wouldn't exist
+    let cache_key = (options.cache && !options.keep_temp)
+        .then(|| {
+            cache_key(
+                &output.code,
+                lang,
+                &lang_config,
+                &invocation,
+                &extra_files,
+                sandbox,
+                &limits,
+            )
+        })
+        .flatten();
+    if let Some(CacheEntry {
+        output: mut execution_output,
+        block_file,
+    }) = cache_key.as_deref().and_then(cache::load)
+    {
+        debug!("Using the cached output of block {}", target_block);
+        replay(&execution_output, on_output);
+        execution_output.diagnostics = parse_diagnostics(
+            &execution_output.stderr,
+            &lang_config.diagnostic_regexes,
+            &block_file,
+            &output.source_map,
+        );
+        execution_output.cached = true;
+        return Ok(execution_output);
+    }
 
-    // Write the output to a file
-    let tmp_dir = &get_temp_dir();
+    // Every execution gets its own directory, so simultaneous ones don't overwrite each other's files
+    let work_dir = create_work_dir(target_block, options.keep_temp)
+        .map_err(|e| ExecutionError::WriteError(e.to_string()))?;
+    debug!("Executing in {}", work_dir.path().display());
 
+    // Write the data and source files of the execution, then its code
+    write_extra_files(&extra_files, work_dir.path())
+        .map_err(|e| ExecutionError::WriteError(e.to_string()))?;
     let block_file_path = write_file(
-        output,
-        tmp_dir,
+        output.code,
+        work_dir.path(),
         target_block,
         lang_config.extension.as_deref(),
     )
@@ -64,16 +333,292 @@ pub fn execute(doc: &TanglitDoc, target_block: &str) -> Result<ExecutionOutput,
         .as_ref()
         .ok_or(ExecutionError::ExecutionScriptNotFound)?;
 
-    execute_block(&block_file_path, execution_script)
+    let mut execution_output = execute_block(
+        work_dir.path(),
+        &block_file_path,
+        execution_script,
+        &invocation,
+        sandbox,
+        &limits,
+        on_output,
+    )?;
+    save_large_artifacts(
+        doc,
+        block,
+        &mut execution_output.artifacts,
+        &artifacts_dir(work_dir.path()),
+    )
+    .map_err(|e| {
+        ExecutionError::WriteError(format!("Failed to save the artifacts of the block: {}", e))
+    })?;
+    execution_output.diagnostics = parse_diagnostics(
+        &execution_output.stderr,
+        &lang_config.diagnostic_regexes,
+        &block_file_path,
+        &output.source_map,
+    );
+    if let Some(key) = &cache_key {
+        if let Err(e) = cache::store(key, &execution_output, &block_file_path) {
+            warn!(
+                "Failed to cache the output of block {}: {}",
+                target_block, e
+            );
+        }
+    }
+    execution_output.work_dir = Some(work_dir.path().display().to_string());
+    Ok(execution_output)
+}
+
+// Passes a cached output on as if the block printed it, first its stdout and then its stderr
+fn replay(output: &ExecutionOutput, on_output: &mut dyn FnMut(OutputChunk)) {
+    for (stream, text) in [
+        (OutputStream::Stdout, &output.stdout),
+        (OutputStream::Stderr, &output.stderr),
+    ] {
+        if !text.is_empty() {
+            on_output(OutputChunk {
+                stream,
+                text: text.clone(),
+                elapsed_ms: 0,
+            });
+        }
+    }
+}
+
+/// Returns the limits of an execution of `lang`, the time limit of the block overriding
+/// the one of its language.
+fn resolve_limits(
+    block_timeout: Option<Duration>,
+    lang_config: &LanguageConfig,
+    lang: &str,
+) -> Result<ResourceLimits, ConfigError> {
+    let timeout = match (block_timeout, &lang_config.timeout) {
+        (Some(timeout), _) => timeout,
+        (None, Some(timeout)) => parse_duration(timeout).ok_or_else(|| {
+            ConfigError::ParseError(format!("Invalid timeout '{}' for {}", timeout, lang))
+        })?,
+        (None, None) => DEFAULT_TIMEOUT,
+    };
+
+    Ok(ResourceLimits {
+        wall_time: Some(timeout),
+        cpu_time: parse_setting(&lang_config.max_cpu_time, parse_duration, "CPU time", lang)?,
+        memory_bytes: parse_setting(&lang_config.max_memory, parse_size, "memory", lang)?,
+        output_bytes: Some(
+            parse_setting(&lang_config.max_output, parse_size, "output size", lang)?
+                .unwrap_or(DEFAULT_MAX_OUTPUT_BYTES),
+        ),
+        ..Default::default()
+    })
+}
+
+/// Returns the sandbox of an execution of `lang`, `sandbox` overriding the one of its language.
+fn resolve_sandbox(
+    sandbox: Option<Sandbox>,
+    lang_config: &LanguageConfig,
+    lang: &str,
+) -> Result<Sandbox, ConfigError> {
+    Ok(match (sandbox, &lang_config.sandbox) {
+        (Some(sandbox), _) => sandbox,
+        (None, Some(sandbox)) => sandbox
+            .parse()
+            .map_err(|e| ConfigError::ParseError(format!("{} for {}", e, lang)))?,
+        (None, None) => Sandbox::None,
+    })
+}
+
+// Passes the output of the process `run` waits for on as chunks of text, including the
+// bytes left at the end of its streams
+fn decoding<R>(
+    on_output: &mut dyn FnMut(OutputChunk),
+    run: impl FnOnce(&mut dyn FnMut(OutputStream, &[u8])) -> R,
+) -> R {
+    let mut decoder = ChunkDecoder::new();
+    let result = run(&mut |stream, bytes| {
+        if let Some(chunk) = decoder.decode(stream, bytes) {
+            on_output(chunk);
+        }
+    });
+    for chunk in decoder.finish() {
+        on_output(chunk);
+    }
+    result
+}
+
+// Parses a setting of the language configuration, failing if it's set but invalid
+fn parse_setting<T>(
+    value: &Option<String>,
+    parse: fn(&str) -> Option<T>,
+    setting: &str,
+    lang: &str,
+) -> Result<Option<T>, ConfigError> {
+    value
+        .as_deref()
+        .map(|value| {
+            parse(value).ok_or_else(|| {
+                ConfigError::ParseError(format!("Invalid {} '{}' for {}", setting, value, lang))
+            })
+        })
+        .transpose()
+}
+
+/// Returns how `block` is started with `stdin`. The environment, arguments and directory of
+/// `options` override the ones of the block, which override the ones of the document, except
+/// for variables, which are all set, the latest value of each one winning.
+fn resolve_invocation(
+    doc: &TanglitDoc,
+    block: &CodeBlock,
+    options: &ExecutionOptions,
+    stdin: Option<String>,
+) -> Result<Invocation, ExecutionError> {
+    let defaults = doc.run_settings();
+    let mut env: IndexMap<String, String> = IndexMap::new();
+    for (key, value) in defaults
+        .env
+        .iter()
+        .chain(&block.run.env)
+        .chain(&options.run.env)
+    {
+        env.insert(key.clone(), value.clone());
+    }
+    let args = options
+        .run
+        .args
+        .clone()
+        .or_else(|| block.run.args.clone())
+        .or(defaults.args)
+        .unwrap_or_default();
+    let cwd = match (
+        &options.run.cwd,
+        block.run.cwd.as_ref().or(defaults.cwd.as_ref()),
+    ) {
+        (Some(cwd), _) => Some(PathBuf::from(cwd)),
+        (None, Some(cwd)) => Some(document_dir(doc).join(cwd)),
+        (None, None) => None,
+    };
+    // The execution script runs from there, so its path must not be relative
+    let cwd = cwd
+        .map(|cwd| {
+            fs::canonicalize(&cwd).map_err(|e| {
+                ExecutionError::IOError(format!(
+                    "Failed to find working directory {}: {}",
+                    cwd.display(),
+                    e
+                ))
+            })
+        })
+        .transpose()?;
+
+    Ok(Invocation {
+        args,
+        env: env.into_iter().collect(),
+        cwd,
+        stdin,
+    })
+}
+
+// Directory of the markdown file, which paths in the document are relative to
+fn document_dir(doc: &TanglitDoc) -> &Path {
+    doc.file_path()
+        .and_then(|path| Path::new(path).parent())
+        .unwrap_or(Path::new(""))
 }
 
+// Saves the artifacts of `block` too large to embed, from the artifacts directory `dir`,
+// to `<document>.artifacts/<block>` next to the markdown file, if the document has one
+fn save_large_artifacts(
+    doc: &TanglitDoc,
+    block: &CodeBlock,
+    artifacts: &mut [Artifact],
+    dir: &Path,
+) -> io::Result<()> {
+    // Nothing is written or removed when every artifact is embedded
+    if artifacts.iter().all(|artifact| artifact.data.is_some()) {
+        return Ok(());
+    }
+    let Some(stem) = doc.file_path().and_then(|path| Path::new(path).file_stem()) else {
+        return Ok(());
+    };
+    let mut artifacts_root = stem.to_os_string();
+    artifacts_root.push(".artifacts");
+    // The directory of the block is emptied, so its tag must not lead out of the artifacts one
+    let block_dir = checked_path(
+        &block.tag,
+        block,
+        "artifacts directory",
+        &format!("{} directory", artifacts_root.to_string_lossy()),
+    )?;
+    let save_dir = Path::new(&artifacts_root).join(block_dir);
+    save_artifacts(artifacts, dir, document_dir(doc), &save_dir)
+}
+
+/// Returns the input declared for `block` in the document: the content of its `stdin=` file,
+/// resolved from the directory of the markdown file, or else the content of its `input` block.
+fn read_block_stdin(
+    doc: &TanglitDoc,
+    block: &CodeBlock,
+    blocks: &CodeBlocks,
+) -> Result<Option<String>, ExecutionError> {
+    if let Some(stdin_file) = &block.stdin_file {
+        let stdin_path = document_dir(doc).join(stdin_file);
+        let stdin = fs::read_to_string(&stdin_path).map_err(|e| {
+            ExecutionError::IOError(format!(
+                "Failed to read stdin file {}: {}",
+                stdin_path.display(),
+                e
+            ))
+        })?;
+        return Ok(Some(stdin));
+    }
+
+    // The code of a block doesn't keep the newline closing its last line
+    Ok(blocks
+        .get_input_block(&block.tag)
+        .map(|input| format!("{}\n", input.code)))
+}
+
+/// Creates a directory of its own for an execution of the block `tag` under the temporary
+/// directory, which is removed when dropped unless `keep` is set.
+fn create_work_dir(tag: &str, keep: bool) -> io::Result<TempDir> {
+    fs::create_dir_all(get_temp_dir())?;
+    // The scripts run from within the directory, so its path must not be relative
+    let temp_dir = fs::canonicalize(get_temp_dir())?;
+    let prefix: String = tag
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    tempfile::Builder::new()
+        .prefix(&format!("{}-", prefix))
+        .disable_cleanup(keep)
+        .tempdir_in(temp_dir)
+}
+
+/// Runs `execution_script` on the block file, followed by the arguments of `invocation`, from
+/// the directory of `invocation` or else `work_dir`, killing it and everything it started if
+/// it exceeds the time or output `limits`. The input of `invocation` is fed to its standard
+/// input, and `on_output` gets its output while it runs. The script runs within `sandbox`,
+/// which can only write to `work_dir`. The files it writes to the directory of
+/// `ARTIFACTS_ENV_VAR` are returned as its artifacts.
 pub fn execute_block(
+    work_dir: &Path,
     block_file_path: &Path,
     execution_script: &str,
+    invocation: &Invocation,
+    sandbox: Sandbox,
+    limits: &ResourceLimits,
+    on_output: &mut dyn FnMut(OutputChunk),
 ) -> Result<ExecutionOutput, ExecutionError> {
+    check_available(sandbox)?;
+
     let execution_script_path = write_file(
         execution_script.into(),
-        get_temp_dir().as_path(),
+        work_dir,
         "execute_script",
         Some("sh"),
     )
@@ -87,16 +632,223 @@ pub fn execute_block(
         .map_err(|e| ExecutionError::InternalError(format!("chmod failed: {e}")))?;
 
     // Execute the script
-    let output = Command::new(&execution_script_path)
-        .arg(block_file_path)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .map_err(|e| ExecutionError::InternalError(e.to_string()))?;
+    let args: Vec<&OsStr> = std::iter::once(block_file_path.as_os_str())
+        .chain(invocation.args.iter().map(OsStr::new))
+        .collect();
+    let mut command = sandboxed_command(
+        sandbox,
+        work_dir,
+        invocation.cwd.as_deref().unwrap_or(work_dir),
+        &execution_script_path,
+        &args,
+    );
+    command.envs(invocation.env.iter().map(|(key, value)| (key, value)));
+    command.env(WORK_DIR_ENV_VAR, work_dir);
+    let artifacts_dir =
+        prepare_artifacts_dir(work_dir).map_err(|e| ExecutionError::WriteError(e.to_string()))?;
+    command.env(ARTIFACTS_ENV_VAR, &artifacts_dir);
+    let limits = match sandbox {
+        Sandbox::None => *limits,
+        Sandbox::Bubblewrap => sandbox_limits(limits),
+    };
+    let output = decoding(on_output, |on_bytes| {
+        run_with_limits(
+            &mut command,
+            invocation.stdin.as_deref().map(str::as_bytes),
+            &limits,
+            on_bytes,
+        )
+    })
+    .map_err(|e| spawn_error(sandbox, e))?;
 
+    if output.timed_out {
+        debug!("Execution timed out after {:?}", limits.wall_time);
+    }
+    if output.output_truncated {
+        debug!("Execution output exceeded {:?} bytes", limits.output_bytes);
+    }
+    let artifacts = collect_artifacts(&artifacts_dir).map_err(|e| {
+        ExecutionError::IOError(format!("Failed to read the artifacts of the block: {}", e))
+    })?;
     Ok(ExecutionOutput {
-        status: output.status.code(),
-        stdout: String::from_utf8(output.stdout).expect("Error reading stdout"),
-        stderr: String::from_utf8(output.stderr).expect("Error reading stderr"),
+        status: output.status.and_then(|status| status.code()),
+        // The output of a timed out execution may end in the middle of a character
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        timed_out: output.timed_out,
+        output_truncated: output.output_truncated,
+        signal: output.signal,
+        usage: output.usage,
+        sandbox,
+        artifacts,
+        ..Default::default()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_env::with_var;
+
+    #[test]
+    fn test_create_work_dir() {
+        let temp_dir = std::env::temp_dir().join("tanglit_test_work_dirs");
+        with_var("TANGLIT_TEMP_DIR", Some(&temp_dir), || {
+            let first = create_work_dir("main/x", false).unwrap();
+            let second = create_work_dir("main/x", true).unwrap();
+            assert_ne!(first.path(), second.path());
+            assert!(
+                first
+                    .path()
+                    .starts_with(fs::canonicalize(&temp_dir).unwrap())
+            );
+            assert!(
+                first
+                    .path()
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with("main_x-")
+            );
+
+            let (first_path, second) = (first.path().to_path_buf(), second.keep());
+            // Only the directory that wasn't kept is removed once dropped
+            drop(first);
+            assert!(!first_path.exists());
+            assert!(second.exists());
+            fs::remove_dir_all(second).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_session_blocks_use_the_session_template() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let lang_dir = config_dir.path().join("executors").join("shell");
+        fs::create_dir_all(&lang_dir).unwrap();
+        fs::write(
+            lang_dir.join("config.toml"),
+            r#"
+            extension = "sh"
+            session_command = ["sh"]
+            session_input = ". {file}; s=$?; echo {marker} >&2; echo {marker}:$s\n"
+            "#,
+        )
+        .unwrap();
+        // The interpreter would end with the template of whole programs
+        fs::write(lang_dir.join("template"), "exit 1\n#<BODY>#\n").unwrap();
+        fs::write(lang_dir.join("session_template"), "#<BODY>#\n").unwrap();
+
+        let doc = TanglitDoc::new_from_string("```shell main session=s\necho hi\n```\n").unwrap();
+        with_var("TANGLIT_CONFIG_DIR", Some(config_dir.path()), || {
+            let output = execute(&doc, "main", &ExecutionOptions::default()).unwrap();
+            assert_eq!(output.stdout, "hi\n");
+            assert_eq!(output.status, Some(0));
+        });
+        kill_session(&SessionKey::new(None, "shell", "s"));
+    }
+
+    #[test]
+    fn test_cache_is_opt_in() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let doc = TanglitDoc::new_from_string(
+            "```python main\nimport random\nprint(random.random())\n```\n",
+        )
+        .unwrap();
+        with_var("TANGLIT_TEMP_DIR", Some(temp_dir.path()), || {
+            let run = |cache| {
+                execute(
+                    &doc,
+                    "main",
+                    &ExecutionOptions {
+                        cache,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+            };
+            assert!(!run(false).cached);
+            assert!(!run(false).cached);
+            let first = run(true);
+            let second = run(true);
+            assert!(!first.cached);
+            assert!(second.cached);
+            assert_eq!(first.stdout, second.stdout);
+        });
+    }
+
+    #[test]
+    fn test_save_large_artifacts_of_tags_leading_out_of_the_artifacts_dir() {
+        let doc_dir = tempfile::tempdir().unwrap();
+        let doc_path = doc_dir.path().join("doc.md");
+        fs::write(&doc_path, "# Doc\n").unwrap();
+        let doc = TanglitDoc::new_from_file(doc_path.to_str().unwrap()).unwrap();
+        let work_dir = tempfile::tempdir().unwrap();
+        fs::write(work_dir.path().join("big.bin"), "big").unwrap();
+        let outside = doc_dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("keep.txt"), "keep").unwrap();
+        let block = |tag: &str| {
+            CodeBlock::new(
+                Some("python".to_string()),
+                String::new(),
+                tag.to_string(),
+                Vec::new(),
+                None,
+                1,
+                3,
+            )
+        };
+        let artifact = |data: Option<&str>| Artifact {
+            name: "big.bin".to_string(),
+            mime_type: "application/octet-stream".to_string(),
+            size: 3,
+            data: data.map(str::to_string),
+            path: None,
+        };
+
+        let absolute = outside.to_string_lossy().into_owned();
+        for tag in ["../outside", "..", "plot/../../outside", absolute.as_str()] {
+            let mut artifacts = vec![artifact(None)];
+            let err = save_large_artifacts(&doc, &block(tag), &mut artifacts, work_dir.path())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", tag);
+            assert!(artifacts[0].path.is_none());
+        }
+        assert!(outside.join("keep.txt").exists());
+        assert!(!doc_dir.path().join("doc.artifacts").exists());
+
+        // Nothing is written when every artifact is embedded
+        let mut embedded = vec![artifact(Some("Ymln"))];
+        save_large_artifacts(&doc, &block("plot"), &mut embedded, work_dir.path()).unwrap();
+        assert!(!doc_dir.path().join("doc.artifacts").exists());
+
+        let mut artifacts = vec![artifact(None)];
+        save_large_artifacts(&doc, &block("plot"), &mut artifacts, work_dir.path()).unwrap();
+        assert_eq!(
+            artifacts[0].path.as_deref(),
+            Some("doc.artifacts/plot/big.bin")
+        );
+    }
+
+    #[test]
+    fn test_unit_file_named_like_the_block() {
+        let doc = TanglitDoc::new_from_string(
+            r#"```c helper file=main.c
+int helper;
+```
+
+```c main use=[helper]
+return 0;
+```
+"#,
+        )
+        .unwrap();
+        let err = execute(&doc, "main", &ExecutionOptions::default()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("main.c would overwrite the code of block main"),
+            "{}",
+            err
+        );
+    }
+}
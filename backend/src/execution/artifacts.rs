@@ -0,0 +1,250 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Environment variable holding the directory where a block writes the files it produces,
+/// like plots, which are collected into its output once it finishes.
+pub const ARTIFACTS_ENV_VAR: &str = "TANGLIT_ARTIFACTS_DIR";
+
+// Directory of the artifacts, under the scratch directory of the execution
+const ARTIFACTS_DIR_NAME: &str = "artifacts";
+
+/// Files larger than this are not embedded in the output, they are saved next to the
+/// document instead.
+pub const MAX_INLINE_ARTIFACT_SIZE: u64 = 256 * 1024;
+
+/// Total size of the files embedded in the output of an execution, the files past it are
+/// saved next to the document like larger ones.
+pub const MAX_INLINE_ARTIFACTS_SIZE: u64 = 1024 * 1024;
+
+/// A file produced by a block in the directory of `ARTIFACTS_ENV_VAR`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Artifact {
+    /// Path of the file within the artifacts directory, like `plots/sine.png`.
+    pub name: String,
+    /// MIME type guessed from the extension of the file, like `image/png`.
+    pub mime_type: String,
+    pub size: u64,
+    /// Content of the file encoded in base64, `None` if it's too large to embed.
+    pub data: Option<String>,
+    /// Path of the copy of a file too large to embed, relative to the directory of the
+    /// document, `None` if the document has no file to save it next to.
+    pub path: Option<String>,
+}
+
+impl Artifact {
+    pub fn is_image(&self) -> bool {
+        self.mime_type.starts_with("image/")
+    }
+
+    /// Returns the content of the file as a `data:` URI, which can be used as the source of
+    /// an image or the target of a link.
+    pub fn data_uri(&self) -> Option<String> {
+        let data = self.data.as_ref()?;
+        Some(format!("data:{};base64,{}", self.mime_type, data))
+    }
+}
+
+/// Returns the artifacts directory of the executions in `work_dir`.
+pub(super) fn artifacts_dir(work_dir: &Path) -> PathBuf {
+    work_dir.join(ARTIFACTS_DIR_NAME)
+}
+
+/// Returns the artifacts directory of the execution in `work_dir`, emptied of the files of
+/// any earlier execution.
+pub(super) fn prepare_artifacts_dir(work_dir: &Path) -> io::Result<PathBuf> {
+    let dir = artifacts_dir(work_dir);
+    match fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Reads the files written to the artifacts directory `dir`, sorted by name. Their content
+/// is embedded up to `MAX_INLINE_ARTIFACT_SIZE` per file and `MAX_INLINE_ARTIFACTS_SIZE` in
+/// total, in that order.
+pub(super) fn collect_artifacts(dir: &Path) -> io::Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+    collect_dir(dir, dir, &mut artifacts)?;
+    artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut inline_size = 0;
+    for artifact in &mut artifacts {
+        if artifact.size > MAX_INLINE_ARTIFACT_SIZE
+            || inline_size + artifact.size > MAX_INLINE_ARTIFACTS_SIZE
+        {
+            continue;
+        }
+        inline_size += artifact.size;
+        artifact.data = Some(STANDARD.encode(fs::read(dir.join(&artifact.name))?));
+    }
+    Ok(artifacts)
+}
+
+/// Copies the artifacts that weren't embedded from the artifacts directory `dir` to `save_dir`,
+/// a directory of the artifacts of the block within `doc_dir`, and sets their path to the copy.
+/// The files `save_dir` held from an earlier execution are removed, so it must be a relative
+/// path below the artifacts directory of the document, like `doc.artifacts/plot`.
+pub(super) fn save_artifacts(
+    artifacts: &mut [Artifact],
+    dir: &Path,
+    doc_dir: &Path,
+    save_dir: &Path,
+) -> io::Result<()> {
+    let below_artifacts_dir = save_dir.components().count() >= 2
+        && save_dir
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if !below_artifacts_dir {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid artifacts directory '{}': it must be a relative path inside the artifacts directory of the document",
+                save_dir.display()
+            ),
+        ));
+    }
+    let target_dir = doc_dir.join(save_dir);
+    match fs::remove_dir_all(&target_dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    for artifact in artifacts.iter_mut().filter(|a| a.data.is_none()) {
+        let target = target_dir.join(&artifact.name);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(dir.join(&artifact.name), &target)?;
+        // Links in markdown always use forward slashes
+        let path = save_dir.join(&artifact.name);
+        artifact.path = Some(path.to_string_lossy().replace('\\', "/"));
+    }
+    Ok(())
+}
+
+fn collect_dir(root: &Path, dir: &Path, artifacts: &mut Vec<Artifact>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_dir(root, &path, artifacts)?;
+            continue;
+        }
+        // Links could point anywhere outside of the scratch directory
+        if !file_type.is_file() {
+            continue;
+        }
+        let size = entry.metadata()?.len();
+        let name = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned();
+        artifacts.push(Artifact {
+            mime_type: mime_guess::from_path(&path)
+                .first_or_octet_stream()
+                .essence_str()
+                .to_string(),
+            name,
+            size,
+            data: None,
+            path: None,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_artifacts() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let dir = prepare_artifacts_dir(work_dir.path()).unwrap();
+        fs::create_dir(dir.join("plots")).unwrap();
+        fs::write(dir.join("plots/sine.png"), b"\x89PNG").unwrap();
+        fs::write(dir.join("results.csv"), "x,y\n").unwrap();
+
+        let artifacts = collect_artifacts(&dir).unwrap();
+        assert_eq!(artifacts.len(), 2);
+        assert_eq!(artifacts[0].name, "plots/sine.png");
+        assert!(artifacts[0].is_image());
+        assert_eq!(
+            artifacts[0].data_uri(),
+            Some("data:image/png;base64,iVBORw==".to_string())
+        );
+        assert_eq!(artifacts[1].name, "results.csv");
+        assert_eq!(artifacts[1].mime_type, "text/csv");
+        assert_eq!(artifacts[1].size, 4);
+
+        // The next execution starts with an empty directory
+        let dir = prepare_artifacts_dir(work_dir.path()).unwrap();
+        assert!(collect_artifacts(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_save_large_artifacts() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let doc_dir = tempfile::tempdir().unwrap();
+        let dir = prepare_artifacts_dir(work_dir.path()).unwrap();
+        let large = vec![0u8; MAX_INLINE_ARTIFACT_SIZE as usize + 1];
+        let medium = vec![1u8; MAX_INLINE_ARTIFACT_SIZE as usize];
+        fs::write(dir.join("a_large.bin"), &large).unwrap();
+        for name in ["b.bin", "c.bin", "d.bin", "e.bin"] {
+            fs::write(dir.join(name), &medium).unwrap();
+        }
+        fs::write(dir.join("f.txt"), "small").unwrap();
+
+        let mut artifacts = collect_artifacts(&dir).unwrap();
+        let embedded: Vec<&str> = artifacts
+            .iter()
+            .filter(|a| a.data.is_some())
+            .map(|a| a.name.as_str())
+            .collect();
+        // The fifth file past the first four would exceed the total size
+        assert_eq!(embedded, vec!["b.bin", "c.bin", "d.bin", "e.bin"]);
+
+        let save_dir = Path::new("doc.artifacts/plot");
+        fs::create_dir_all(doc_dir.path().join(save_dir)).unwrap();
+        fs::write(doc_dir.path().join(save_dir).join("old.bin"), "old").unwrap();
+        save_artifacts(&mut artifacts, &dir, doc_dir.path(), save_dir).unwrap();
+        assert_eq!(
+            artifacts[0].path.as_deref(),
+            Some("doc.artifacts/plot/a_large.bin")
+        );
+        assert_eq!(
+            fs::read(doc_dir.path().join("doc.artifacts/plot/a_large.bin")).unwrap(),
+            large
+        );
+        assert_eq!(
+            artifacts[5].path.as_deref(),
+            Some("doc.artifacts/plot/f.txt")
+        );
+        assert!(artifacts[1].path.is_none());
+        assert!(!doc_dir.path().join(save_dir).join("old.bin").exists());
+
+        // Directories that aren't below the artifacts directory are never emptied
+        for save_dir in ["doc.artifacts", "doc.artifacts/../other", "/tmp/plot"] {
+            assert!(
+                save_artifacts(&mut artifacts, &dir, doc_dir.path(), Path::new(save_dir)).is_err()
+            );
+        }
+        assert!(
+            doc_dir
+                .path()
+                .join("doc.artifacts/plot/a_large.bin")
+                .exists()
+        );
+    }
+}
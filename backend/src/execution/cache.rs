@@ -0,0 +1,377 @@
+use super::{ExecutionOutput, Invocation, ResourceLimits, Sandbox};
+use crate::configuration::get_temp_dir;
+use crate::configuration::language_config::LanguageConfig;
+use crate::doc::ExtraFile;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// Directory of the cache, under the temporary directory
+const CACHE_DIR_NAME: &str = "cache";
+const CACHE_FILE_EXTENSION: &str = "json";
+// Executions running from a directory holding more files than this aren't cached
+const MAX_CWD_FILES: usize = 10_000;
+
+/// An execution saved in the cache.
+#[derive(Serialize, Deserialize)]
+pub(super) struct CacheEntry {
+    /// The output, without diagnostics, since the lines they point to in the markdown may
+    /// have moved since.
+    pub output: ExecutionOutput,
+    /// Path the block file had when it ran, which its errors and warnings refer to.
+    pub block_file: PathBuf,
+}
+
+/// Files removed by `prune_cache`, and the bytes they took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    pub removed: usize,
+    pub freed_bytes: u64,
+}
+
+/// Returns the directory where the outputs of executions are cached.
+pub fn cache_dir() -> PathBuf {
+    get_temp_dir().join(CACHE_DIR_NAME)
+}
+
+/// Returns the key of an execution in the cache: a hash of everything its output depends on,
+/// which is the tangled code along with the template and execution script of its language,
+/// the other files written next to it, its resolved input, arguments, environment and directory,
+/// the size and modification time of the files under that directory, and the sandbox and limits
+/// it runs with.
+/// Returns `None` when the files under its directory can't all be listed, so it isn't cached.
+pub(super) fn cache_key(
+    code: &str,
+    lang: &str,
+    lang_config: &LanguageConfig,
+    invocation: &Invocation,
+    extra_files: &[ExtraFile],
+    sandbox: Sandbox,
+    limits: &ResourceLimits,
+) -> Option<String> {
+    let process = format!("{:?}", (&invocation.args, &invocation.env, &invocation.cwd));
+    let cwd_files = match &invocation.cwd {
+        Some(cwd) => Some(files_state(cwd)?),
+        None => None,
+    };
+    let extra_files = format!("{:?}", extra_files);
+    let sandbox = sandbox.to_string();
+    let limits = format!("{:?}", limits);
+    let mut hasher = Sha256::new();
+    let parts = [
+        Some(lang),
+        Some(code),
+        lang_config.template.as_deref(),
+        lang_config.execution_script.as_deref(),
+        invocation.stdin.as_deref(),
+        Some(&process),
+        cwd_files.as_deref(),
+        Some(&extra_files),
+        Some(&sandbox),
+        Some(&limits),
+    ];
+    for part in parts {
+        // Each part is prefixed by its length, so moving text from one to the next changes the key
+        match part {
+            Some(part) => {
+                hasher.update((part.len() as u64 + 1).to_le_bytes());
+                hasher.update(part.as_bytes());
+            }
+            None => hasher.update(0u64.to_le_bytes()),
+        }
+    }
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    )
+}
+
+/// Lists the path, size and modification time of every file under `dir`, in order, or returns
+/// `None` if some can't be read or there are more than `MAX_CWD_FILES` of them.
+/// Symbolic links are listed but not followed.
+fn files_state(dir: &Path) -> Option<String> {
+    let mut files = Vec::new();
+    let mut to_visit = vec![dir.to_path_buf()];
+    while let Some(current) = to_visit.pop() {
+        for entry in fs::read_dir(&current).ok()? {
+            let entry = entry.ok()?;
+            let metadata = entry.metadata().ok()?;
+            if metadata.is_dir() {
+                to_visit.push(entry.path());
+                continue;
+            }
+            let modified = metadata
+                .modified()
+                .ok()?
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            files.push(format!(
+                "{} {} {}",
+                entry.path().strip_prefix(dir).ok()?.display(),
+                metadata.len(),
+                modified.as_nanos()
+            ));
+            if files.len() > MAX_CWD_FILES {
+                debug!(
+                    "Not caching executions from {}, it holds too many files",
+                    dir.display()
+                );
+                return None;
+            }
+        }
+    }
+    files.sort();
+    Some(files.join("\n"))
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.{}", key, CACHE_FILE_EXTENSION))
+}
+
+/// Returns the execution cached under `key`, if any.
+pub(super) fn load(key: &str) -> Option<CacheEntry> {
+    load_from(&cache_dir(), key)
+}
+
+fn load_from(dir: &Path, key: &str) -> Option<CacheEntry> {
+    let path = entry_path(dir, key);
+    let content = fs::read_to_string(&path).ok()?;
+    let entry = serde_json::from_str(&content)
+        .inspect_err(|e| debug!("Ignoring invalid cache entry {}: {}", path.display(), e))
+        .ok()?;
+    // Entries are pruned by the last time they were used
+    if let Err(e) = File::options()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+    {
+        debug!("Failed to touch cache entry {}: {}", path.display(), e);
+    }
+    Some(entry)
+}
+
+/// Saves an execution in the cache under `key`. Executions that were killed aren't saved,
+/// since running them again may well end otherwise.
+pub(super) fn store(key: &str, output: &ExecutionOutput, block_file: &Path) -> io::Result<()> {
+    store_in(&cache_dir(), key, output, block_file)
+}
+
+fn store_in(dir: &Path, key: &str, output: &ExecutionOutput, block_file: &Path) -> io::Result<()> {
+    if output.timed_out || output.output_truncated || output.signal.is_some() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+    let entry = CacheEntry {
+        output: ExecutionOutput {
+            diagnostics: Vec::new(),
+            work_dir: None,
+            ..output.clone()
+        },
+        block_file: block_file.to_path_buf(),
+    };
+    let content = serde_json::to_string(&entry).map_err(io::Error::other)?;
+    // Written aside and renamed, so a simultaneous execution never reads half an entry
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    io::Write::write_all(&mut file, content.as_bytes())?;
+    file.persist(entry_path(dir, key))
+        .map_err(|e| e.error)
+        .map(|_| ())
+}
+
+/// Removes the cached executions that weren't used for `older_than`, or all of them.
+pub fn prune_cache(older_than: Option<Duration>) -> io::Result<PruneStats> {
+    prune_dir(&cache_dir(), older_than)
+}
+
+fn prune_dir(dir: &Path, older_than: Option<Duration>) -> io::Result<PruneStats> {
+    let mut stats = PruneStats::default();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(stats),
+        Err(e) => return Err(e),
+    };
+    let now = SystemTime::now();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(CACHE_FILE_EXTENSION) {
+            continue;
+        }
+        let metadata = fs::metadata(&path)?;
+        let unused_for = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or_default();
+        if older_than.is_some_and(|older_than| unused_for < older_than) {
+            continue;
+        }
+        fs::remove_file(&path)?;
+        stats.removed += 1;
+        stats.freed_bytes += metadata.len();
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        let config = LanguageConfig {
+            template: Some("fn main() { #<main># }".to_string()),
+            execution_script: Some("rustc $1".to_string()),
+            ..Default::default()
+        };
+        let limits = ResourceLimits::default();
+        let key = |code, stdin: Option<&str>| {
+            let invocation = Invocation {
+                stdin: stdin.map(str::to_string),
+                ..Default::default()
+            };
+            cache_key(
+                code,
+                "rust",
+                &config,
+                &invocation,
+                &[],
+                Sandbox::None,
+                &limits,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(key("a", None), key("a", None));
+        assert_eq!(key("a", None).len(), 64);
+        assert_ne!(key("a", None), key("b", None));
+        assert_ne!(key("a", None), key("a", Some("")));
+        assert_ne!(key("ab", Some("c")), key("a", Some("bc")));
+        let with_args = Invocation {
+            args: vec!["-v".to_string()],
+            ..Default::default()
+        };
+        assert_ne!(
+            key("a", None),
+            cache_key(
+                "a",
+                "rust",
+                &config,
+                &with_args,
+                &[],
+                Sandbox::None,
+                &limits
+            )
+            .unwrap()
+        );
+        let extra_file = ExtraFile {
+            path: PathBuf::from("data.csv"),
+            content: "1,2\n".to_string(),
+        };
+        assert_ne!(
+            key("a", None),
+            cache_key(
+                "a",
+                "rust",
+                &config,
+                &Invocation::default(),
+                &[extra_file],
+                Sandbox::None,
+                &limits
+            )
+            .unwrap()
+        );
+
+        let other_script = LanguageConfig {
+            execution_script: Some("rustc -O $1".to_string()),
+            ..config.clone()
+        };
+        assert_ne!(
+            key("a", None),
+            cache_key(
+                "a",
+                "rust",
+                &other_script,
+                &Invocation::default(),
+                &[],
+                Sandbox::None,
+                &limits
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_the_files_of_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("data")).unwrap();
+        let file = dir.path().join("data").join("input.txt");
+        fs::write(&file, "1").unwrap();
+        let invocation = Invocation {
+            cwd: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let key = || {
+            cache_key(
+                "print(open('data/input.txt').read())",
+                "python",
+                &LanguageConfig::default(),
+                &invocation,
+                &[],
+                Sandbox::None,
+                &ResourceLimits::default(),
+            )
+            .unwrap()
+        };
+
+        let before = key();
+        assert_eq!(before, key());
+        File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1))
+            .unwrap();
+        assert_ne!(before, key());
+    }
+
+    #[test]
+    fn test_store_load_and_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = ExecutionOutput {
+            status: Some(0),
+            stdout: "hello\n".to_string(),
+            work_dir: Some("/tmp/tanglit/main-1".to_string()),
+            ..Default::default()
+        };
+        let block_file = Path::new("/tmp/tanglit/main-1/main.rs");
+
+        assert!(load_from(dir.path(), "key").is_none());
+        store_in(dir.path(), "key", &output, block_file).unwrap();
+        let entry = load_from(dir.path(), "key").unwrap();
+        assert_eq!(entry.output.stdout, "hello\n");
+        assert_eq!(entry.output.work_dir, None);
+        assert_eq!(entry.block_file, block_file);
+
+        // Killed executions aren't cached
+        let timed_out = ExecutionOutput {
+            timed_out: true,
+            ..output.clone()
+        };
+        store_in(dir.path(), "slow", &timed_out, block_file).unwrap();
+        assert!(load_from(dir.path(), "slow").is_none());
+
+        let recent = prune_dir(dir.path(), Some(Duration::from_secs(3600))).unwrap();
+        assert_eq!(recent.removed, 0);
+        let all = prune_dir(dir.path(), None).unwrap();
+        assert_eq!(all.removed, 1);
+        assert!(all.freed_bytes > 0);
+        assert!(load_from(dir.path(), "key").is_none());
+    }
+}
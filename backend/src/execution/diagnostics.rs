@@ -0,0 +1,230 @@
+use crate::doc::SourceMap;
+use log::warn;
+use regex::RegexBuilder;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    /// Parses the severity label printed by a tool, e.g. `fatal error` or `warning`.
+    /// Unknown labels are considered errors.
+    fn from_label(label: &str) -> Self {
+        let label = label.to_lowercase();
+        if label.contains("warning") {
+            Severity::Warning
+        } else if label.contains("note") || label.contains("help") {
+            Severity::Note
+        } else {
+            Severity::Error
+        }
+    }
+}
+
+/// An error or warning reported by a compiler or at runtime, parsed from the output of an execution.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// The markdown document if the diagnostic could be mapped back to it,
+    /// otherwise the file reported by the tool.
+    pub file: String,
+    /// 1-based line in `file`.
+    pub line: usize,
+    /// Column as reported by the tool.
+    pub column: Option<usize>,
+    pub message: String,
+    pub severity: Severity,
+}
+
+/// Parses the diagnostics found in `output` with the given regexes, in the order they appear.
+/// Each regex must have a `line` named group, and may have `file`, `column`, `severity` and
+/// `message` groups. Regexes are multi-line, so `^` and `$` match at line boundaries.
+/// Diagnostics in `source_file` (or without a file) on a line that comes from the document
+/// are rewritten to point to the markdown line, using `source_map`.
+pub fn parse_diagnostics(
+    output: &str,
+    patterns: &[String],
+    source_file: &Path,
+    source_map: &SourceMap,
+) -> Vec<Diagnostic> {
+    let mut found: Vec<(usize, Diagnostic)> = vec![];
+    for pattern in patterns {
+        let regex = match RegexBuilder::new(pattern).multi_line(true).build() {
+            Ok(regex) => regex,
+            Err(e) => {
+                warn!("Skipping invalid diagnostic regex '{}': {}", pattern, e);
+                continue;
+            }
+        };
+        for caps in regex.captures_iter(output) {
+            let Some(line) = caps.name("line").and_then(|m| m.as_str().parse().ok()) else {
+                continue;
+            };
+            let file = caps.name("file").map(|m| m.as_str().trim());
+            let diagnostic = Diagnostic {
+                file: file.unwrap_or_default().to_string(),
+                line,
+                column: caps.name("column").and_then(|m| m.as_str().parse().ok()),
+                message: caps
+                    .name("message")
+                    .map_or(caps[0].trim(), |m| m.as_str().trim())
+                    .to_string(),
+                severity: caps
+                    .name("severity")
+                    .map_or(Severity::Error, |m| Severity::from_label(m.as_str())),
+            };
+            let in_source_file = file.is_none_or(|f| is_same_file(f, source_file));
+            found.push((
+                caps.get(0).unwrap().start(),
+                map_to_source(diagnostic, in_source_file, source_file, source_map),
+            ));
+        }
+    }
+    found.sort_by_key(|(position, _)| *position);
+
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    for (_, diagnostic) in found {
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    }
+    diagnostics
+}
+
+fn map_to_source(
+    mut diagnostic: Diagnostic,
+    in_source_file: bool,
+    source_file: &Path,
+    source_map: &SourceMap,
+) -> Diagnostic {
+    if !in_source_file {
+        return diagnostic;
+    }
+    match source_map.source_line(diagnostic.line) {
+        Some(markdown_line) => {
+            diagnostic.file = source_map.file.clone();
+            diagnostic.line = markdown_line;
+        }
+        None => diagnostic.file = source_file.display().to_string(),
+    }
+    diagnostic
+}
+
+// Tools may report the source file relative to the directory they run from, in which case
+// it's compared with the end of its path. Other files of the execution, like headers or
+// objects named after it, never match.
+fn is_same_file(reported: &str, source_file: &Path) -> bool {
+    let reported = Path::new(reported);
+    if reported.is_absolute() {
+        return reported == source_file;
+    }
+    let reported: PathBuf = reported
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect();
+    !reported.as_os_str().is_empty() && source_file.ends_with(reported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GCC_REGEX: &str = r"^(?P<file>[^:\n]+):(?P<line>\d+):(?:(?P<column>\d+):)? (?P<severity>fatal error|error|warning|note): (?P<message>.*)$";
+
+    fn source_map() -> SourceMap {
+        SourceMap {
+            file: "doc.md".to_string(),
+            lines: vec![None, None, None, Some(12), Some(13), None],
+        }
+    }
+
+    #[test]
+    fn test_parse_diagnostics_maps_to_markdown() {
+        let stderr = "/tmp/tanglit/main.c: In function 'main':\n/tmp/tanglit/main.c:5:22: error: expected ';' before 'return'\n/tmp/tanglit/main.c:4:9: warning: unused variable 'y'\n";
+
+        let diagnostics = parse_diagnostics(
+            stderr,
+            &[GCC_REGEX.to_string()],
+            Path::new("/tmp/tanglit/main.c"),
+            &source_map(),
+        );
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic {
+                    file: "doc.md".to_string(),
+                    line: 13,
+                    column: Some(22),
+                    message: "expected ';' before 'return'".to_string(),
+                    severity: Severity::Error,
+                },
+                Diagnostic {
+                    file: "doc.md".to_string(),
+                    line: 12,
+                    column: Some(9),
+                    message: "unused variable 'y'".to_string(),
+                    severity: Severity::Warning,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_diagnostics_outside_document() {
+        let stderr = "/tmp/tanglit/main.c:6:1: error: expected declaration\n/usr/include/stdio.h:3:1: note: declared here\n";
+
+        let diagnostics = parse_diagnostics(
+            stderr,
+            &[GCC_REGEX.to_string()],
+            Path::new("/tmp/tanglit/main.c"),
+            &source_map(),
+        );
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].file, "/tmp/tanglit/main.c");
+        assert_eq!(diagnostics[0].line, 6);
+        assert_eq!(diagnostics[1].file, "/usr/include/stdio.h");
+        assert_eq!(diagnostics[1].severity, Severity::Note);
+    }
+
+    #[test]
+    fn test_is_same_file() {
+        let source_file = Path::new("/tmp/tanglit/main-1/main.c");
+        assert!(is_same_file("/tmp/tanglit/main-1/main.c", source_file));
+        assert!(is_same_file("main.c", source_file));
+        assert!(is_same_file("./main-1/main.c", source_file));
+        assert!(!is_same_file("main.h", source_file));
+        assert!(!is_same_file("main.o", source_file));
+        assert!(!is_same_file("src/main.c", source_file));
+        assert!(!is_same_file("/tmp/tanglit/main-2/main.c", source_file));
+    }
+
+    #[test]
+    fn test_parse_diagnostics_without_file_or_message() {
+        let stderr = "Traceback (most recent call last):\nline 5\n";
+
+        let diagnostics = parse_diagnostics(
+            stderr,
+            &[r"^line (?P<line>\d+)$".to_string(), "(invalid".to_string()],
+            Path::new("/tmp/tanglit/main.py"),
+            &source_map(),
+        );
+
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                file: "doc.md".to_string(),
+                line: 13,
+                column: None,
+                message: "line 5".to_string(),
+                severity: Severity::Error,
+            }]
+        );
+    }
+}
@@ -0,0 +1,559 @@
+use super::notebook::{executable_blocks, run_blocks};
+use super::{BlockRun, BlockRunResult, ExecuteAllOptions, ExecutionOutput, NotebookEvent};
+use crate::doc::{RecordedOutput, TanglitDoc};
+use crate::errors::ExecutionError;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// How outputs are normalized before being compared with the recorded ones.
+#[derive(Debug, Clone, Default)]
+pub struct Normalization {
+    /// Ignores whitespace at the end of lines and empty lines around the output.
+    pub trim_whitespace: bool,
+    /// Removes the text matching any of these, like timestamps or addresses.
+    pub ignore: Vec<Regex>,
+}
+
+impl Normalization {
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for regex in &self.ignore {
+            text = regex.replace_all(&text, "").into_owned();
+        }
+        if self.trim_whitespace {
+            text = text
+                .lines()
+                .map(str::trim_end)
+                .collect::<Vec<&str>>()
+                .join("\n")
+                .trim_matches('\n')
+                .to_string();
+        }
+        text
+    }
+}
+
+/// Which blocks of a document `run_tests` tests, and how.
+#[derive(Debug, Clone, Default)]
+pub struct TestOptions {
+    /// Blocks to run and settings of their executions, as for `execute_all`.
+    /// Only the blocks with an output block below them are tested.
+    pub run: ExecuteAllOptions,
+    pub normalization: Normalization,
+}
+
+/// Part of the output compared with the recorded one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compared {
+    Stdout,
+    Stderr,
+    ExitCode,
+}
+
+impl fmt::Display for Compared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compared::Stdout => write!(f, "stdout"),
+            Compared::Stderr => write!(f, "stderr"),
+            Compared::ExitCode => write!(f, "exit code"),
+        }
+    }
+}
+
+/// A part of the output that doesn't match the recorded one, both normalized.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mismatch {
+    pub compared: Compared,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+pub enum TestResult {
+    Passed,
+    Failed {
+        mismatches: Vec<Mismatch>,
+    },
+    /// The block couldn't run, like when a block it references doesn't exist.
+    Error {
+        error: String,
+    },
+    /// The block didn't run because `dependency`, a block it uses, didn't succeed.
+    Skipped {
+        dependency: String,
+    },
+}
+
+/// A block compared with the output recorded below it.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockTest {
+    pub tag: String,
+    /// Line of the block in the markdown file, 1-based.
+    pub line: usize,
+    /// Line of its output block in the markdown file, 1-based.
+    pub output_line: usize,
+    /// Time the block ran for, if it ran.
+    pub duration_ms: Option<u64>,
+    #[serde(flatten)]
+    pub result: TestResult,
+}
+
+impl BlockTest {
+    pub fn passed(&self) -> bool {
+        matches!(self.result, TestResult::Passed)
+    }
+}
+
+/// Number of tests with each result.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TestSummary {
+    pub passed: usize,
+    pub failed: usize,
+    pub errors: usize,
+    pub skipped: usize,
+}
+
+impl TestSummary {
+    pub fn of(tests: &[BlockTest]) -> TestSummary {
+        let mut summary = TestSummary::default();
+        for test in tests {
+            match test.result {
+                TestResult::Passed => summary.passed += 1,
+                TestResult::Failed { .. } => summary.failed += 1,
+                TestResult::Error { .. } => summary.errors += 1,
+                TestResult::Skipped { .. } => summary.skipped += 1,
+            }
+        }
+        summary
+    }
+
+    pub fn total(&self) -> usize {
+        self.passed + self.failed + self.errors + self.skipped
+    }
+
+    /// Whether every test passed.
+    pub fn is_success(&self) -> bool {
+        self.passed == self.total()
+    }
+}
+
+/// Runs the document as `execute_all` does and compares the stdout, stderr and exit code of
+/// every block having an `output` block right below it with the ones recorded there, including
+/// the blocks other blocks use or reference.
+/// Other blocks only run when they belong to a session, since the tested blocks of that
+/// session may use what they define.
+pub fn run_tests(
+    doc: &TanglitDoc,
+    options: &TestOptions,
+) -> Result<Vec<BlockTest>, ExecutionError> {
+    run_tests_streaming(doc, options, &mut |_| {})
+}
+
+/// Same as `run_tests`, but calls `on_test` as each block is tested.
+pub fn run_tests_streaming(
+    doc: &TanglitDoc,
+    options: &TestOptions,
+    on_test: &mut dyn FnMut(&BlockTest),
+) -> Result<Vec<BlockTest>, ExecutionError> {
+    let blocks = doc.get_code_blocks()?;
+    let mut recorded: HashMap<String, (usize, RecordedOutput)> = HashMap::new();
+    let mut executable = Vec::new();
+    for block in executable_blocks(&blocks, &options.run) {
+        if let Some(output) = doc.recorded_output_of(block) {
+            recorded.insert(block.tag.clone(), (block.start_line, output));
+            executable.push(block);
+        } else if block.session.is_some() {
+            executable.push(block);
+        }
+    }
+
+    let mut tests = Vec::new();
+    run_blocks(
+        doc,
+        &blocks,
+        executable,
+        &options.run.execution,
+        options.run.jobs,
+        &mut |event| {
+            let NotebookEvent::Finished(run) = event else {
+                return;
+            };
+            if let Some((line, expected)) = recorded.get(&run.tag) {
+                let test = check(run, *line, expected, &options.normalization);
+                on_test(&test);
+                tests.push(test);
+            }
+        },
+    );
+    Ok(tests)
+}
+
+fn check(
+    run: &BlockRun,
+    line: usize,
+    expected: &RecordedOutput,
+    normalization: &Normalization,
+) -> BlockTest {
+    let result = match &run.result {
+        BlockRunResult::Executed { output } => {
+            let mismatches = compare(expected, output, normalization);
+            if mismatches.is_empty() {
+                TestResult::Passed
+            } else {
+                TestResult::Failed { mismatches }
+            }
+        }
+        BlockRunResult::Failed { error } => TestResult::Error {
+            error: error.clone(),
+        },
+        BlockRunResult::Skipped { dependency } => TestResult::Skipped {
+            dependency: dependency.clone(),
+        },
+    };
+    BlockTest {
+        tag: run.tag.clone(),
+        line,
+        output_line: expected.start_line,
+        duration_ms: run.output().map(|output| output.usage.wall_time_ms),
+        result,
+    }
+}
+
+/// Compares `output` with the recorded one, after normalizing stdout and stderr.
+/// The stderr and exit code are only compared when they were recorded.
+pub fn compare(
+    expected: &RecordedOutput,
+    output: &ExecutionOutput,
+    normalization: &Normalization,
+) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    let mut compare_text = |compared, expected: &str, actual: &str| {
+        let expected = normalization.apply(expected);
+        let actual = normalization.apply(actual);
+        if expected != actual {
+            mismatches.push(Mismatch {
+                compared,
+                expected,
+                actual,
+            });
+        }
+    };
+    compare_text(Compared::Stdout, &expected.stdout, &output.stdout);
+    if let Some(stderr) = &expected.stderr {
+        compare_text(Compared::Stderr, stderr, &output.stderr);
+    }
+    let exit_code = output.exit_code_label();
+    if let Some(expected_exit_code) = expected.exit_code.as_ref().filter(|e| **e != exit_code) {
+        mismatches.push(Mismatch {
+            compared: Compared::ExitCode,
+            expected: expected_exit_code.clone(),
+            actual: exit_code,
+        });
+    }
+    mismatches
+}
+
+/// Returns a line diff from `expected` to `actual`, with removed lines starting with `-`,
+/// added ones with `+` and the others with a space.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    // Length of the longest common subsequence of expected[i..] and actual[j..]
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(format!(" {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            lines.push(format!("-{}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+{}", actual[j]));
+            j += 1;
+        }
+    }
+    lines.join("\n")
+}
+
+/// Returns a JSON report of the tests of `document`, with their summary.
+pub fn json_report(document: &str, tests: &[BlockTest]) -> Result<String, ExecutionError> {
+    #[derive(Serialize)]
+    struct Report<'a> {
+        document: &'a str,
+        summary: TestSummary,
+        tests: &'a [BlockTest],
+    }
+
+    serde_json::to_string_pretty(&Report {
+        document,
+        summary: TestSummary::of(tests),
+        tests,
+    })
+    .map_err(|e| ExecutionError::InternalError(e.to_string()))
+}
+
+/// Returns a JUnit XML report of the tests of `document`, as read by CI servers, with the
+/// document as the test suite and every block as a test case.
+pub fn junit_report(document: &str, tests: &[BlockTest]) -> String {
+    let summary = TestSummary::of(tests);
+    let seconds = |ms: u64| format!("{:.3}", ms as f64 / 1000.0);
+    let total_ms: u64 = tests.iter().filter_map(|test| test.duration_ms).sum();
+    let counts = format!(
+        "tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{}\"",
+        summary.total(),
+        summary.failed,
+        summary.errors,
+        summary.skipped,
+        seconds(total_ms)
+    );
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuites {}>\n", counts));
+    xml.push_str(&format!(
+        "  <testsuite name=\"{}\" {}>\n",
+        escape_xml(document),
+        counts
+    ));
+    for test in tests {
+        xml.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"{}\" file=\"{}\" line=\"{}\" time=\"{}\"",
+            escape_xml(&test.tag),
+            escape_xml(document),
+            escape_xml(document),
+            test.line,
+            seconds(test.duration_ms.unwrap_or(0))
+        ));
+        match &test.result {
+            TestResult::Passed => xml.push_str("/>\n"),
+            TestResult::Failed { mismatches } => {
+                xml.push_str(">\n");
+                for mismatch in mismatches {
+                    xml.push_str(&format!(
+                        "      <failure message=\"{} differs from the output recorded at line {}\">{}</failure>\n",
+                        mismatch.compared,
+                        test.output_line,
+                        escape_xml(&diff(&mismatch.expected, &mismatch.actual))
+                    ));
+                }
+                xml.push_str("    </testcase>\n");
+            }
+            TestResult::Error { error } => xml.push_str(&format!(
+                ">\n      <error message=\"{}\"/>\n    </testcase>\n",
+                escape_xml(error)
+            )),
+            TestResult::Skipped { dependency } => xml.push_str(&format!(
+                ">\n      <skipped message=\"{} didn't succeed\"/>\n    </testcase>\n",
+                escape_xml(dependency)
+            )),
+        }
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(stdout: &str, stderr: &str, exit_code: &str) -> RecordedOutput {
+        RecordedOutput {
+            stdout: stdout.to_string(),
+            stderr: Some(stderr.to_string()),
+            exit_code: Some(exit_code.to_string()),
+            start_line: 5,
+        }
+    }
+
+    fn output(stdout: &str, stderr: &str, status: i32) -> ExecutionOutput {
+        ExecutionOutput {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            status: Some(status),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_normalization() {
+        let normalization = Normalization {
+            trim_whitespace: true,
+            ignore: vec![Regex::new(r"0x[0-9a-f]+").unwrap()],
+        };
+        assert_eq!(
+            normalization.apply("\nobject at 0x7f3a  \n  indented\t\n\n"),
+            "object at\n  indented"
+        );
+        assert_eq!(Normalization::default().apply(" a \n"), " a \n");
+    }
+
+    #[test]
+    fn test_compare() {
+        let expected = recorded("hello\n", "", "0");
+        let normalization = Normalization::default();
+        assert!(compare(&expected, &output("hello\n", "", 0), &normalization).is_empty());
+
+        let mismatches = compare(&expected, &output("bye\n", "oops", 1), &normalization);
+        let compared: Vec<Compared> = mismatches.iter().map(|m| m.compared).collect();
+        assert_eq!(
+            compared,
+            vec![Compared::Stdout, Compared::Stderr, Compared::ExitCode]
+        );
+        assert_eq!(mismatches[2].actual, "1");
+
+        // Only the stdout is compared when it is all that was recorded
+        let stdout_only = RecordedOutput {
+            stderr: None,
+            exit_code: None,
+            ..expected
+        };
+        let trimmed = Normalization {
+            trim_whitespace: true,
+            ..Default::default()
+        };
+        assert!(compare(&stdout_only, &output("hello", "oops", 1), &trimmed).is_empty());
+    }
+
+    #[test]
+    fn test_run_tests_with_output_written_by_hand() {
+        let doc = TanglitDoc::new_from_string(
+            r#"```python monty
+for i in range(2):
+    print("SPAM")
+```
+```output
+SPAM
+SPAM
+```
+"#,
+        )
+        .unwrap();
+        let options = TestOptions {
+            run: ExecuteAllOptions {
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let tests = run_tests(&doc, &options).unwrap();
+        assert_eq!(tests.len(), 1);
+        assert!(tests[0].passed(), "{:?}", tests[0]);
+    }
+
+    #[test]
+    fn test_run_tests_of_blocks_used_by_others() {
+        let doc = TanglitDoc::new_from_string(
+            r#"```python helper
+x = 1
+print("helper")
+```
+```output
+helper
+```
+
+```python main
+@[helper]
+print(x)
+```
+```output
+helper
+1
+```
+
+```python load
+data = [1, 2]
+```
+```output
+```
+
+```python report use=[load]
+print(sum(data))
+```
+```output
+3
+```
+"#,
+        )
+        .unwrap();
+        let options = TestOptions {
+            run: ExecuteAllOptions {
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let tests = run_tests(&doc, &options).unwrap();
+        let tags: Vec<&str> = tests.iter().map(|test| test.tag.as_str()).collect();
+        assert_eq!(tags, vec!["helper", "main", "load", "report"]);
+        assert!(tests.iter().all(BlockTest::passed), "{:?}", tests);
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("a\nb\nc", "a\nx\nc\nd"), " a\n-b\n+x\n c\n+d");
+        assert_eq!(diff("", "a"), "+a");
+    }
+
+    #[test]
+    fn test_junit_report() {
+        let tests = vec![
+            BlockTest {
+                tag: "ok".to_string(),
+                line: 1,
+                output_line: 5,
+                duration_ms: Some(1500),
+                result: TestResult::Passed,
+            },
+            BlockTest {
+                tag: "wrong".to_string(),
+                line: 10,
+                output_line: 14,
+                duration_ms: Some(20),
+                result: TestResult::Failed {
+                    mismatches: vec![Mismatch {
+                        compared: Compared::Stdout,
+                        expected: "<a>".to_string(),
+                        actual: "b".to_string(),
+                    }],
+                },
+            },
+        ];
+
+        let xml = junit_report("doc.md", &tests);
+        assert!(xml.contains(
+            "<testsuite name=\"doc.md\" tests=\"2\" failures=\"1\" errors=\"0\" skipped=\"0\" time=\"1.520\">"
+        ));
+        assert!(xml.contains(
+            "<testcase name=\"ok\" classname=\"doc.md\" file=\"doc.md\" line=\"1\" time=\"1.500\"/>"
+        ));
+        assert!(xml.contains(
+            "<failure message=\"stdout differs from the output recorded at line 14\">-&lt;a&gt;\n+b</failure>"
+        ));
+    }
+}
@@ -0,0 +1,648 @@
+use super::{ExecutionOptions, ExecutionOutput, OutputChunk, execute_streaming};
+use crate::configuration::language_config::LanguageConfig;
+use crate::doc::{
+    CodeBlock, CodeBlocks, FILE_LANGUAGE, INPUT_LANGUAGE, OUTPUT_LANGUAGE, TanglitDoc,
+};
+use crate::errors::ExecutionError;
+use indexmap::IndexSet;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, PoisonError, mpsc};
+use std::thread;
+
+/// Which blocks of a document `execute_all` runs, and how.
+#[derive(Debug, Clone, Default)]
+pub struct ExecuteAllOptions {
+    /// Only runs the blocks whose tag matches.
+    pub tags: Option<Regex>,
+    /// Only runs the blocks written in this language.
+    pub language: Option<String>,
+    /// Settings of every execution.
+    pub execution: ExecutionOptions,
+    /// Number of blocks run at the same time. With 0 or 1, they run one after the other.
+    pub jobs: usize,
+}
+
+/// What happened to a block in a run of the whole document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+pub enum BlockRunResult {
+    /// The block ran, whether it succeeded or not.
+    Executed { output: ExecutionOutput },
+    /// The block couldn't run, like when a block it references doesn't exist.
+    Failed { error: String },
+    /// The block didn't run because `dependency`, a block it uses, didn't succeed.
+    Skipped { dependency: String },
+}
+
+/// A block run by `execute_all`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockRun {
+    pub tag: String,
+    #[serde(flatten)]
+    pub result: BlockRunResult,
+}
+
+impl BlockRun {
+    /// Whether the block ran to completion with exit status 0.
+    pub fn succeeded(&self) -> bool {
+        match &self.result {
+            BlockRunResult::Executed { output } => output.status == Some(0),
+            _ => false,
+        }
+    }
+
+    pub fn output(&self) -> Option<&ExecutionOutput> {
+        match &self.result {
+            BlockRunResult::Executed { output } => Some(output),
+            _ => None,
+        }
+    }
+}
+
+/// Progress of `execute_all_streaming`.
+pub enum NotebookEvent<'a> {
+    /// The block is about to run.
+    Started(&'a CodeBlock),
+    /// The block printed a chunk of output.
+    Output(&'a CodeBlock, OutputChunk),
+    /// The block ran, failed or was skipped.
+    Finished(&'a BlockRun),
+}
+
+/// Runs every executable block of the document, like "Run All" in a notebook, and returns
+/// what happened to each of them in document order.
+/// Blocks run in document order, except that the blocks they use with `use=[...]` or
+/// reference with macros run before them. A block is skipped if one of those didn't succeed.
+/// Blocks whose language can't be executed, files of multi-file units and input, output
+/// and data file blocks don't run on their own.
+/// With `ExecuteAllOptions::jobs`, blocks that don't depend on each other run at the same
+/// time, except for the blocks of a session, which run one after the other.
+pub fn execute_all(
+    doc: &TanglitDoc,
+    options: &ExecuteAllOptions,
+) -> Result<Vec<BlockRun>, ExecutionError> {
+    execute_all_streaming(doc, options, &mut |_| {})
+}
+
+/// Same as `execute_all`, but calls `on_event` as each block starts, prints and finishes.
+/// When blocks run at the same time, their events are interleaved.
+pub fn execute_all_streaming(
+    doc: &TanglitDoc,
+    options: &ExecuteAllOptions,
+    on_event: &mut dyn FnMut(NotebookEvent),
+) -> Result<Vec<BlockRun>, ExecutionError> {
+    let blocks = doc.get_code_blocks()?;
+    let executable = executable_blocks(&blocks, options);
+    Ok(run_blocks(
+        doc,
+        &blocks,
+        executable,
+        &options.execution,
+        options.jobs,
+        on_event,
+    ))
+}
+
+/// Runs `executable`, blocks of `doc` in document order, the way `execute_all_streaming` does,
+/// up to `jobs` of them at the same time.
+pub(super) fn run_blocks(
+    doc: &TanglitDoc,
+    blocks: &CodeBlocks,
+    executable: Vec<&CodeBlock>,
+    execution: &ExecutionOptions,
+    jobs: usize,
+    on_event: &mut dyn FnMut(NotebookEvent),
+) -> Vec<BlockRun> {
+    let order = run_order(blocks, executable);
+    let mut runs = if jobs > 1 {
+        run_parallel(doc, &order, execution, jobs, on_event)
+    } else {
+        run_sequential(doc, &order, execution, on_event)
+    };
+
+    let lines: HashMap<&str, usize> = order
+        .iter()
+        .map(|(block, _)| (block.tag.as_str(), block.start_line))
+        .collect();
+    runs.sort_by_key(|run| lines.get(run.tag.as_str()).copied());
+    runs
+}
+
+fn run_sequential(
+    doc: &TanglitDoc,
+    order: &[(&CodeBlock, IndexSet<String>)],
+    execution: &ExecutionOptions,
+    on_event: &mut dyn FnMut(NotebookEvent),
+) -> Vec<BlockRun> {
+    let mut runs: Vec<BlockRun> = Vec::new();
+    let mut unsuccessful: HashSet<String> = HashSet::new();
+
+    for (block, dependencies) in order {
+        let result = match dependencies.iter().find(|tag| unsuccessful.contains(*tag)) {
+            Some(dependency) => BlockRunResult::Skipped {
+                dependency: dependency.clone(),
+            },
+            None => {
+                on_event(NotebookEvent::Started(block));
+                run_block(doc, block, execution, &mut |chunk| {
+                    on_event(NotebookEvent::Output(block, chunk))
+                })
+            }
+        };
+        let run = BlockRun {
+            tag: block.tag.clone(),
+            result,
+        };
+        if !run.succeeded() {
+            unsuccessful.insert(run.tag.clone());
+        }
+        on_event(NotebookEvent::Finished(&run));
+        runs.push(run);
+    }
+    runs
+}
+
+// What a worker of `run_parallel` reports about the block at some position of the run order
+enum WorkerEvent {
+    Started(usize),
+    Output(usize, OutputChunk),
+    Finished(usize, BlockRunResult),
+}
+
+/// Runs the blocks of `order` on `jobs` threads. A block starts once the blocks it waits for
+/// finished, see `waits_for`, so blocks are skipped exactly as when they run one after the other.
+/// The calling thread schedules the blocks and passes the events of the workers on.
+fn run_parallel(
+    doc: &TanglitDoc,
+    order: &[(&CodeBlock, IndexSet<String>)],
+    execution: &ExecutionOptions,
+    jobs: usize,
+    on_event: &mut dyn FnMut(NotebookEvent),
+) -> Vec<BlockRun> {
+    let waits_for = waits_for(order);
+    let (job_sender, job_receiver) = mpsc::channel::<usize>();
+    let job_receiver = Mutex::new(job_receiver);
+    let (event_sender, event_receiver) = mpsc::channel::<WorkerEvent>();
+    let mut runs: Vec<Option<BlockRun>> = vec![None; order.len()];
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(order.len()) {
+            let event_sender = event_sender.clone();
+            let job_receiver = &job_receiver;
+            scope.spawn(move || {
+                loop {
+                    // The lock is released as soon as a job is taken
+                    let job = job_receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    let Ok(position) = job else {
+                        break;
+                    };
+                    let _ = event_sender.send(WorkerEvent::Started(position));
+                    let result = run_block(doc, order[position].0, execution, &mut |chunk| {
+                        let _ = event_sender.send(WorkerEvent::Output(position, chunk));
+                    });
+                    let _ = event_sender.send(WorkerEvent::Finished(position, result));
+                }
+            });
+        }
+        drop(event_sender);
+
+        let mut scheduled = vec![false; order.len()];
+        let mut running = 0;
+        loop {
+            // Schedules every block whose waits are over, until none is left to skip
+            let mut skipped_any = true;
+            while skipped_any {
+                skipped_any = false;
+                for position in 0..order.len() {
+                    if scheduled[position]
+                        || !waits_for[position]
+                            .iter()
+                            .all(|&other| runs[other].is_some())
+                    {
+                        continue;
+                    }
+                    scheduled[position] = true;
+                    let (block, dependencies) = &order[position];
+                    let failed_dependency = waits_for[position]
+                        .iter()
+                        .filter_map(|&other| runs[other].as_ref())
+                        .filter(|run| !run.succeeded() && dependencies.contains(&run.tag))
+                        .min_by_key(|run| dependencies.get_index_of(&run.tag));
+                    match failed_dependency {
+                        Some(dependency) => {
+                            let run = BlockRun {
+                                tag: block.tag.clone(),
+                                result: BlockRunResult::Skipped {
+                                    dependency: dependency.tag.clone(),
+                                },
+                            };
+                            on_event(NotebookEvent::Finished(&run));
+                            runs[position] = Some(run);
+                            skipped_any = true;
+                        }
+                        None => {
+                            job_sender
+                                .send(position)
+                                .expect("workers outlive the scheduling");
+                            running += 1;
+                        }
+                    }
+                }
+            }
+            if running == 0 {
+                break;
+            }
+
+            match event_receiver.recv() {
+                Ok(WorkerEvent::Started(position)) => {
+                    on_event(NotebookEvent::Started(order[position].0))
+                }
+                Ok(WorkerEvent::Output(position, chunk)) => {
+                    on_event(NotebookEvent::Output(order[position].0, chunk))
+                }
+                Ok(WorkerEvent::Finished(position, result)) => {
+                    running -= 1;
+                    let run = BlockRun {
+                        tag: order[position].0.tag.clone(),
+                        result,
+                    };
+                    on_event(NotebookEvent::Finished(&run));
+                    runs[position] = Some(run);
+                }
+                // Every worker panicked
+                Err(_) => break,
+            }
+        }
+        // Lets the workers stop
+        drop(job_sender);
+    });
+    runs.into_iter().flatten().collect()
+}
+
+/// Returns the positions in `order` of the blocks each block of `order` waits for in
+/// `run_parallel`: its dependencies earlier in `order` and the previous block of its session.
+fn waits_for(order: &[(&CodeBlock, IndexSet<String>)]) -> Vec<Vec<usize>> {
+    let positions: HashMap<&str, usize> = order
+        .iter()
+        .enumerate()
+        .map(|(position, (block, _))| (block.tag.as_str(), position))
+        .collect();
+    order
+        .iter()
+        .enumerate()
+        .map(|(position, (block, dependencies))| {
+            let mut waits_for: Vec<usize> = dependencies
+                .iter()
+                .filter_map(|tag| positions.get(tag.as_str()).copied())
+                .filter(|dependency| *dependency < position)
+                .collect();
+            // The blocks of a session share an interpreter, so they keep their order
+            if block.session.is_some() {
+                let previous = order[..position].iter().rposition(|(other, _)| {
+                    other.session == block.session && other.language == block.language
+                });
+                waits_for.extend(previous);
+            }
+            waits_for
+        })
+        .collect()
+}
+
+fn run_block(
+    doc: &TanglitDoc,
+    block: &CodeBlock,
+    execution: &ExecutionOptions,
+    on_output: &mut dyn FnMut(OutputChunk),
+) -> BlockRunResult {
+    match execute_streaming(doc, &block.tag, execution, on_output) {
+        Ok(output) => BlockRunResult::Executed { output },
+        Err(e) => BlockRunResult::Failed {
+            error: e.to_string(),
+        },
+    }
+}
+
+/// Returns the blocks `execute_all` runs with `options`, in document order.
+pub(super) fn executable_blocks<'a>(
+    blocks: &'a CodeBlocks,
+    options: &ExecuteAllOptions,
+) -> Vec<&'a CodeBlock> {
+    let mut executable_languages: HashMap<&str, bool> = HashMap::new();
+
+    let mut executable: Vec<&CodeBlock> = blocks
+        .blocks
+        .values()
+        .filter(|block| {
+            options
+                .tags
+                .as_ref()
+                .is_none_or(|tags| tags.is_match(&block.tag))
+        })
+        .filter(|block| {
+            let Some(lang) = block.language.as_deref() else {
+                return false;
+            };
+            if [INPUT_LANGUAGE, OUTPUT_LANGUAGE, FILE_LANGUAGE].contains(&lang) {
+                return false;
+            }
+            // Files of multi-file units only run along with the blocks using them
+            if block.file.is_some() {
+                return false;
+            }
+            if options
+                .language
+                .as_deref()
+                .is_some_and(|language| language != lang)
+            {
+                return false;
+            }
+            *executable_languages.entry(lang).or_insert_with(|| {
+                LanguageConfig::load_for_lang(lang).is_ok_and(|config| {
+                    config.execution_script.is_some() || config.session_command.is_some()
+                })
+            })
+        })
+        .collect();
+    executable.sort_by_key(|block| block.start_line);
+    executable
+}
+
+/// Orders `executable`, which is in document order, so every block comes after the blocks
+/// it depends on, and returns each of them along with its dependencies.
+/// Blocks depending on each other keep their document order.
+fn run_order<'a>(
+    blocks: &CodeBlocks,
+    executable: Vec<&'a CodeBlock>,
+) -> Vec<(&'a CodeBlock, IndexSet<String>)> {
+    let mut pending: Vec<(&CodeBlock, IndexSet<String>)> = executable
+        .into_iter()
+        .map(|block| (block, dependencies(blocks, &block.tag)))
+        .collect();
+    let pending_tags: HashSet<String> =
+        pending.iter().map(|(block, _)| block.tag.clone()).collect();
+
+    let mut ordered = Vec::with_capacity(pending.len());
+    let mut done: HashSet<String> = HashSet::new();
+    while !pending.is_empty() {
+        // The first block whose dependencies already ran, or the first one if they depend
+        // on each other
+        let next = pending
+            .iter()
+            .position(|(block, dependencies)| {
+                dependencies.iter().all(|tag| {
+                    tag == &block.tag || done.contains(tag) || !pending_tags.contains(tag)
+                })
+            })
+            .unwrap_or(0);
+        let (block, dependencies) = pending.remove(next);
+        done.insert(block.tag.clone());
+        ordered.push((block, dependencies));
+    }
+    ordered
+}
+
+/// Returns the blocks `tag` uses with `use=[...]` or references with macros, directly
+/// or through other blocks.
+fn dependencies(blocks: &CodeBlocks, tag: &str) -> IndexSet<String> {
+    let mut found: IndexSet<String> = IndexSet::new();
+    let mut to_visit = vec![tag.to_string()];
+    while let Some(current) = to_visit.pop() {
+        let imports = blocks
+            .get_block(&current)
+            .map(|block| block.imports.clone())
+            .unwrap_or_default();
+        for dependency in imports.into_iter().chain(blocks.get_references(&current)) {
+            if dependency != tag && found.insert(dependency.clone()) {
+                to_visit.push(dependency);
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(tag: &str, code: &str, imports: &[&str], start_line: usize) -> CodeBlock {
+        CodeBlock::new(
+            Some("python".to_string()),
+            code.to_string(),
+            tag.to_string(),
+            imports.iter().map(|import| import.to_string()).collect(),
+            None,
+            start_line,
+            start_line + 2,
+        )
+    }
+
+    #[test]
+    fn test_run_order() {
+        let blocks = CodeBlocks::from_pieces(vec![
+            block("report", "print(total)", &["totals"], 1),
+            block("load", "data = [1, 2]", &[], 5),
+            block("totals", "@[sum]", &["load"], 9),
+            block("sum", "total = sum(data)", &[], 13),
+            block("other", "print('other')", &[], 17),
+        ]);
+        let executable: Vec<&CodeBlock> = ["report", "load", "totals", "other"]
+            .iter()
+            .map(|tag| blocks.get_block(tag).unwrap())
+            .collect();
+
+        let order = run_order(&blocks, executable);
+        let tags: Vec<&str> = order.iter().map(|(block, _)| block.tag.as_str()).collect();
+        assert_eq!(tags, vec!["load", "totals", "report", "other"]);
+
+        let (_, report_dependencies) = &order[2];
+        assert!(report_dependencies.contains("load"));
+        assert!(report_dependencies.contains("sum"));
+    }
+
+    #[test]
+    fn test_executable_blocks() {
+        let doc = TanglitDoc::new_from_string(
+            r#"```python main
+@[helper]
+print(x)
+```
+
+```python helper
+x = 1
+```
+
+```python report use=[main]
+print("report")
+```
+
+```input
+1 2
+```
+
+```file name=data.csv
+a,b
+```
+
+```c point file=point.h
+struct point { int x; };
+```
+
+```text notes
+Not code
+```
+
+```python other
+print(2)
+```
+"#,
+        )
+        .unwrap();
+        let blocks = doc.get_code_blocks().unwrap();
+
+        let tags = |options: &ExecuteAllOptions| -> Vec<String> {
+            executable_blocks(&blocks, options)
+                .iter()
+                .map(|block| block.tag.clone())
+                .collect()
+        };
+        // Blocks referenced or used by others still run on their own
+        assert_eq!(
+            tags(&ExecuteAllOptions::default()),
+            vec!["main", "helper", "report", "other"]
+        );
+        let filtered = ExecuteAllOptions {
+            tags: Some(Regex::new("^oth").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(tags(&filtered), vec!["other"]);
+        let other_language = ExecuteAllOptions {
+            language: Some("rust".to_string()),
+            ..Default::default()
+        };
+        assert!(tags(&other_language).is_empty());
+
+        // The blocks they depend on run first
+        let order: Vec<String> =
+            run_order(&blocks, executable_blocks(&blocks, &Default::default()))
+                .into_iter()
+                .map(|(block, _)| block.tag.clone())
+                .collect();
+        assert_eq!(order, vec!["helper", "main", "report", "other"]);
+    }
+
+    #[test]
+    fn test_waits_for() {
+        let doc = TanglitDoc::new_from_string(
+            r#"```python load
+data = [1, 2]
+```
+
+```python first session=main
+x = 1
+```
+
+```python other
+print("other")
+```
+
+```python report use=[load]
+print(data)
+```
+
+```python second session=main
+print(x)
+```
+"#,
+        )
+        .unwrap();
+        let blocks = doc.get_code_blocks().unwrap();
+
+        let order = run_order(&blocks, executable_blocks(&blocks, &Default::default()));
+        assert_eq!(
+            waits_for(&order),
+            vec![vec![], vec![], vec![], vec![0], vec![1]]
+        );
+    }
+
+    #[test]
+    fn test_run_blocks_in_parallel() {
+        let doc = TanglitDoc::new_from_string(
+            r#"```python report use=[load]
+print("report")
+```
+
+```python other
+print("other")
+```
+
+```python load
+import time
+time.sleep(0.5)
+print("load")
+```
+
+```python total
+@[broken]
+print("total")
+```
+
+```python broken
+raise ValueError("broken")
+```
+"#,
+        )
+        .unwrap();
+        let blocks = doc.get_code_blocks().unwrap();
+        let executable = executable_blocks(&blocks, &Default::default());
+
+        let mut events: Vec<String> = Vec::new();
+        let runs = run_blocks(
+            &doc,
+            &blocks,
+            executable,
+            &ExecutionOptions::default(),
+            4,
+            &mut |event| match event {
+                NotebookEvent::Started(block) => events.push(format!("start {}", block.tag)),
+                NotebookEvent::Finished(run) => events.push(format!("finish {}", run.tag)),
+                NotebookEvent::Output(..) => {}
+            },
+        );
+
+        // The results are in document order, whichever block finished first
+        let tags: Vec<&str> = runs.iter().map(|run| run.tag.as_str()).collect();
+        assert_eq!(tags, vec!["report", "other", "load", "total", "broken"]);
+        assert!(runs[..3].iter().all(BlockRun::succeeded), "{:?}", runs);
+        // The block using `load` only starts once it finished
+        let position = |event: &str| events.iter().position(|e| e == event).unwrap();
+        assert!(position("finish load") < position("start report"));
+        // The block referencing `broken` is skipped once it failed
+        assert!(matches!(
+            &runs[3].result,
+            BlockRunResult::Skipped { dependency } if dependency == "broken"
+        ));
+        assert!(!events.contains(&"start total".to_string()));
+        assert!(!runs[4].succeeded());
+    }
+
+    #[test]
+    fn test_run_order_with_cycle() {
+        let blocks =
+            CodeBlocks::from_pieces(vec![block("a", "", &["b"], 1), block("b", "", &["a"], 5)]);
+        let executable = vec![
+            blocks.get_block("a").unwrap(),
+            blocks.get_block("b").unwrap(),
+        ];
+
+        let tags: Vec<String> = run_order(&blocks, executable)
+            .into_iter()
+            .map(|(block, _)| block.tag.clone())
+            .collect();
+        assert_eq!(tags, vec!["a", "b"]);
+    }
+}
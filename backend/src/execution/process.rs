@@ -0,0 +1,538 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How often a running process is checked for completion
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Most bytes read from an output pipe at once
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// What a process printed, and how it ended.
+pub struct ProcessOutput {
+    /// `None` if the process was killed for exceeding its time or output limit.
+    pub status: Option<ExitStatus>,
+    /// Signal that ended the process, including the kill after exceeding a limit.
+    pub signal: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub timed_out: bool,
+    /// Whether the output was cut at `ResourceLimits::output_bytes`.
+    pub output_truncated: bool,
+    pub usage: ResourceUsage,
+}
+
+/// The output stream a chunk of output was printed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Limits on the resources of a process. Those left as `None` aren't limited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Time the process may run for before it's killed.
+    pub wall_time: Option<Duration>,
+    /// CPU time of each process, rounded up to whole seconds.
+    pub cpu_time: Option<Duration>,
+    /// Size of the data segment (the heap) of each process.
+    pub memory_bytes: Option<u64>,
+    /// Processes the user may have at once.
+    pub processes: Option<u64>,
+    /// Size of the files the processes write.
+    pub file_size_bytes: Option<u64>,
+    /// Bytes of stdout and stderr together, beyond which the output is cut and the process killed.
+    pub output_bytes: Option<u64>,
+}
+
+/// Resources used by a process, counting the processes it waited for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub wall_time_ms: u64,
+    /// CPU time spent in user and kernel mode, when the platform reports it.
+    pub cpu_time_ms: Option<u64>,
+    /// Largest resident set size, when the platform reports it.
+    pub peak_memory_bytes: Option<u64>,
+}
+
+/// Runs `command` capturing its output, and kills it if it's still running after
+/// `limits.wall_time` or prints more than `limits.output_bytes`. The rest of the limits
+/// are set as rlimits of the command on unix.
+/// The command runs in its own process group, and the whole group is killed once it
+/// finishes or is killed, so processes it spawned don't outlive it.
+/// The output printed before a timeout is kept.
+/// `stdin` is written to the standard input of the command, which is empty when it's `None`.
+/// `on_output` is called with every chunk of output as soon as it's read, in the order it was read.
+pub fn run_with_limits(
+    command: &mut Command,
+    stdin: Option<&[u8]>,
+    limits: &ResourceLimits,
+    on_output: &mut dyn FnMut(OutputStream, &[u8]),
+) -> io::Result<ProcessOutput> {
+    let stdin_config = if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    let start = Instant::now();
+    let (mut child, receiver) = spawn_with_limits(command, stdin_config, limits)?;
+    let stdin_writer = write_in_background(child.stdin.take(), stdin);
+    let mut output = OutputCollector::new(limits.output_bytes, on_output);
+
+    let finished = loop {
+        if let Some(finished) = try_wait_with_usage(&mut child)? {
+            break Some(finished);
+        }
+        let remaining = limits.wall_time.map_or(POLL_INTERVAL, |timeout| {
+            timeout.saturating_sub(start.elapsed())
+        });
+        if remaining.is_zero() || output.truncated {
+            break None;
+        }
+        // Waiting for output doubles as the pause between checks
+        if let Ok((stream, chunk)) = receiver.recv_timeout(POLL_INTERVAL.min(remaining)) {
+            output.collect(stream, &chunk);
+        }
+    };
+    kill_process_group(child.id());
+    let killed = finished.is_none();
+    let (status, mut usage) = match finished {
+        Some(finished) => finished,
+        None => wait_with_usage(&mut child)?,
+    };
+    usage.wall_time_ms = start.elapsed().as_millis() as u64;
+    // The readers stop once the pipes are closed, which ends the iteration
+    for (stream, chunk) in receiver {
+        output.collect(stream, &chunk);
+    }
+    // The writer fails with a broken pipe if the command doesn't read all of its input
+    let _ = stdin_writer.join();
+
+    Ok(ProcessOutput {
+        status: if killed { None } else { Some(status) },
+        signal: exit_signal(&status),
+        stdout: output.stdout,
+        stderr: output.stderr,
+        timed_out: killed && !output.truncated,
+        output_truncated: output.truncated,
+        usage,
+    })
+}
+
+/// The chunks of output read from a process spawned with `spawn_with_limits`, which
+/// disconnects once the process and everything it started closed their output.
+pub(crate) type OutputReceiver = Receiver<(OutputStream, Vec<u8>)>;
+
+/// Spawns `command` with `stdin`, in its own process group and with the rlimits of `limits`,
+/// reading its stdout and stderr in the background.
+pub(crate) fn spawn_with_limits(
+    command: &mut Command,
+    stdin: Stdio,
+    limits: &ResourceLimits,
+) -> io::Result<(Child, OutputReceiver)> {
+    command
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+        set_rlimits(command, limits);
+    }
+    #[cfg(not(unix))]
+    let _ = limits;
+
+    let mut child = command.spawn()?;
+    let (sender, receiver) = mpsc::channel();
+    read_in_background(child.stdout.take(), OutputStream::Stdout, sender.clone());
+    read_in_background(child.stderr.take(), OutputStream::Stderr, sender);
+    Ok((child, receiver))
+}
+
+/// Keeps the output of a process up to its limit, passing it on as it arrives.
+pub(crate) struct OutputCollector<'a> {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    limit: Option<u64>,
+    /// Whether some output was dropped for exceeding the limit.
+    pub truncated: bool,
+    on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+}
+
+impl<'a> OutputCollector<'a> {
+    pub fn new(limit: Option<u64>, on_output: &'a mut dyn FnMut(OutputStream, &[u8])) -> Self {
+        Self {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            limit,
+            truncated: false,
+            on_output,
+        }
+    }
+
+    pub fn collect(&mut self, stream: OutputStream, chunk: &[u8]) {
+        let collected = (self.stdout.len() + self.stderr.len()) as u64;
+        let room = self
+            .limit
+            .map_or(u64::MAX, |limit| limit.saturating_sub(collected));
+        let kept = &chunk[..chunk.len().min(usize::try_from(room).unwrap_or(usize::MAX))];
+        if kept.len() < chunk.len() {
+            self.truncated = true;
+        }
+        if kept.is_empty() {
+            return;
+        }
+        (self.on_output)(stream, kept);
+        match stream {
+            OutputStream::Stdout => self.stdout.extend_from_slice(kept),
+            OutputStream::Stderr => self.stderr.extend_from_slice(kept),
+        }
+    }
+}
+
+pub(crate) fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+// Sets the limits of the command as rlimits of its process, inherited by the processes it
+// starts. Limits above the current hard limit are lowered to it, as they can't be raised.
+#[cfg(unix)]
+fn set_rlimits(command: &mut Command, limits: &ResourceLimits) {
+    use std::os::unix::process::CommandExt;
+    let rlimits = [
+        (
+            libc::RLIMIT_CPU,
+            limits
+                .cpu_time
+                .map(|cpu_time| cpu_time.as_secs_f64().ceil().max(1.0) as u64),
+        ),
+        (libc::RLIMIT_DATA, limits.memory_bytes),
+        (libc::RLIMIT_NPROC, limits.processes),
+        (libc::RLIMIT_FSIZE, limits.file_size_bytes),
+    ];
+    if rlimits.iter().all(|(_, value)| value.is_none()) {
+        return;
+    }
+    // SAFETY: the closure runs between fork and exec, where it only makes system calls
+    // that are async-signal-safe and doesn't allocate
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, value) in rlimits {
+                let Some(value) = value else {
+                    continue;
+                };
+                let mut current = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if libc::getrlimit(resource, &mut current) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let soft = (value as libc::rlim_t).min(current.rlim_max);
+                // The CPU soft limit sends SIGXCPU, the hard one a second later SIGKILL
+                let hard = if resource == libc::RLIMIT_CPU {
+                    soft.saturating_add(1).min(current.rlim_max)
+                } else {
+                    soft
+                };
+                let limit = libc::rlimit {
+                    rlim_cur: soft,
+                    rlim_max: hard,
+                };
+                if libc::setrlimit(resource, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+// Returns the exit status and usage of the child if it finished, reaping it
+#[cfg(unix)]
+pub(crate) fn try_wait_with_usage(
+    child: &mut Child,
+) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    wait4(child, libc::WNOHANG)
+}
+
+#[cfg(unix)]
+pub(crate) fn wait_with_usage(child: &mut Child) -> io::Result<(ExitStatus, ResourceUsage)> {
+    Ok(wait4(child, 0)?.expect("A blocking wait returns once the child finished"))
+}
+
+#[cfg(unix)]
+fn wait4(child: &mut Child, options: i32) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    use std::os::unix::process::ExitStatusExt;
+    let mut status = 0;
+    // SAFETY: rusage is plain data, for which zeroes are valid
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: the pointers are valid for the duration of the call. The child is reaped
+        // here instead of by `Child`, which doesn't report its usage, so it's never waited
+        // for with `Child` afterwards.
+        let pid =
+            unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, options, &mut rusage) };
+        match pid {
+            0 => return Ok(None),
+            -1 => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+            _ => break,
+        }
+    }
+
+    let cpu_time = |time: libc::timeval| time.tv_sec as u64 * 1000 + time.tv_usec as u64 / 1000;
+    // Linux reports the resident set size in kilobytes, macOS in bytes
+    let max_rss = rusage.ru_maxrss as u64;
+    let peak_memory_bytes = if cfg!(target_os = "macos") {
+        max_rss
+    } else {
+        max_rss * 1024
+    };
+    Ok(Some((
+        ExitStatus::from_raw(status),
+        ResourceUsage {
+            wall_time_ms: 0,
+            cpu_time_ms: Some(cpu_time(rusage.ru_utime) + cpu_time(rusage.ru_stime)),
+            peak_memory_bytes: Some(peak_memory_bytes),
+        },
+    )))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn try_wait_with_usage(
+    child: &mut Child,
+) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    Ok(child
+        .try_wait()?
+        .map(|status| (status, ResourceUsage::default())))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn wait_with_usage(child: &mut Child) -> io::Result<(ExitStatus, ResourceUsage)> {
+    Ok((child.wait()?, ResourceUsage::default()))
+}
+
+/// Kills the process group led by the process `pid`, as started by `spawn_with_limits`.
+pub(crate) fn kill_process_group(pid: u32) {
+    #[cfg(unix)]
+    {
+        // SAFETY: kill has no memory safety requirements. The group may be gone already,
+        // so errors are ignored.
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        // Without process groups only the process itself is killed
+        let _ = Command::new("taskkill")
+            .args(["/F", "/T", "/PID", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+// Writes `input` to the pipe from another thread, so a command that doesn't read its
+// input until it has printed some output doesn't deadlock with us
+fn write_in_background<W: Write + Send + 'static>(
+    pipe: Option<W>,
+    input: Option<&[u8]>,
+) -> JoinHandle<()> {
+    let input = input.map(<[u8]>::to_vec);
+    thread::spawn(move || {
+        if let (Some(mut pipe), Some(input)) = (pipe, input) {
+            let _ = pipe.write_all(&input);
+        }
+        // Dropping the pipe closes it, so the command reads the end of its input
+    })
+}
+
+// Sends what's read from the pipe as it arrives, until the pipe is closed or fails
+fn read_in_background<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    stream: OutputStream,
+    sender: Sender<(OutputStream, Vec<u8>)>,
+) {
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    thread::spawn(move || {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        loop {
+            match pipe.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => {
+                    if sender.send((stream, buffer[..read].to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_timeout(timeout: Duration) -> ResourceLimits {
+        ResourceLimits {
+            wall_time: Some(timeout),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_run_with_limits_completes() {
+        let output = run_with_limits(
+            Command::new("sh")
+                .arg("-c")
+                .arg("echo out; echo err >&2; exit 3"),
+            None,
+            &with_timeout(Duration::from_secs(10)),
+            &mut |_, _| {},
+        )
+        .unwrap();
+
+        assert!(!output.timed_out);
+        assert_eq!(output.status.and_then(|s| s.code()), Some(3));
+        assert_eq!(output.signal, None);
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[test]
+    fn test_run_with_limits_kills_process_tree() {
+        let start = Instant::now();
+        // The background sleep keeps the pipes open unless the whole group is killed
+        let output = run_with_limits(
+            Command::new("sh")
+                .arg("-c")
+                .arg("echo partial; sleep 30 & sleep 30"),
+            None,
+            &with_timeout(Duration::from_millis(200)),
+            &mut |_, _| {},
+        )
+        .unwrap();
+
+        assert!(output.timed_out);
+        assert!(output.status.is_none());
+        assert_eq!(output.stdout, b"partial\n");
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_run_with_limits_feeds_stdin() {
+        let output = run_with_limits(
+            Command::new("sh")
+                .arg("-c")
+                .arg("read a; read b; echo $((a + b))"),
+            Some(b"2\n3\n"),
+            &with_timeout(Duration::from_secs(10)),
+            &mut |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(output.stdout, b"5\n");
+
+        // Without input the command reads the end of its input right away
+        let output = run_with_limits(
+            &mut Command::new("cat"),
+            None,
+            &with_timeout(Duration::from_secs(10)),
+            &mut |_, _| {},
+        )
+        .unwrap();
+        assert!(!output.timed_out);
+        assert!(output.stdout.is_empty());
+    }
+
+    #[test]
+    fn test_run_with_limits_streams_output() {
+        let start = Instant::now();
+        let mut chunks = vec![];
+        let output = run_with_limits(
+            Command::new("sh")
+                .arg("-c")
+                .arg("echo first; sleep 0.5; echo second >&2"),
+            None,
+            &with_timeout(Duration::from_secs(10)),
+            &mut |stream, chunk| chunks.push((stream, chunk.to_vec(), start.elapsed())),
+        )
+        .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].0, OutputStream::Stdout);
+        assert_eq!(chunks[0].1, b"first\n");
+        assert_eq!(chunks[1].0, OutputStream::Stderr);
+        assert_eq!(chunks[1].1, b"second\n");
+        // The first chunk arrived while the command was still running
+        assert!(chunks[1].2 - chunks[0].2 >= Duration::from_millis(300));
+        assert_eq!(output.stdout, b"first\n");
+        assert_eq!(output.stderr, b"second\n");
+    }
+
+    #[test]
+    fn test_run_with_limits_truncates_output() {
+        let output = run_with_limits(
+            Command::new("sh")
+                .arg("-c")
+                .arg("while true; do echo spam; done"),
+            None,
+            &ResourceLimits {
+                output_bytes: Some(12),
+                ..with_timeout(Duration::from_secs(10))
+            },
+            &mut |_, _| {},
+        )
+        .unwrap();
+
+        assert!(output.output_truncated);
+        assert!(!output.timed_out);
+        assert!(output.status.is_none());
+        assert_eq!(output.stdout, b"spam\nspam\nsp");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_with_limits_reports_usage_and_signal() {
+        // Busy loop until the CPU limit sends SIGXCPU
+        let output = run_with_limits(
+            Command::new("sh").arg("-c").arg("while true; do :; done"),
+            None,
+            &ResourceLimits {
+                cpu_time: Some(Duration::from_secs(1)),
+                ..with_timeout(Duration::from_secs(20))
+            },
+            &mut |_, _| {},
+        )
+        .unwrap();
+
+        assert!(!output.timed_out);
+        assert_eq!(output.signal, Some(libc::SIGXCPU));
+        assert!(output.usage.cpu_time_ms.unwrap() >= 900);
+        assert!(output.usage.wall_time_ms >= output.usage.cpu_time_ms.unwrap() / 2);
+        assert!(output.usage.peak_memory_bytes.unwrap() > 0);
+    }
+}
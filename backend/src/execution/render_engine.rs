@@ -1,3 +1,4 @@
+use crate::doc::MappedCode;
 use crate::errors::ExecutionError;
 use crate::utils::get_indentation_at_offset;
 use crate::utils::set_indentation;
@@ -17,56 +18,59 @@ use std::collections::HashMap;
 ///   into the IMPORTS placeholder marker of the template
 /// * `body` - contents of the codeblock to be spliced into
 ///   the BODY placeholder marker of the template
+///
+/// Both replacements keep the markdown lines they come from, so the rendered code can be
+/// mapped back to the document. Lines from the template itself are left unmapped.
 /// # Returns
-/// * A result with a string with the rendered template content
+/// * A result with the rendered template content
 ///   or an error if rendering fails
 pub fn render(
     template: String,
     regex: &Regex,
-    imports: &str,
-    body: &str,
-) -> Result<String, ExecutionError> {
-    let replacements = HashMap::from([
-        ("IMPORTS".to_string(), imports.to_string()),
-        ("BODY".to_string(), body.to_string()),
-    ]);
+    imports: MappedCode,
+    body: MappedCode,
+) -> Result<MappedCode, ExecutionError> {
+    let replacements =
+        HashMap::from([("IMPORTS".to_string(), imports), ("BODY".to_string(), body)]);
 
-    let result = process_replacements(&replacements, regex, template)?;
-
-    Ok(result)
+    process_replacements(&replacements, regex, template)
 }
 
 // TODO: Remove unwraps.
 fn process_replacements(
-    replacements: &HashMap<String, String>,
+    replacements: &HashMap<String, MappedCode>,
     regex: &Regex,
     template: String,
-) -> Result<String, ExecutionError> {
+) -> Result<MappedCode, ExecutionError> {
     // Replace all occurrences, adjusting indentation for each match
-    let result = regex
-        .replace_all(&template, |caps: &regex::Captures| {
-            // Extract the key from the first capture group
-            if let Some(captured_key) = caps.get(1) {
-                debug!("Found placeholder key: {}", captured_key.as_str());
-                let replacement_value = replacements
-                    .get(captured_key.as_str())
-                    .cloned()
-                    .unwrap_or("".to_string());
-                format_replacement(&template, replacement_value, caps)
-            } else {
-                // If no capture group, keep the original match
-                caps.get(0).unwrap().as_str().to_string()
-            }
-        })
-        .into_owned();
+    let mut result = MappedCode::new(None);
+    let mut final_index = 0;
+    for caps in regex.captures_iter(&template) {
+        let mat = caps.get(0).unwrap();
+        result.push_generated(&template[final_index..mat.start()]);
+        // Extract the key from the first capture group
+        if let Some(captured_key) = caps.get(1) {
+            debug!("Found placeholder key: {}", captured_key.as_str());
+            let replacement_value = replacements
+                .get(captured_key.as_str())
+                .cloned()
+                .unwrap_or(MappedCode::new(None));
+            result.push_mapped(format_replacement(&template, replacement_value, &caps));
+        } else {
+            // If no capture group, keep the original match
+            result.push_generated(mat.as_str());
+        }
+        final_index = mat.end();
+    }
+    result.push_generated(&template[final_index..]);
     Ok(result)
 }
 
 fn format_replacement(
     wrapper_template: &str,
-    replacement_value: String,
+    replacement_value: MappedCode,
     captures: &regex::Captures,
-) -> String {
+) -> MappedCode {
     // Find the start of the match in the result string to calculate indentation
     let mat = captures.get(0).unwrap();
     let start_offset = mat.start();
@@ -75,11 +79,13 @@ fn format_replacement(
 
     let mut replacement = replacement_value;
     if indent_size > 0 {
+        let mut code = replacement.code.clone();
         set_indentation(
-            &mut replacement,
+            &mut code,
             Some(indent_size),
             Some(' '), // Use space as default indent character
         );
+        replacement.replace_code(code);
     }
     replacement
 }
@@ -90,6 +96,12 @@ mod tests {
 
     use super::*;
 
+    fn unmapped(text: &str) -> MappedCode {
+        let mut mapped_code = MappedCode::new(None);
+        mapped_code.push_generated(text);
+        mapped_code
+    }
+
     fn get_sample_template() -> String {
         r##"#<IMPORTS>#
 
@@ -106,10 +118,16 @@ void main(){
         let regex = Regex::new(PLACEHOLDER_DEFAULT_PATTERN).unwrap();
 
         // The template expects to replace #<IMPORTS># and #<BODY>#
-        let rendered = render(template, &regex, "use std::io;", "println!(\"Hello\");").unwrap();
+        let rendered = render(
+            template,
+            &regex,
+            unmapped("use std::io;"),
+            unmapped("println!(\"Hello\");"),
+        )
+        .unwrap();
 
         assert_eq!(
-            rendered,
+            rendered.code,
             r##"use std::io;
 
 void main(){
@@ -126,13 +144,13 @@ void main(){
         let placeholder = Regex::new(PLACEHOLDER_DEFAULT_PATTERN).unwrap();
 
         let mut replacements = HashMap::new();
-        replacements.insert("IMPORTS".to_string(), "use std::io;".to_string());
+        replacements.insert("IMPORTS".to_string(), unmapped("use std::io;"));
         // Missing BODY replacement
 
         let result = process_replacements(&replacements, &placeholder, template).unwrap();
 
         assert_eq!(
-            result,
+            result.code,
             r##"use std::io;
 
 void main(){
@@ -142,6 +160,27 @@ void main(){
         );
     }
 
+    #[test]
+    fn test_render_keeps_source_lines() {
+        let template = get_sample_template();
+        let regex = Regex::new(PLACEHOLDER_DEFAULT_PATTERN).unwrap();
+        let mut imports = MappedCode::new(Some(2));
+        imports.push_source("#include <stdio.h>", 2);
+        let mut body = MappedCode::new(Some(6));
+        body.push_source("int x = 1;\nprintf(\"%d\", x);", 6);
+
+        let rendered = render(template, &regex, imports, body).unwrap();
+
+        assert_eq!(
+            rendered.code,
+            "#include <stdio.h>\n\nvoid main(){\n    int x = 1;\n    printf(\"%d\", x);\n    return;\n}"
+        );
+        assert_eq!(
+            rendered.lines,
+            vec![Some(2), None, None, Some(6), Some(7), None, None]
+        );
+    }
+
     #[test]
     fn test_set_indentation_default() {
         let mut code = "fn main() {\nprintln!(\"hi\");\n}".to_string();
@@ -0,0 +1,208 @@
+use super::process::ResourceLimits;
+use crate::errors::ExecutionError;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+pub(crate) const BUBBLEWRAP_PROGRAM: &str = "bwrap";
+
+// Limits of a sandboxed execution when its language doesn't set them. Its CPU time is
+// limited to its wall time.
+const MAX_DATA_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+const MAX_PROCESSES: u64 = 2048;
+const MAX_FILE_SIZE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// How executions are isolated from the rest of the system.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sandbox {
+    /// The block runs as the user, with access to everything the user can access.
+    #[default]
+    None,
+    /// The block runs with bubblewrap (Linux only) in its own namespaces: the filesystem is
+    /// read-only except for its scratch directory, there's no network, and its CPU time,
+    /// memory, processes and file sizes are limited.
+    Bubblewrap,
+}
+
+impl FromStr for Sandbox {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "none" => Ok(Sandbox::None),
+            "bubblewrap" | "bwrap" => Ok(Sandbox::Bubblewrap),
+            _ => Err(format!(
+                "Unknown sandbox '{}', expected 'none' or 'bubblewrap'",
+                name
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Sandbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sandbox::None => write!(f, "none"),
+            Sandbox::Bubblewrap => write!(f, "bubblewrap"),
+        }
+    }
+}
+
+/// Builds the command running `program` with `args` from `cwd` within `sandbox`, which can
+/// only write to `work_dir`.
+pub(crate) fn sandboxed_command(
+    sandbox: Sandbox,
+    work_dir: &Path,
+    cwd: &Path,
+    program: impl AsRef<OsStr>,
+    args: &[impl AsRef<OsStr>],
+) -> Command {
+    let mut command = match sandbox {
+        Sandbox::None => {
+            let mut command = Command::new(program);
+            command.args(args);
+            command
+        }
+        Sandbox::Bubblewrap => {
+            let mut command = Command::new(BUBBLEWRAP_PROGRAM);
+            command
+                .args(bubblewrap_args(work_dir, cwd))
+                .arg(program.as_ref())
+                .args(args);
+            command
+        }
+    };
+    command.current_dir(cwd);
+    command
+}
+
+// Mounts everything read-only but the scratch directory, with fresh /dev, /proc and /tmp,
+// and unshares every namespace, the network included. The sandbox dies with its parent,
+// so killing bubblewrap on a timeout kills everything inside.
+fn bubblewrap_args(work_dir: &Path, cwd: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = [
+        "--ro-bind",
+        "/",
+        "/",
+        "--dev",
+        "/dev",
+        "--proc",
+        "/proc",
+        "--tmpfs",
+        "/tmp",
+        "--unshare-all",
+        "--die-with-parent",
+    ]
+    .iter()
+    .map(OsString::from)
+    .collect();
+    // The directory to run from may be under /tmp, which the fresh one hides
+    if cwd != work_dir {
+        args.extend(["--ro-bind".into(), cwd.into(), cwd.into()]);
+    }
+    args.extend([
+        "--bind".into(),
+        work_dir.into(),
+        work_dir.into(),
+        "--chdir".into(),
+        cwd.into(),
+        "--".into(),
+    ]);
+    args
+}
+
+/// Fails if `sandbox` isn't available on this platform.
+pub(crate) fn check_available(sandbox: Sandbox) -> Result<(), ExecutionError> {
+    if sandbox == Sandbox::Bubblewrap && !cfg!(target_os = "linux") {
+        return Err(ExecutionError::SandboxError(
+            "The bubblewrap sandbox is only available on Linux".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Maps the error of spawning a command built with `sandboxed_command`, telling apart
+/// a missing bubblewrap.
+pub(crate) fn spawn_error(sandbox: Sandbox, error: io::Error) -> ExecutionError {
+    match sandbox {
+        Sandbox::Bubblewrap if error.kind() == io::ErrorKind::NotFound => {
+            ExecutionError::SandboxError(format!(
+                "{} was not found, install bubblewrap to use its sandbox",
+                BUBBLEWRAP_PROGRAM
+            ))
+        }
+        _ => ExecutionError::InternalError(error.to_string()),
+    }
+}
+
+/// Completes `limits` with the defaults of sandboxed executions.
+pub(crate) fn sandbox_limits(limits: &ResourceLimits) -> ResourceLimits {
+    ResourceLimits {
+        cpu_time: limits.cpu_time.or(limits.wall_time),
+        memory_bytes: limits.memory_bytes.or(Some(MAX_DATA_BYTES)),
+        processes: limits.processes.or(Some(MAX_PROCESSES)),
+        file_size_bytes: limits.file_size_bytes.or(Some(MAX_FILE_SIZE_BYTES)),
+        ..*limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sandbox() {
+        assert_eq!("none".parse(), Ok(Sandbox::None));
+        assert_eq!("bubblewrap".parse(), Ok(Sandbox::Bubblewrap));
+        assert!("docker".parse::<Sandbox>().is_err());
+    }
+
+    #[test]
+    fn test_bubblewrap_command() {
+        let command = sandboxed_command(
+            Sandbox::Bubblewrap,
+            Path::new("/tmp/tanglit/main-1"),
+            Path::new("/home/user/docs"),
+            Path::new("/tmp/tanglit/main-1/execute_script.sh"),
+            &[Path::new("/tmp/tanglit/main-1/main.py")],
+        );
+        let args: Vec<String> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+
+        assert_eq!(command.get_program(), BUBBLEWRAP_PROGRAM);
+        assert!(args.contains(&"--unshare-all".to_string()));
+        let bind = args.iter().position(|arg| arg == "--bind").unwrap();
+        assert_eq!(args[bind + 1], "/tmp/tanglit/main-1");
+        // The writable scratch directory is mounted over the fresh /tmp
+        let tmpfs = args.iter().position(|arg| arg == "--tmpfs").unwrap();
+        assert!(tmpfs < bind);
+        let chdir = args.iter().position(|arg| arg == "--chdir").unwrap();
+        assert_eq!(args[chdir + 1], "/home/user/docs");
+        assert_eq!(
+            args[args.len() - 2..],
+            [
+                "/tmp/tanglit/main-1/execute_script.sh",
+                "/tmp/tanglit/main-1/main.py"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sandbox_limits() {
+        let limits = sandbox_limits(&ResourceLimits {
+            wall_time: Some(std::time::Duration::from_secs(5)),
+            memory_bytes: Some(1024),
+            ..Default::default()
+        });
+        assert_eq!(limits.cpu_time, Some(std::time::Duration::from_secs(5)));
+        assert_eq!(limits.memory_bytes, Some(1024));
+        assert_eq!(limits.processes, Some(MAX_PROCESSES));
+    }
+}
@@ -0,0 +1,567 @@
+use super::artifacts::{
+    ARTIFACTS_ENV_VAR, Artifact, artifacts_dir, collect_artifacts, prepare_artifacts_dir,
+};
+use super::process::{
+    OutputCollector, OutputReceiver, OutputStream, POLL_INTERVAL, ResourceLimits, ResourceUsage,
+    exit_signal, kill_process_group, spawn_with_limits, try_wait_with_usage, wait_with_usage,
+};
+use super::sandbox::{Sandbox, check_available, sandbox_limits, sandboxed_command, spawn_error};
+use super::{ExecutionOutput, Invocation, WORK_DIR_ENV_VAR, resolve_limits, resolve_sandbox};
+use crate::configuration::get_temp_dir;
+use crate::configuration::language_config::LanguageConfig;
+use crate::doc::DEFAULT_SOURCE_NAME;
+use crate::errors::ExecutionError;
+use log::debug;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdin, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+// What is written to an interpreter to run a block when its language doesn't say
+const DEFAULT_SESSION_INPUT: &str = "{file}\n";
+
+// The running sessions, started by the first block that runs in each of them
+static SESSIONS: Lazy<Mutex<HashMap<SessionKey, Arc<SessionHandle>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Identifies an interpreter session: blocks share one if they are in the same document,
+/// have the same language and are marked with the same `session=` name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub document: String,
+    pub language: String,
+    pub name: String,
+}
+
+impl SessionKey {
+    /// The session `name` of `language` in `document`, which is the path of the markdown
+    /// file or `None` for documents that weren't read from a file.
+    pub fn new(document: Option<&str>, language: &str, name: &str) -> Self {
+        Self {
+            document: document.unwrap_or(DEFAULT_SOURCE_NAME).to_string(),
+            language: language.to_string(),
+            name: name.to_string(),
+        }
+    }
+}
+
+// A running session. Its process can be killed while a block runs in it, which makes
+// the block finish, so the pid is kept outside of the lock.
+struct SessionHandle {
+    pid: u32,
+    sandbox: Sandbox,
+    // Environment and directory the interpreter was started with
+    env: Vec<(String, String)>,
+    cwd: Option<PathBuf>,
+    session: Mutex<Session>,
+}
+
+struct Session {
+    child: std::process::Child,
+    stdin: ChildStdin,
+    output: OutputReceiver,
+    // Line the interpreter prints to both streams after running a block
+    marker: String,
+    input: String,
+    // Directory the interpreter runs from and the block files are written to
+    work_dir: TempDir,
+    // Set once the process was reaped
+    exit: Option<ExitStatus>,
+}
+
+/// Runs the block file written by `write_code` in the session `key`, starting it with
+/// `lang_config` and the environment and directory of `invocation` if it isn't running,
+/// whose arguments and input are ignored. A session started within another sandbox,
+/// environment or directory is restarted. If the block exceeds its time or output `limits`, or kills the interpreter,
+/// the session ends and the next block starts a fresh one.
+/// The artifacts of the block are passed to `save_artifacts` along with their directory while
+/// it still holds them.
+/// Returns the output of the block and the path of its file.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_in_session(
+    key: SessionKey,
+    lang_config: &LanguageConfig,
+    sandbox: Sandbox,
+    limits: &ResourceLimits,
+    invocation: &Invocation,
+    write_code: impl FnOnce(&Path) -> io::Result<PathBuf>,
+    save_artifacts: impl FnOnce(&mut [Artifact], &Path) -> io::Result<()>,
+    on_output: &mut dyn FnMut(OutputStream, &[u8]),
+) -> Result<(ExecutionOutput, PathBuf), ExecutionError> {
+    let limits = match sandbox {
+        Sandbox::None => *limits,
+        Sandbox::Bubblewrap => sandbox_limits(limits),
+    };
+    let handle = {
+        let mut sessions = lock(&SESSIONS);
+        match sessions.get(&key) {
+            Some(handle)
+                if handle.sandbox == sandbox
+                    && handle.env == invocation.env
+                    && handle.cwd == invocation.cwd =>
+            {
+                Arc::clone(handle)
+            }
+            _ => {
+                if let Some(handle) = sessions.remove(&key) {
+                    kill_process_group(handle.pid);
+                }
+                let handle = Arc::new(start_session(
+                    lang_config,
+                    sandbox,
+                    &limits,
+                    &invocation.env,
+                    invocation.cwd.as_deref(),
+                )?);
+                debug!("Started session {:?} with pid {}", key, handle.pid);
+                sessions.insert(key.clone(), Arc::clone(&handle));
+                handle
+            }
+        }
+    };
+
+    let mut session = lock(&handle.session);
+    let block_file_path = write_code(session.work_dir.path())
+        .map_err(|e| ExecutionError::WriteError(e.to_string()))?;
+    // Each block only gets the artifacts it wrote
+    let artifacts_dir = prepare_artifacts_dir(session.work_dir.path())
+        .map_err(|e| ExecutionError::WriteError(e.to_string()))?;
+    let mut output = session
+        .run(&block_file_path, &limits, on_output)
+        .map_err(|e| ExecutionError::SessionError(e.to_string()))?;
+    output.artifacts = collect_artifacts(&artifacts_dir).map_err(|e| {
+        ExecutionError::IOError(format!("Failed to read the artifacts of the block: {}", e))
+    })?;
+    save_artifacts(&mut output.artifacts, &artifacts_dir).map_err(|e| {
+        ExecutionError::WriteError(format!("Failed to save the artifacts of the block: {}", e))
+    })?;
+    output.sandbox = sandbox;
+    output.work_dir = Some(session.work_dir.path().display().to_string());
+
+    if session.exit.is_some() {
+        debug!("Session {:?} ended", key);
+        let mut sessions = lock(&SESSIONS);
+        // It may have been restarted already
+        if sessions
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &handle))
+        {
+            sessions.remove(&key);
+        }
+    }
+    Ok((output, block_file_path))
+}
+
+/// Starts the session `key` over with a new interpreter, dropping the state of the
+/// blocks that ran in it. The interpreter keeps the environment and directory of the
+/// previous one.
+pub fn restart_session(key: SessionKey) -> Result<(), ExecutionError> {
+    let (env, cwd) = match lock(&SESSIONS).get(&key) {
+        Some(handle) => (handle.env.clone(), handle.cwd.clone()),
+        None => (Vec::new(), None),
+    };
+    kill_session(&key);
+    let lang_config = LanguageConfig::load_for_lang(&key.language)?;
+    let sandbox = resolve_sandbox(None, &lang_config, &key.language)?;
+    let limits = resolve_limits(None, &lang_config, &key.language)?;
+    let limits = match sandbox {
+        Sandbox::None => limits,
+        Sandbox::Bubblewrap => sandbox_limits(&limits),
+    };
+    let handle = Arc::new(start_session(
+        &lang_config,
+        sandbox,
+        &limits,
+        &env,
+        cwd.as_deref(),
+    )?);
+    debug!("Restarted session {:?} with pid {}", key, handle.pid);
+    lock(&SESSIONS).insert(key, handle);
+    Ok(())
+}
+
+/// Kills the interpreter of the session `key`, and the block running in it if any.
+/// Returns whether the session was running.
+pub fn kill_session(key: &SessionKey) -> bool {
+    let handle = lock(&SESSIONS).remove(key);
+    match handle {
+        Some(handle) => {
+            // The process is reaped once the block running in it, if any, notices
+            kill_process_group(handle.pid);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Kills every running session, as when the program exits.
+pub fn kill_all_sessions() {
+    let handles: Vec<_> = lock(&SESSIONS).drain().map(|(_, handle)| handle).collect();
+    for handle in handles {
+        kill_process_group(handle.pid);
+    }
+}
+
+/// Returns the sessions currently running.
+pub fn running_sessions() -> Vec<SessionKey> {
+    lock(&SESSIONS).keys().cloned().collect()
+}
+
+// A panic while holding a lock leaves the sessions usable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Starts the interpreter from `cwd`, or else the directory of the session, with the
+// variables of `env` added to its environment
+fn start_session(
+    lang_config: &LanguageConfig,
+    sandbox: Sandbox,
+    limits: &ResourceLimits,
+    env: &[(String, String)],
+    cwd: Option<&Path>,
+) -> Result<SessionHandle, ExecutionError> {
+    check_available(sandbox)?;
+    let session_command = lang_config
+        .session_command
+        .as_ref()
+        .filter(|command| !command.is_empty())
+        .ok_or_else(|| {
+            ExecutionError::SessionError(
+                "The language doesn't support sessions, set its session_command".to_string(),
+            )
+        })?;
+
+    let marker = new_marker();
+    let args: Vec<String> = session_command
+        .iter()
+        .map(|arg| arg.replace("{marker}", &marker))
+        .collect();
+    let work_dir = create_session_dir().map_err(|e| ExecutionError::WriteError(e.to_string()))?;
+    let mut command = sandboxed_command(
+        sandbox,
+        work_dir.path(),
+        cwd.unwrap_or(work_dir.path()),
+        &args[0],
+        &args[1..],
+    );
+    command.envs(env.iter().map(|(key, value)| (key, value)));
+    command.env(WORK_DIR_ENV_VAR, work_dir.path());
+    command.env(ARTIFACTS_ENV_VAR, artifacts_dir(work_dir.path()));
+    // The CPU time limit would add up over every block of the session
+    let limits = ResourceLimits {
+        cpu_time: None,
+        ..*limits
+    };
+    let (mut child, output) = spawn_with_limits(&mut command, Stdio::piped(), &limits)
+        .map_err(|e| spawn_error(sandbox, e))?;
+    let stdin = child
+        .stdin
+        .take()
+        .expect("The stdin of the session is piped");
+
+    Ok(SessionHandle {
+        pid: child.id(),
+        sandbox,
+        env: env.to_vec(),
+        cwd: cwd.map(Path::to_path_buf),
+        session: Mutex::new(Session {
+            child,
+            stdin,
+            output,
+            input: lang_config
+                .session_input
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SESSION_INPUT.to_string()),
+            marker,
+            work_dir,
+            exit: None,
+        }),
+    })
+}
+
+// A line no block prints by chance
+fn new_marker() -> String {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos());
+    format!(
+        "__tanglit_session_{:x}_{:x}_{}__",
+        std::process::id(),
+        nanos,
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn create_session_dir() -> io::Result<TempDir> {
+    fs::create_dir_all(get_temp_dir())?;
+    // The interpreter runs from within the directory, so its path must not be relative
+    let temp_dir = fs::canonicalize(get_temp_dir())?;
+    tempfile::Builder::new()
+        .prefix("session-")
+        .tempdir_in(temp_dir)
+}
+
+// How a block run in a session ended
+enum RunEnd {
+    // The interpreter printed the markers, with the exit status of the block
+    Finished(i32),
+    // The interpreter exited
+    Exited,
+    // The block exceeded a limit
+    Killed,
+}
+
+impl Session {
+    // Runs the block file in the interpreter, up to the markers it prints once done
+    fn run(
+        &mut self,
+        block_file_path: &Path,
+        limits: &ResourceLimits,
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> io::Result<ExecutionOutput> {
+        let start = Instant::now();
+        let mut output = OutputCollector::new(limits.output_bytes, on_output);
+        let mut stdout_scanner = MarkerScanner::new(&self.marker);
+        let mut stderr_scanner = MarkerScanner::new(&self.marker);
+        // Output printed after the previous block finished, like by threads it left running,
+        // doesn't belong to this one
+        while self.output.try_recv().is_ok() {}
+
+        let input = self
+            .input
+            .replace("{file}", &block_file_path.display().to_string())
+            .replace("{marker}", &self.marker);
+        let written = self
+            .stdin
+            .write_all(input.as_bytes())
+            .and_then(|_| self.stdin.flush());
+
+        let end = loop {
+            if written.is_err() {
+                break RunEnd::Exited;
+            }
+            if let (Some(status), Some(_)) = (&stdout_scanner.line, &stderr_scanner.line) {
+                // The status follows the marker on stdout, as in `marker:1`
+                let status = status
+                    .strip_prefix(':')
+                    .and_then(|status| status.trim().parse().ok())
+                    .unwrap_or(0);
+                break RunEnd::Finished(status);
+            }
+            let remaining = limits.wall_time.map_or(POLL_INTERVAL, |timeout| {
+                timeout.saturating_sub(start.elapsed())
+            });
+            if remaining.is_zero() || output.truncated {
+                break RunEnd::Killed;
+            }
+            match self.output.recv_timeout(POLL_INTERVAL.min(remaining)) {
+                Ok((stream, chunk)) => {
+                    let scanner = match stream {
+                        OutputStream::Stdout => &mut stdout_scanner,
+                        OutputStream::Stderr => &mut stderr_scanner,
+                    };
+                    output.collect(stream, &scanner.scan(&chunk));
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some((status, _)) = try_wait_with_usage(&mut self.child)? {
+                        kill_process_group(self.child.id());
+                        self.exit = Some(status);
+                        break RunEnd::Exited;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break RunEnd::Exited,
+            }
+        };
+
+        let mut execution_output = ExecutionOutput::default();
+        match end {
+            RunEnd::Finished(status) => execution_output.status = Some(status),
+            RunEnd::Exited | RunEnd::Killed => {
+                let status = self.reap()?;
+                // The rest of the output of the interpreter, up to the pipes closing
+                for (stream, chunk) in self.output.try_iter() {
+                    let scanner = match stream {
+                        OutputStream::Stdout => &mut stdout_scanner,
+                        OutputStream::Stderr => &mut stderr_scanner,
+                    };
+                    output.collect(stream, &scanner.scan(&chunk));
+                }
+                output.collect(OutputStream::Stdout, &stdout_scanner.finish());
+                output.collect(OutputStream::Stderr, &stderr_scanner.finish());
+                execution_output.signal = exit_signal(&status);
+                if matches!(end, RunEnd::Killed) {
+                    execution_output.timed_out = !output.truncated;
+                    execution_output.output_truncated = output.truncated;
+                } else {
+                    execution_output.status = status.code();
+                }
+            }
+        }
+
+        execution_output.stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        execution_output.stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        execution_output.output_truncated |= output.truncated;
+        execution_output.usage = ResourceUsage {
+            wall_time_ms: start.elapsed().as_millis() as u64,
+            ..Default::default()
+        };
+        Ok(execution_output)
+    }
+
+    // Kills the interpreter and everything it started, returning how it exited
+    fn reap(&mut self) -> io::Result<ExitStatus> {
+        if let Some(status) = self.exit {
+            return Ok(status);
+        }
+        kill_process_group(self.child.id());
+        let (status, _) = wait_with_usage(&mut self.child)?;
+        self.exit = Some(status);
+        Ok(status)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.reap();
+    }
+}
+
+/// Finds the marker line printed by an interpreter in a stream of output, passing on
+/// what precedes it.
+struct MarkerScanner {
+    marker: Vec<u8>,
+    // Output held back until it's known whether it's part of the marker
+    pending: Vec<u8>,
+    /// What followed the marker in its line, once found.
+    line: Option<String>,
+}
+
+impl MarkerScanner {
+    fn new(marker: &str) -> Self {
+        Self {
+            marker: marker.as_bytes().to_vec(),
+            pending: Vec::new(),
+            line: None,
+        }
+    }
+
+    /// Returns the part of `chunk` that can be passed on as output of the block.
+    fn scan(&mut self, chunk: &[u8]) -> Vec<u8> {
+        if self.line.is_some() {
+            return Vec::new();
+        }
+        self.pending.extend_from_slice(chunk);
+        if let Some(start) = self
+            .pending
+            .windows(self.marker.len())
+            .position(|window| window == self.marker)
+        {
+            let after_marker = &self.pending[start + self.marker.len()..];
+            if let Some(end) = after_marker.iter().position(|&byte| byte == b'\n') {
+                self.line = Some(String::from_utf8_lossy(&after_marker[..end]).into_owned());
+                let output = self.pending[..start].to_vec();
+                self.pending.clear();
+                return output;
+            }
+            // The rest of the marker line is yet to come
+            return self.pending.drain(..start).collect();
+        }
+        // The end of the chunk may be the start of the marker
+        let kept = (1..self.marker.len())
+            .rev()
+            .find(|&len| self.pending.ends_with(&self.marker[..len]))
+            .unwrap_or(0);
+        self.pending.drain(..self.pending.len() - kept).collect()
+    }
+
+    /// Returns the output held back, once the stream ended.
+    fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::write_file;
+
+    #[test]
+    fn test_marker_scanner() {
+        let mut scanner = MarkerScanner::new("@@done@@");
+        let mut output = scanner.scan(b"hello\nwor");
+        output.extend(scanner.scan(b"ld @@do"));
+        assert_eq!(output, b"hello\nworld ");
+        assert!(scanner.line.is_none());
+
+        assert_eq!(scanner.scan(b"ne@@:1"), b"");
+        assert!(scanner.line.is_none());
+        assert_eq!(scanner.scan(b"\nignored"), b"");
+        assert_eq!(scanner.line.as_deref(), Some(":1"));
+
+        // Held back output that turns out not to be the marker is passed on
+        let mut scanner = MarkerScanner::new("@@done@@");
+        assert_eq!(scanner.scan(b"@@d"), b"");
+        assert_eq!(scanner.scan(b"x"), b"@@dx");
+        assert_eq!(scanner.scan(b"@"), b"");
+        assert_eq!(scanner.finish(), b"@");
+    }
+
+    #[test]
+    fn test_session_keeps_state() {
+        let lang_config = LanguageConfig::load_from_str(
+            r#"
+            extension = "sh"
+            session_command = ["sh"]
+            session_input = ". {file}; s=$?; echo {marker} >&2; echo {marker}:$s\n"
+            "#,
+        )
+        .unwrap();
+        let key = SessionKey::new(Some("test_session_keeps_state.md"), "sh", "main");
+        let limits = ResourceLimits {
+            wall_time: Some(std::time::Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let run = |code: &str| {
+            let (output, _) = run_in_session(
+                key.clone(),
+                &lang_config,
+                Sandbox::None,
+                &limits,
+                &Invocation::default(),
+                |dir| write_file(code.to_string(), dir, "block", Some("sh")),
+                |_, _| Ok(()),
+                &mut |_, _| {},
+            )
+            .unwrap();
+            output
+        };
+
+        let output = run("count=41\necho started\n");
+        assert_eq!(output.status, Some(0));
+        assert_eq!(output.stdout, "started\n");
+        let output = run("count=$((count + 1))\necho $count\nfalse\n");
+        assert_eq!(output.stdout, "42\n");
+        assert_eq!(output.status, Some(1));
+        assert!(running_sessions().contains(&key));
+
+        assert!(kill_session(&key));
+        assert!(!kill_session(&key));
+        // The next block starts over
+        let output = run("echo ${count:-unset}\n");
+        assert_eq!(output.stdout, "unset\n");
+
+        // A block ending the interpreter ends the session
+        let output = run("exit 3\n");
+        assert_eq!(output.status, Some(3));
+        assert!(!running_sessions().contains(&key));
+    }
+}
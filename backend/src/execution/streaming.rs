@@ -0,0 +1,122 @@
+use super::process::OutputStream;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// A piece of the output of an execution, delivered while it runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub text: String,
+    /// Milliseconds since the execution started.
+    pub elapsed_ms: u64,
+}
+
+/// Turns the bytes read from each stream into `OutputChunk`s. A character split between
+/// two reads is held back until the rest of it arrives, so chunks are never cut in the
+/// middle of a character. Once the streams end, `finish` returns what's left of them.
+pub(crate) struct ChunkDecoder {
+    start: Instant,
+    pending_stdout: Vec<u8>,
+    pending_stderr: Vec<u8>,
+}
+
+impl ChunkDecoder {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            pending_stdout: Vec::new(),
+            pending_stderr: Vec::new(),
+        }
+    }
+
+    /// Returns the chunk for `bytes`, or `None` if they only hold part of a character.
+    pub fn decode(&mut self, stream: OutputStream, bytes: &[u8]) -> Option<OutputChunk> {
+        let pending = match stream {
+            OutputStream::Stdout => &mut self.pending_stdout,
+            OutputStream::Stderr => &mut self.pending_stderr,
+        };
+        pending.extend_from_slice(bytes);
+
+        let complete = match std::str::from_utf8(pending) {
+            Ok(_) => pending.len(),
+            // An incomplete character at the end is kept for the next read
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            // Invalid bytes won't become valid later, they're replaced
+            Err(_) => pending.len(),
+        };
+        if complete == 0 {
+            return None;
+        }
+        let text = String::from_utf8_lossy(&pending[..complete]).into_owned();
+        pending.drain(..complete);
+
+        Some(OutputChunk {
+            stream,
+            text,
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Returns the chunks of the bytes still held back, which end in the middle of a
+    /// character that will never be completed, with it replaced.
+    pub fn finish(&mut self) -> Vec<OutputChunk> {
+        let elapsed_ms = self.start.elapsed().as_millis() as u64;
+        [
+            (OutputStream::Stdout, &mut self.pending_stdout),
+            (OutputStream::Stderr, &mut self.pending_stderr),
+        ]
+        .into_iter()
+        .filter(|(_, pending)| !pending.is_empty())
+        .map(|(stream, pending)| OutputChunk {
+            stream,
+            text: String::from_utf8_lossy(&std::mem::take(pending)).into_owned(),
+            elapsed_ms,
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_split_characters() {
+        let mut decoder = ChunkDecoder::new();
+        let bytes = "añb".as_bytes();
+
+        let chunk = decoder.decode(OutputStream::Stdout, &bytes[..2]).unwrap();
+        assert_eq!(chunk.text, "a");
+        assert!(decoder.decode(OutputStream::Stdout, &[]).is_none());
+        // The other stream doesn't see the pending byte
+        let chunk = decoder.decode(OutputStream::Stderr, b"x").unwrap();
+        assert_eq!(chunk.text, "x");
+        let chunk = decoder.decode(OutputStream::Stdout, &bytes[2..]).unwrap();
+        assert_eq!(chunk.text, "ñb");
+        assert_eq!(chunk.stream, OutputStream::Stdout);
+        assert!(decoder.finish().is_empty());
+    }
+
+    #[test]
+    fn test_finish_flushes_incomplete_characters() {
+        let mut decoder = ChunkDecoder::new();
+        let bytes = "añ".as_bytes();
+
+        assert_eq!(
+            decoder.decode(OutputStream::Stderr, b"x").unwrap().text,
+            "x"
+        );
+        assert_eq!(
+            decoder
+                .decode(OutputStream::Stdout, &bytes[..2])
+                .unwrap()
+                .text,
+            "a"
+        );
+        let chunks = decoder.finish();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].stream, OutputStream::Stdout);
+        assert_eq!(chunks[0].text, "\u{FFFD}");
+        assert!(decoder.finish().is_empty());
+    }
+}
@@ -2,12 +2,14 @@ use crate::configuration::language_config::LanguageConfig;
 use crate::doc::CodeBlock;
 use crate::doc::CodeBlocks;
 use crate::doc::DocError;
+use crate::doc::ExtraFile;
+use crate::doc::{MappedCode, TangledCode};
 use crate::errors::ExecutionError;
 use crate::execution::render_engine::render;
 use regex::Regex;
-use std::fs::write;
+use std::fs::{create_dir_all, write};
 use std::io;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 pub fn full_filename(name: &str, ext: Option<&str>) -> String {
     ext.as_ref()
@@ -29,23 +31,96 @@ pub fn write_file(
     io::Result::Ok(dst_path)
 }
 
+/// Writes the tangled code of a block to its export path under `dir`,
+/// creating any missing parent directories.
 pub fn write_code_to_file(block: &CodeBlock, code: String, dir: &str) -> io::Result<PathBuf> {
-    let lang = block.language.as_deref();
-    let extension = lang
+    let dst_path = PathBuf::from(dir).join(export_path(block)?);
+    if let Some(parent) = dst_path.parent() {
+        create_dir_all(parent)?;
+    }
+    write(&dst_path, code)?;
+    Ok(dst_path)
+}
+
+/// Writes the data files and source files written next to the code of an execution under `dir`,
+/// creating any missing parent directories.
+pub fn write_extra_files(files: &[ExtraFile], dir: &Path) -> io::Result<()> {
+    for file in files {
+        let dst_path = dir.join(&file.path);
+        if let Some(parent) = dst_path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(&dst_path, &file.content)?;
+    }
+    Ok(())
+}
+
+/// Returns the path a block is exported to, relative to the output directory.
+/// The export name may contain directories, e.g. `export=src/net/client.rs`, and is kept as
+/// written, so `export=Makefile` stays extensionless. Blocks without `export=` fall back to
+/// their tag, with the language extension added unless the tag already has one.
+/// Paths that would escape the output directory (absolute paths or `..`) are rejected.
+pub fn export_path(block: &CodeBlock) -> io::Result<PathBuf> {
+    if let Some(export) = &block.export {
+        return checked_path(export, block, "export path", "output directory");
+    }
+
+    let path = checked_path(&block.tag, block, "export path", "output directory")?;
+    if path.extension().is_some() {
+        return Ok(path);
+    }
+
+    let extension = block
+        .language
+        .as_deref()
         .and_then(|l| LanguageConfig::load_for_lang(l).ok())
         .and_then(|cfg| cfg.extension);
+    Ok(PathBuf::from(full_filename(
+        &block.tag,
+        extension.as_deref(),
+    )))
+}
 
-    let file_name = block.export.clone().unwrap_or(block.tag.clone());
+/// Returns the path of the file a block is written to with `file=`, relative to the directory
+/// of the execution. Paths that would escape it (absolute paths or `..`) are rejected.
+pub fn unit_file_path(block: &CodeBlock) -> io::Result<PathBuf> {
+    let file_name = block.file.clone().unwrap_or(block.tag.clone());
+    checked_path(&file_name, block, "file", "execution directory")
+}
 
-    write_file(code, &PathBuf::from(dir), &file_name, extension.as_deref())
+// Returns `file_name` as a path without `.` components, if it's a relative path that stays
+// inside its directory
+pub(super) fn checked_path(
+    file_name: &str,
+    block: &CodeBlock,
+    kind: &str,
+    dir: &str,
+) -> io::Result<PathBuf> {
+    let path = PathBuf::from(file_name);
+    let escapes_dir = path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if escapes_dir || path.file_name().is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid {} '{}' for block '{}': it must be a relative path inside the {}",
+                kind, file_name, block.tag, dir
+            ),
+        ));
+    }
+    Ok(path
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect())
 }
 
 /// Loads and applies a template wrapper for the given language
 fn add_wrapper(
     lang_config: &LanguageConfig,
-    code: &str,
-    imports: &str,
-) -> Result<String, ExecutionError> {
+    code: MappedCode,
+    imports: MappedCode,
+) -> Result<MappedCode, ExecutionError> {
     // TODO: This should be done in config
     let Some(pattern) = &lang_config.placeholder_regex else {
         return Err(ExecutionError::InternalError("Unable to get regex.".into()));
@@ -65,17 +140,21 @@ fn add_wrapper(
 pub fn tangle_imports(
     code_block: &CodeBlock,
     blocks: &CodeBlocks,
-) -> Result<String, ExecutionError> {
-    let mut imports_output = String::new();
+) -> Result<MappedCode, ExecutionError> {
+    let mut imports_output = MappedCode::new(None);
     for import in &code_block.imports {
         if let Some(import_block) = blocks.get_block(import) {
+            // Blocks with `file=` are written to their own file, see `TanglitDoc::unit_files`
+            if import_block.file.is_some() {
+                continue;
+            }
             // Tangle the imported block
             let import_output = blocks
-                .tangle_codeblock(import_block)
+                .tangle_mapped(import_block)
                 .map_err(|e| ExecutionError::from(DocError::from(e)))?;
             // Append the import output to the main output
-            imports_output.push_str(&import_output);
-            imports_output.push('\n');
+            imports_output.push_mapped(import_output);
+            imports_output.push_generated("\n");
         } else {
             return Err(ExecutionError::InternalError(format!(
                 "Import '{}' not found in blocks",
@@ -93,14 +172,36 @@ pub fn make_executable_code(
     blocks: &CodeBlocks,
     lang_config: &LanguageConfig,
 ) -> Result<String, ExecutionError> {
+    Ok(make_executable_mapped_code(code_block, blocks, lang_config)?.code)
+}
+
+/// Builds the executable code like `make_executable_code`, along with a source map
+/// from its lines to the lines of `source_file`.
+pub fn make_executable_code_with_source_map(
+    code_block: &CodeBlock,
+    blocks: &CodeBlocks,
+    lang_config: &LanguageConfig,
+    source_file: &str,
+) -> Result<TangledCode, ExecutionError> {
+    Ok(
+        make_executable_mapped_code(code_block, blocks, lang_config)?
+            .into_tangled_code(source_file),
+    )
+}
+
+fn make_executable_mapped_code(
+    code_block: &CodeBlock,
+    blocks: &CodeBlocks,
+    lang_config: &LanguageConfig,
+) -> Result<MappedCode, ExecutionError> {
     // Tangle blocks
     let imports_output = tangle_imports(code_block, blocks)?;
 
     let code = blocks
-        .tangle_codeblock(code_block)
+        .tangle_mapped(code_block)
         .map_err(|e| ExecutionError::from(DocError::from(e)))?;
 
-    add_wrapper(lang_config, &code, &imports_output)
+    add_wrapper(lang_config, code, imports_output)
 }
 
 #[cfg(test)]
@@ -164,4 +265,68 @@ mod tests {
             );
         });
     }
+
+    fn export_block(export: &str) -> CodeBlock {
+        CodeBlock::new(
+            Option::from("c".to_string()),
+            "int x;".to_string(),
+            "block".to_string(),
+            vec![],
+            Some(export.to_string()),
+            0,
+            0,
+        )
+    }
+
+    #[test]
+    fn test_export_path_keeps_export_names_as_written() {
+        let config_path = format!(
+            "{}/resources/config",
+            std::env::var("CARGO_MANIFEST_DIR").unwrap()
+        );
+        with_var("TANGLIT_CONFIG_DIR", Some(config_path), || {
+            assert_eq!(
+                export_path(&export_block("src/net/client.h")).unwrap(),
+                PathBuf::from("src/net/client.h")
+            );
+            assert_eq!(
+                export_path(&export_block("./src/main")).unwrap(),
+                PathBuf::from("src/main")
+            );
+            assert_eq!(
+                export_path(&export_block("Makefile")).unwrap(),
+                PathBuf::from("Makefile")
+            );
+
+            // Without `export=`, the tag is used with the extension of the language
+            let mut block = export_block("main");
+            block.export = None;
+            assert_eq!(export_path(&block).unwrap(), PathBuf::from("block.c"));
+            block.tag = "block.h".to_string();
+            assert_eq!(export_path(&block).unwrap(), PathBuf::from("block.h"));
+        });
+    }
+
+    #[test]
+    fn test_export_path_rejects_paths_outside_output_dir() {
+        for export in ["../main.c", "src/../../main.c", "/etc/main.c", "src/.."] {
+            let err = export_path(&export_block(export)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", export);
+        }
+    }
+
+    #[test]
+    fn test_write_code_to_file_creates_directories() {
+        let out_dir = tempfile::tempdir().unwrap();
+
+        let path = write_code_to_file(
+            &export_block("src/net/client.c"),
+            "int x;".to_string(),
+            out_dir.path().to_str().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(path, out_dir.path().join("src/net/client.c"));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "int x;");
+    }
 }
@@ -1,15 +1,22 @@
 use clap::Parser;
 use env_logger::init;
 use std::fs::{self, write};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use tanglit::cli::GenerateSlidesPdfArgs;
-use tanglit::cli::{Commands, GenerateDocArgs, GenerateSlidesMdArgs, TangleAllArgs, TangleArgs};
+use tanglit::cli::{
+    CachePruneArgs, Commands, ExecuteAllArgs, GenerateDocArgs, GenerateSlidesMdArgs, RunArgs,
+    TangleAllArgs, TangleArgs, TestArgs, UntangleArgs,
+};
 use tanglit::configuration::init_configuration;
 use tanglit::configuration::language_config::LanguageConfig;
-use tanglit::doc::{DEFAULT_THEME, TangleError, TanglitDoc};
+use tanglit::doc::{DEFAULT_THEME, RunSettings, TangleError, TanglitDoc, apply_edits};
 use tanglit::errors::ExecutionError;
 use tanglit::errors::ExecutionError::WriteError;
-use tanglit::execution::write_file;
+use tanglit::execution::{
+    BlockRunResult, Compared, ExecuteAllOptions, ExecutionOptions, Normalization, NotebookEvent,
+    OutputStream, Sandbox, TestOptions, TestResult, TestSummary, write_file,
+};
 use tanglit::{cli::Cli, execution};
 
 fn handle_tangle_command(tangle_args: TangleArgs) -> Result<String, ExecutionError> {
@@ -21,26 +28,61 @@ fn handle_tangle_command(tangle_args: TangleArgs) -> Result<String, ExecutionErr
     let block = blocks
         .get_block(&tangle_args.target_block)
         .ok_or(TangleError::BlockNotFound(tangle_args.target_block.clone()))?;
-    let output = blocks.tangle_codeblock(block)?;
-
     let lang = block.language.clone();
 
     // we can tangle even if we don't have a config for the language
     let lang_config = lang
         .as_deref()
         .and_then(|l| LanguageConfig::load_for_lang(l).ok());
+    let line_directive = lang_config
+        .as_ref()
+        .and_then(|cfg| cfg.line_directive.clone())
+        .filter(|_| tangle_args.line_directives.enabled);
+    let line_comment = lang_config
+        .as_ref()
+        .and_then(|cfg| cfg.line_comment.clone())
+        .filter(|_| tangle_args.chunk_markers.enabled);
     // we can tangle even if we don't have an extension
     let extension = lang_config.and_then(|cfg| cfg.extension);
 
+    let tangled = blocks.tangle_codeblock_with_source_map(
+        block,
+        line_directive.as_deref(),
+        line_comment.as_deref(),
+        doc.source_name(),
+    )?;
+
     // Write the output to a file
-    match write_file(
-        output,
+    let output_path = write_file(
+        tangled.code,
         &PathBuf::from(tangle_args.output.out_dir),
         &tangle_args.target_block,
         extension.as_deref(),
-    ) {
-        Ok(r) => Ok(format!("Blocks written to {}", r.display())),
-        Err(e) => Err(WriteError(format!("Error writing to file: {}", e))),
+    )
+    .map_err(|e| WriteError(format!("Error writing to file: {}", e)))?;
+
+    if !tangle_args.source_map {
+        return Ok(format!("Blocks written to {}", output_path.display()));
+    }
+
+    let source_map_path = PathBuf::from(format!("{}.map.json", output_path.display()));
+    let source_map = serde_json::to_string_pretty(&tangled.source_map)
+        .map_err(|e| ExecutionError::InternalError(e.to_string()))?;
+    write(&source_map_path, source_map)
+        .map_err(|e| WriteError(format!("Error writing to file: {}", e)))?;
+    Ok(format!(
+        "Blocks written to {}, source map written to {}",
+        output_path.display(),
+        source_map_path.display()
+    ))
+}
+
+// Arguments are only overridden when some were given
+fn run_settings(args: RunArgs) -> RunSettings {
+    RunSettings {
+        env: args.env,
+        args: (!args.args.is_empty()).then_some(args.args),
+        cwd: args.cwd,
     }
 }
 
@@ -48,13 +90,234 @@ fn handle_execute_command(
     execute_args: tanglit::cli::ExecuteArgs,
 ) -> Result<String, ExecutionError> {
     let doc = TanglitDoc::new_from_file(&execute_args.input.in_file)?;
-    let output = execution::execute(&doc, &execute_args.target_block)?;
+    let stdin = execute_args
+        .stdin
+        .as_ref()
+        .map(|path| {
+            fs::read_to_string(path).map_err(|e| {
+                ExecutionError::IOError(format!("Failed to read stdin file {}: {}", path, e))
+            })
+        })
+        .transpose()?;
+    println!("Output of block {}:", execute_args.target_block);
+    // The output is printed as the block runs, each stream to its own
+    let options = ExecutionOptions {
+        stdin,
+        keep_temp: execute_args.keep_temp,
+        sandbox: execute_args.sandbox,
+        cache: execute_args.cache,
+        run: run_settings(execute_args.run),
+    };
+    let output =
+        execution::execute_streaming(&doc, &execute_args.target_block, &options, &mut |chunk| {
+            match chunk.stream {
+                OutputStream::Stdout => {
+                    print!("{}", chunk.text);
+                    let _ = io::stdout().flush();
+                }
+                OutputStream::Stderr => {
+                    eprint!("{}", chunk.text);
+                    let _ = io::stderr().flush();
+                }
+            }
+        })?;
+    // The interpreter of a `session=` block doesn't outlive the command
+    execution::kill_all_sessions();
+    let mut message = format!("exit code: {}", output.status.unwrap_or(-1));
+    if let Some(signal) = output.signal {
+        message.push_str(&format!("\nkilled by signal {}", signal));
+    }
+    if output.timed_out {
+        message.push_str("\n⏱ Execution timed out, the output above is partial");
+    }
+    if output.output_truncated {
+        message.push_str("\nThe output exceeded its limit, the output above is cut");
+    }
+    if output.cached {
+        message.push_str(&format!(
+            "\nCached output of an identical execution, which ran in {} ms",
+            output.usage.wall_time_ms
+        ));
+    } else {
+        message.push_str(&format!("\nRan in {} ms", output.usage.wall_time_ms));
+    }
+    if let Some(cpu_time_ms) = output.usage.cpu_time_ms {
+        message.push_str(&format!(", {} ms of CPU time", cpu_time_ms));
+    }
+    if let Some(peak_memory_bytes) = output.usage.peak_memory_bytes {
+        message.push_str(&format!(
+            ", {:.1} MiB of peak memory",
+            peak_memory_bytes as f64 / (1024.0 * 1024.0)
+        ));
+    }
+    if output.sandbox != Sandbox::None {
+        message.push_str(&format!("\nRan in the {} sandbox", output.sandbox));
+    }
+    if let Some(work_dir) = output.work_dir.as_ref().filter(|_| options.keep_temp) {
+        message.push_str(&format!("\nScratch directory kept at {}", work_dir));
+    }
+    for artifact in &output.artifacts {
+        message.push_str(&format!(
+            "\nArtifact {} ({}, {} bytes)",
+            artifact.name, artifact.mime_type, artifact.size
+        ));
+        if let Some(path) = &artifact.path {
+            message.push_str(&format!(", saved to {}", path));
+        }
+    }
+    for diagnostic in &output.diagnostics {
+        message.push_str(&format!(
+            "\n{}:{}:{} {:?}: {}",
+            diagnostic.file,
+            diagnostic.line,
+            diagnostic
+                .column
+                .map_or(String::new(), |c| format!("{}:", c)),
+            diagnostic.severity,
+            diagnostic.message
+        ));
+    }
+    Ok(message)
+}
+
+fn handle_execute_all_command(args: ExecuteAllArgs) -> Result<String, ExecutionError> {
+    let doc = TanglitDoc::new_from_file(&args.input.in_file)?;
+    let options = ExecuteAllOptions {
+        tags: args.filter,
+        language: args.language,
+        execution: ExecutionOptions {
+            sandbox: args.sandbox,
+            cache: args.cache,
+            run: run_settings(args.run),
+            ..Default::default()
+        },
+        jobs: args.jobs,
+    };
+    // Blocks running at the same time would mix their output, so it's printed once they finish
+    let parallel = args.jobs > 1;
+    let runs = execution::execute_all_streaming(&doc, &options, &mut |event| match event {
+        NotebookEvent::Started(block) => println!("▶ {} (line {})", block.tag, block.start_line),
+        NotebookEvent::Output(_, chunk) if !parallel => match chunk.stream {
+            OutputStream::Stdout => {
+                print!("{}", chunk.text);
+                let _ = io::stdout().flush();
+            }
+            OutputStream::Stderr => {
+                eprint!("{}", chunk.text);
+                let _ = io::stderr().flush();
+            }
+        },
+        NotebookEvent::Output(..) => {}
+        NotebookEvent::Finished(run) => {
+            if let Some(output) = run.output().filter(|_| parallel) {
+                print!("{}", output.stdout);
+                eprint!("{}", output.stderr);
+            }
+            match &run.result {
+                BlockRunResult::Executed { output } if output.timed_out => {
+                    println!("✗ {} timed out", run.tag)
+                }
+                BlockRunResult::Executed { output } => match output.status {
+                    Some(0) if output.cached => println!("✓ {} (cached)", run.tag),
+                    Some(0) => println!("✓ {} in {} ms", run.tag, output.usage.wall_time_ms),
+                    Some(status) => println!("✗ {} exited with code {}", run.tag, status),
+                    None => println!("✗ {} was killed", run.tag),
+                },
+                BlockRunResult::Failed { error } => println!("✗ {}: {}", run.tag, error),
+                BlockRunResult::Skipped { dependency } => {
+                    println!("- {} skipped, {} didn't succeed", run.tag, dependency)
+                }
+            }
+        }
+    })?;
+    // The interpreters of `session=` blocks don't outlive the command
+    execution::kill_all_sessions();
+
+    let succeeded = runs.iter().filter(|run| run.succeeded()).count();
+    let mut message = format!("{} of {} blocks succeeded", succeeded, runs.len());
+    if args.write {
+        let markdown = doc.write_outputs(&runs)?;
+        write(&args.input.in_file, markdown)
+            .map_err(|e| WriteError(format!("Error writing to file: {}", e)))?;
+        message.push_str(&format!(", outputs written to {}", args.input.in_file));
+    }
+    Ok(message)
+}
+
+fn handle_test_command(args: TestArgs) -> Result<String, ExecutionError> {
+    let doc = TanglitDoc::new_from_file(&args.input.in_file)?;
+    let options = TestOptions {
+        run: ExecuteAllOptions {
+            tags: args.filter,
+            language: args.language,
+            execution: ExecutionOptions {
+                sandbox: args.sandbox,
+                run: run_settings(args.run),
+                ..Default::default()
+            },
+            jobs: args.jobs,
+        },
+        normalization: Normalization {
+            trim_whitespace: args.trim_whitespace,
+            ignore: args.ignore,
+        },
+    };
+    let tests = execution::run_tests_streaming(&doc, &options, &mut |test| match &test.result {
+        TestResult::Passed => println!("✓ {} (line {})", test.tag, test.line),
+        TestResult::Failed { mismatches } => {
+            println!("✗ {} (line {})", test.tag, test.line);
+            for mismatch in mismatches {
+                println!(
+                    "  {} differs from the output recorded at line {}:",
+                    mismatch.compared, test.output_line
+                );
+                let difference = match mismatch.compared {
+                    Compared::ExitCode => {
+                        format!("-{}\n+{}", mismatch.expected, mismatch.actual)
+                    }
+                    _ => execution::diff(&mismatch.expected, &mismatch.actual),
+                };
+                for line in difference.lines() {
+                    println!("    {}", line);
+                }
+            }
+        }
+        TestResult::Error { error } => println!("✗ {} (line {}): {}", test.tag, test.line, error),
+        TestResult::Skipped { dependency } => {
+            println!("- {} skipped, {} didn't succeed", test.tag, dependency)
+        }
+    })?;
+    // The interpreters of `session=` blocks don't outlive the command
+    execution::kill_all_sessions();
+
+    if let Some(path) = &args.junit {
+        write(path, execution::junit_report(&args.input.in_file, &tests))
+            .map_err(|e| WriteError(format!("Error writing to file: {}", e)))?;
+    }
+    if let Some(path) = &args.json {
+        write(path, execution::json_report(&args.input.in_file, &tests)?)
+            .map_err(|e| WriteError(format!("Error writing to file: {}", e)))?;
+    }
+
+    let summary = TestSummary::of(&tests);
+    let message = format!(
+        "{} passed, {} failed, {} errors, {} skipped",
+        summary.passed, summary.failed, summary.errors, summary.skipped
+    );
+    if summary.is_success() {
+        Ok(message)
+    } else {
+        Err(ExecutionError::TestFailure(message))
+    }
+}
+
+fn handle_cache_prune_command(args: CachePruneArgs) -> Result<String, ExecutionError> {
+    let stats = execution::prune_cache(args.older_than)?;
     Ok(format!(
-        "Output of block {}:\n{}\nstderr: {}\nexit code: {}",
-        execute_args.target_block,
-        output.stdout,
-        output.stderr,
-        output.status.unwrap_or(-1)
+        "✅ {} cached outputs removed from {}, {:.1} MiB freed",
+        stats.removed,
+        execution::cache_dir().display(),
+        stats.freed_bytes as f64 / (1024.0 * 1024.0)
     ))
 }
 
@@ -96,10 +359,41 @@ fn handle_generate_pdf_command(
 fn handle_tangle_all_command(tangle_all_command: TangleAllArgs) -> Result<String, ExecutionError> {
     let input_file_path = &tangle_all_command.input.in_file;
     let doc = TanglitDoc::new_from_file(input_file_path)?;
-    let blocks_processed = doc.generate_code_files(&tangle_all_command.output.out_dir)?;
-    Ok(format!(
+    let exported_files = doc.generate_code_files(
+        &tangle_all_command.output.out_dir,
+        tangle_all_command.line_directives.enabled,
+        tangle_all_command.chunk_markers.enabled,
+    )?;
+    let blocks_processed: usize = exported_files.iter().map(|f| f.blocks.len()).sum();
+    let mut message = format!(
         "✅ {} blocks tangled to {}",
         blocks_processed, tangle_all_command.output.out_dir
+    );
+    for exported_file in exported_files {
+        message.push_str(&format!(
+            "\n  {}: {}",
+            exported_file.path.display(),
+            exported_file.blocks.join(", ")
+        ));
+    }
+    Ok(message)
+}
+
+fn handle_untangle_command(untangle_args: UntangleArgs) -> Result<String, ExecutionError> {
+    let input_file_path = &untangle_args.input.in_file;
+    let doc = TanglitDoc::new_from_file(input_file_path)?;
+    let edits = doc.untangle_code_files(&untangle_args.output.out_dir)?;
+    if edits.is_empty() {
+        return Ok("✅ No changes to untangle".to_string());
+    }
+
+    let markdown = fs::read_to_string(input_file_path)?;
+    write(input_file_path, apply_edits(&markdown, &edits))
+        .map_err(|e| WriteError(format!("Error writing to file: {}", e)))?;
+    Ok(format!(
+        "✅ {} blocks updated in {}",
+        edits.len(),
+        input_file_path
     ))
 }
 
@@ -144,14 +438,21 @@ fn main() {
     let result = match cli.command {
         Commands::Tangle(args) => handle_tangle_command(args),
         Commands::Execute(args) => handle_execute_command(args),
+        Commands::ExecuteAll(args) => handle_execute_all_command(args),
+        Commands::Test(args) => handle_test_command(args),
+        Commands::CachePrune(args) => handle_cache_prune_command(args),
         Commands::GeneratePDF(args) => handle_generate_pdf_command(args),
         Commands::GenerateHTML(args) => handle_generate_html_command(args),
         Commands::TangleAll(args) => handle_tangle_all_command(args),
+        Commands::Untangle(args) => handle_untangle_command(args),
         Commands::GenerateSlidesMd(args) => handle_generate_md_slides(args),
         Commands::GenerateSlidesPdf(args) => handle_generate_slides_pdf(args),
     };
     match result {
         Ok(message) => println!("{}", message),
-        Err(e) => eprintln!("Error: {}", e),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
     }
 }
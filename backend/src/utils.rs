@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 const DEFAULT_INDENT_SIZE: usize = 4;
 const DEFAULT_INDENT_CHARACTER: char = ' ';
 
@@ -58,4 +60,68 @@ pub fn get_indentation_at_offset(code: &str, offset: usize) -> usize {
     line.len()
 }
 
+/// Units accepted by `parse_duration`, with their length in milliseconds.
+/// A number without unit is in seconds.
+pub const DURATION_UNITS: [(&str, u64); 5] = [
+    ("ms", 1),
+    ("s", 1000),
+    ("m", 60 * 1000),
+    ("h", 3600 * 1000),
+    ("d", 24 * 3600 * 1000),
+];
+
+/// Parses a duration like `500ms`, `5s`, `2m`, `1h` or `7d`, see `DURATION_UNITS`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let unit_start = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let value: u64 = text[..unit_start].parse().ok()?;
+    let unit = match &text[unit_start..] {
+        "" => "s",
+        unit => unit,
+    };
+    let (_, millis) = DURATION_UNITS.iter().find(|(name, _)| *name == unit)?;
+    // Values too large for their unit are invalid rather than wrapped around
+    value.checked_mul(*millis).map(Duration::from_millis)
+}
+
+/// Parses a size like `512`, `64K`, `256M` or `2G`, in bytes. Units are powers of 1024.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let unit_start = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let value: u64 = text[..unit_start].parse().ok()?;
+    let multiplier: u64 = match text[unit_start..].to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    value.checked_mul(multiplier)
+}
+
 // TODO: This file could be moved under the render module
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("5"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("5x"), None);
+        assert_eq!(parse_duration(&format!("{}m", u64::MAX)), None);
+        assert_eq!(parse_duration(&format!("{}h", u64::MAX / 60)), None);
+        assert_eq!(
+            parse_duration("7d"),
+            Some(Duration::from_secs(7 * 24 * 3600))
+        );
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX / 3600)), None);
+    }
+}
@@ -1,6 +1,16 @@
 use tanglit::configuration::init_configuration;
 use tanglit::doc::{CodeBlock, Edit, SlideByIndex, TanglitDoc};
-use tanglit::execution::ExecutionOutput;
+use tanglit::execution::{ExecutionOptions, ExecutionOutput, OutputChunk, SessionKey};
+use tauri::{AppHandle, Emitter, RunEvent};
+
+// Event carrying the output of a block while it runs
+const EXECUTION_OUTPUT_EVENT: &str = "tanglit://execution-output";
+
+#[derive(Clone, serde::Serialize)]
+struct ExecutionOutputEvent {
+    block_name: String,
+    chunk: OutputChunk,
+}
 
 #[tauri::command(rename_all = "snake_case")]
 fn tanglit_exclude(raw_markdown: &str) -> Result<String, String> {
@@ -28,13 +38,82 @@ fn tanglit_parse_blocks(raw_markdown: &str) -> Result<Vec<CodeBlock>, String> {
     Ok(blocks)
 }
 
-#[tauri::command(rename_all = "snake_case")]
-fn tanglit_execute_block(raw_markdown: &str, block_name: &str) -> Result<ExecutionOutput, String> {
-    let doc = TanglitDoc::new_from_string(raw_markdown)
+// Parses the document open in the editor, resolving it from `file_path` if it was
+// opened from a file, so its blocks share sessions and find their `stdin=` files
+fn open_document(raw_markdown: &str, file_path: Option<&str>) -> Result<TanglitDoc, String> {
+    let mut doc = TanglitDoc::new_from_string(raw_markdown)
         .map_err(|e| format!("Error creating TanglitDoc: {}", e))?;
+    if let Some(file_path) = file_path {
+        doc.set_file_path(file_path);
+    }
+    Ok(doc)
+}
+
+#[tauri::command(rename_all = "snake_case")]
+fn tanglit_execute_block(
+    raw_markdown: &str,
+    block_name: &str,
+    stdin: Option<&str>,
+    file_path: Option<&str>,
+    cache: Option<bool>,
+) -> Result<ExecutionOutput, String> {
+    let doc = open_document(raw_markdown, file_path)?;
+
+    let options = ExecutionOptions {
+        stdin: stdin.map(str::to_string),
+        cache: cache.unwrap_or(false),
+        ..Default::default()
+    };
+    tanglit::execution::execute(&doc, block_name, &options)
+        .map_err(|e| format!("Error executing block: {}", e))
+}
+
+/// Executes the block on a blocking thread, emitting its output with `EXECUTION_OUTPUT_EVENT`
+/// while it runs.
+#[tauri::command(rename_all = "snake_case")]
+async fn tanglit_execute_block_streaming(
+    app: AppHandle,
+    raw_markdown: String,
+    block_name: String,
+    stdin: Option<String>,
+    file_path: Option<String>,
+    cache: Option<bool>,
+) -> Result<ExecutionOutput, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let doc = open_document(&raw_markdown, file_path.as_deref())?;
 
-    tanglit::execution::execute(&doc, block_name)
+        let options = ExecutionOptions {
+            stdin,
+            cache: cache.unwrap_or(false),
+            ..Default::default()
+        };
+        tanglit::execution::execute_streaming(&doc, &block_name, &options, &mut |chunk| {
+            let event = ExecutionOutputEvent {
+                block_name: block_name.clone(),
+                chunk,
+            };
+            // A window that closed in the meantime doesn't stop the execution
+            let _ = app.emit(EXECUTION_OUTPUT_EVENT, event);
+        })
         .map_err(|e| format!("Error executing block: {}", e))
+    })
+    .await
+    .map_err(|e| format!("Error executing block: {}", e))?
+}
+
+#[tauri::command(rename_all = "snake_case")]
+fn tanglit_restart_session(
+    file_path: Option<&str>,
+    language: &str,
+    session: &str,
+) -> Result<(), String> {
+    tanglit::execution::restart_session(SessionKey::new(file_path, language, session))
+        .map_err(|e| format!("Error restarting session: {}", e))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+fn tanglit_kill_session(file_path: Option<&str>, language: &str, session: &str) -> bool {
+    tanglit::execution::kill_session(&SessionKey::new(file_path, language, session))
 }
 
 #[tauri::command(rename_all = "snake_case")]
@@ -104,8 +183,10 @@ fn tanglit_preview_slides(
 fn tanglit_tangle(raw_markdown: &str, output_path: &str) -> Result<usize, String> {
     let doc = TanglitDoc::new_from_string(raw_markdown)
         .map_err(|e| format!("Error creating TanglitDoc: {}", e))?;
-    doc.generate_code_files(output_path)
-        .map_err(|e| format!("Error tangling code: {}", e))
+    let exported_files = doc
+        .generate_code_files(output_path, false, false)
+        .map_err(|e| format!("Error tangling code: {}", e))?;
+    Ok(exported_files.iter().map(|f| f.blocks.len()).sum())
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -121,14 +202,23 @@ pub fn run() {
             tanglit_parse_slides,
             tanglit_parse_blocks,
             tanglit_execute_block,
+            tanglit_execute_block_streaming,
             tanglit_format_output,
             tanglit_gen_slides,
             tanglit_preview_html,
             tanglit_preview_slides,
             tanglit_save_pdf,
             tanglit_save_slides_pdf,
-            tanglit_tangle
+            tanglit_tangle,
+            tanglit_restart_session,
+            tanglit_kill_session
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|_app, event| {
+            // The interpreters of the sessions don't outlive the app
+            if let RunEvent::Exit = event {
+                tanglit::execution::kill_all_sessions();
+            }
+        });
 }
@@ -1,9 +1,47 @@
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 
 export type ExecutionOutput = {
   stdout: string;
   stderr: string;
   status: number;
+  diagnostics: Diagnostic[];
+  timed_out: boolean;
+  work_dir: string | null;
+  sandbox: "none" | "bubblewrap";
+  output_truncated: boolean;
+  signal: number | null;
+  usage: ResourceUsage;
+  cached: boolean;
+  artifacts: Artifact[];
+};
+
+export type Artifact = {
+  name: string;
+  mime_type: string;
+  size: number;
+  data: string | null;
+  path: string | null;
+};
+
+export type ResourceUsage = {
+  wall_time_ms: number;
+  cpu_time_ms: number | null;
+  peak_memory_bytes: number | null;
+};
+
+export type OutputChunk = {
+  stream: "stdout" | "stderr";
+  text: string;
+  elapsed_ms: number;
+};
+
+export type Diagnostic = {
+  file: string;
+  line: number;
+  column: number | null;
+  message: string;
+  severity: "error" | "warning" | "note";
 };
 
 export type BlockExecute = {
@@ -23,6 +61,7 @@ enum TANGLIT_COMMANDS {
   parse_slides = "tanglit_parse_slides",
   parse_blocks = "tanglit_parse_blocks",
   execute = "tanglit_execute_block",
+  execute_streaming = "tanglit_execute_block_streaming",
   format_output = "tanglit_format_output",
   gen_slides = "tanglit_gen_slides",
   preview_html = "tanglit_preview_html",
@@ -30,6 +69,8 @@ enum TANGLIT_COMMANDS {
   save_pdf = "tanglit_save_pdf",
   save_slides_pdf = "tanglit_save_slides_pdf",
   tangle = "tanglit_tangle",
+  restart_session = "tanglit_restart_session",
+  kill_session = "tanglit_kill_session",
 }
 
 export async function exclude(raw_markdown: string): Promise<string> {
@@ -53,12 +94,49 @@ export async function parse_blocks(raw_markdown: string) {
   return rv;
 }
 
-export async function execute_block(raw_markdown: string, block_name: string): Promise<BlockExecute> {
+export async function execute_block(
+  raw_markdown: string,
+  block_name: string,
+  stdin?: string,
+  file_path?: string,
+  cache?: boolean
+): Promise<BlockExecute> {
+  try {
+    const r = await invoke(TANGLIT_COMMANDS.execute, { raw_markdown, block_name, stdin, file_path, cache });
+    return { output: r as ExecutionOutput };
+  } catch (e) {
+    return { error: e };
+  }
+}
+
+const EXECUTION_OUTPUT_EVENT = "tanglit://execution-output";
+
+export async function execute_block_streaming(
+  raw_markdown: string,
+  block_name: string,
+  on_output: (chunk: OutputChunk) => void,
+  stdin?: string,
+  file_path?: string,
+  cache?: boolean
+): Promise<BlockExecute> {
+  const unlisten = await listen<{ block_name: string; chunk: OutputChunk }>(EXECUTION_OUTPUT_EVENT, (event) => {
+    if (event.payload.block_name === block_name) {
+      on_output(event.payload.chunk);
+    }
+  });
   try {
-    const r = await invoke(TANGLIT_COMMANDS.execute, { raw_markdown, block_name });
+    const r = await invoke(TANGLIT_COMMANDS.execute_streaming, {
+      raw_markdown,
+      block_name,
+      stdin,
+      file_path,
+      cache,
+    });
     return { output: r as ExecutionOutput };
   } catch (e) {
     return { error: e };
+  } finally {
+    unlisten();
   }
 }
 
@@ -95,3 +173,12 @@ export async function save_slides_pdf(raw_markdown: string, theme: string, code_
 export async function tangle(raw_markdown: string, output_dir: string) {
   return (await invoke(TANGLIT_COMMANDS.tangle, { raw_markdown, output_path: output_dir })) as number;
 }
+
+// Starts the interpreter of a session over, dropping the state of the blocks that ran in it
+export async function restart_session(language: string, session: string, file_path?: string) {
+  await invoke(TANGLIT_COMMANDS.restart_session, { file_path, language, session });
+}
+
+export async function kill_session(language: string, session: string, file_path?: string) {
+  return (await invoke(TANGLIT_COMMANDS.kill_session, { file_path, language, session })) as boolean;
+}
@@ -1,8 +1,11 @@
 use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi::{Env, JsFunction, Task};
 use napi_derive::napi;
 use tanglit::configuration::init_configuration as tanglit_init_configuration;
 use tanglit::doc::TanglitDoc;
 use tanglit::execution::ExecutionOutput as TanglitExecutionOutput;
+use tanglit::execution::{ExecutionOptions, OutputStream, SessionKey, Severity};
 
 #[napi(object)]
 pub struct CodeBlock {
@@ -13,6 +16,8 @@ pub struct CodeBlock {
     pub export: Option<String>,
     pub start_line: u32,
     pub end_line: u32,
+    pub heading_path: Vec<String>,
+    pub session: Option<String>,
 }
 
 #[napi(object)]
@@ -26,6 +31,48 @@ pub struct ExecutionOutput {
     pub stdout: String,
     pub stderr: String,
     pub status: Option<i32>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub timed_out: bool,
+    pub work_dir: Option<String>,
+    /// Either `none` or `bubblewrap`.
+    pub sandbox: String,
+    pub output_truncated: bool,
+    pub signal: Option<i32>,
+    pub usage: ResourceUsage,
+    /// Whether the output was taken from the cache instead of running the block.
+    pub cached: bool,
+    pub artifacts: Vec<Artifact>,
+}
+
+#[napi(object)]
+#[derive(Clone)]
+pub struct Artifact {
+    pub name: String,
+    pub mime_type: String,
+    pub size: i64,
+    /// Content of the file encoded in base64, unless it was too large.
+    pub data: Option<String>,
+    /// Path of the copy of a file too large to embed, relative to the directory of the document.
+    pub path: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Clone)]
+pub struct ResourceUsage {
+    pub wall_time_ms: u32,
+    pub cpu_time_ms: Option<u32>,
+    pub peak_memory_bytes: Option<i64>,
+}
+
+#[napi(object)]
+#[derive(Clone)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: u32,
+    pub column: Option<u32>,
+    pub message: String,
+    /// One of `error`, `warning` or `note`.
+    pub severity: String,
 }
 
 #[napi(object)]
@@ -58,6 +105,8 @@ pub fn parse_blocks(raw_markdown: String) -> Result<Vec<CodeBlock>> {
             export: b.export.clone(),
             start_line: b.start_line as u32,
             end_line: b.end_line as u32,
+            heading_path: b.heading_path.clone(),
+            session: b.session.clone(),
         })
         .collect();
     Ok(blocks)
@@ -81,17 +130,176 @@ pub fn parse_slides(raw_markdown: String) -> Result<Vec<SlideByIndex>> {
     Ok(slides)
 }
 
-#[napi]
-pub fn execute_block(raw_markdown: String, block_name: String) -> Result<ExecutionOutput> {
-    let doc = TanglitDoc::new_from_string(&raw_markdown)
+/// Parses a document, resolving it from `file_path` if it's in a file, so its blocks
+/// share sessions and find their `stdin=` files.
+fn load_document(raw_markdown: &str, file_path: Option<&str>) -> Result<TanglitDoc> {
+    let mut doc = TanglitDoc::new_from_string(raw_markdown)
         .map_err(|e| Error::from_reason(format!("Parse error: {}", e)))?;
-    let output = tanglit::execution::execute(&doc, &block_name)
+    if let Some(file_path) = file_path {
+        doc.set_file_path(file_path);
+    }
+    Ok(doc)
+}
+
+#[napi]
+pub fn execute_block(
+    raw_markdown: String,
+    block_name: String,
+    stdin: Option<String>,
+    file_path: Option<String>,
+    cache: Option<bool>,
+) -> Result<ExecutionOutput> {
+    let doc = load_document(&raw_markdown, file_path.as_deref())?;
+    let options = ExecutionOptions {
+        stdin,
+        cache: cache.unwrap_or(false),
+        ..Default::default()
+    };
+    let output = tanglit::execution::execute(&doc, &block_name, &options)
         .map_err(|e| Error::from_reason(format!("Execution error: {}", e)))?;
-    Ok(ExecutionOutput {
+    Ok(to_js_output(output))
+}
+
+#[napi(object)]
+pub struct OutputChunk {
+    /// Either `stdout` or `stderr`.
+    pub stream: String,
+    pub text: String,
+    /// Milliseconds since the execution started.
+    pub elapsed_ms: u32,
+}
+
+pub struct ExecuteBlockTask {
+    raw_markdown: String,
+    block_name: String,
+    stdin: Option<String>,
+    file_path: Option<String>,
+    cache: bool,
+    on_output: ThreadsafeFunction<OutputChunk, ErrorStrategy::Fatal>,
+}
+
+impl Task for ExecuteBlockTask {
+    type Output = TanglitExecutionOutput;
+    type JsValue = ExecutionOutput;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let doc = load_document(&self.raw_markdown, self.file_path.as_deref())?;
+        let options = ExecutionOptions {
+            stdin: self.stdin.clone(),
+            cache: self.cache,
+            ..Default::default()
+        };
+        tanglit::execution::execute_streaming(
+            &doc,
+            &self.block_name,
+            &options,
+            &mut |chunk| {
+                let chunk = OutputChunk {
+                    stream: match chunk.stream {
+                        OutputStream::Stdout => "stdout",
+                        OutputStream::Stderr => "stderr",
+                    }
+                    .to_string(),
+                    text: chunk.text,
+                    elapsed_ms: chunk.elapsed_ms as u32,
+                };
+                self.on_output
+                    .call(chunk, ThreadsafeFunctionCallMode::NonBlocking);
+            },
+        )
+        .map_err(|e| Error::from_reason(format!("Execution error: {}", e)))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        Ok(to_js_output(output))
+    }
+}
+
+/// Executes the block in the background, calling `on_output` with its output as it's printed.
+/// Resolves to the whole output once the execution finishes.
+#[napi(ts_return_type = "Promise<ExecutionOutput>")]
+pub fn execute_block_streaming(
+    raw_markdown: String,
+    block_name: String,
+    #[napi(ts_arg_type = "(chunk: OutputChunk) => void")] on_output: JsFunction,
+    stdin: Option<String>,
+    file_path: Option<String>,
+    cache: Option<bool>,
+) -> Result<AsyncTask<ExecuteBlockTask>> {
+    let on_output = on_output.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+    Ok(AsyncTask::new(ExecuteBlockTask {
+        raw_markdown,
+        block_name,
+        stdin,
+        file_path,
+        cache: cache.unwrap_or(false),
+        on_output,
+    }))
+}
+
+/// Starts the interpreter of the session `name` of `language` over, dropping its state.
+/// `file_path` is the markdown file of the session, as passed to `execute_block`.
+#[napi]
+pub fn restart_session(file_path: Option<String>, language: String, name: String) -> Result<()> {
+    tanglit::execution::restart_session(SessionKey::new(file_path.as_deref(), &language, &name))
+        .map_err(|e| Error::from_reason(format!("Execution error: {}", e)))
+}
+
+/// Kills the interpreter of a session, returning whether it was running.
+#[napi]
+pub fn kill_session(file_path: Option<String>, language: String, name: String) -> bool {
+    tanglit::execution::kill_session(&SessionKey::new(file_path.as_deref(), &language, &name))
+}
+
+#[napi]
+pub fn kill_all_sessions() {
+    tanglit::execution::kill_all_sessions()
+}
+
+fn to_js_output(output: TanglitExecutionOutput) -> ExecutionOutput {
+    ExecutionOutput {
         stdout: output.stdout,
         stderr: output.stderr,
         status: output.status,
-    })
+        diagnostics: output
+            .diagnostics
+            .into_iter()
+            .map(|d| Diagnostic {
+                file: d.file,
+                line: d.line as u32,
+                column: d.column.map(|c| c as u32),
+                message: d.message,
+                severity: match d.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                    Severity::Note => "note",
+                }
+                .to_string(),
+            })
+            .collect(),
+        timed_out: output.timed_out,
+        work_dir: output.work_dir,
+        sandbox: output.sandbox.to_string(),
+        output_truncated: output.output_truncated,
+        signal: output.signal,
+        usage: ResourceUsage {
+            wall_time_ms: output.usage.wall_time_ms as u32,
+            cpu_time_ms: output.usage.cpu_time_ms.map(|ms| ms as u32),
+            peak_memory_bytes: output.usage.peak_memory_bytes.map(|bytes| bytes as i64),
+        },
+        cached: output.cached,
+        artifacts: output
+            .artifacts
+            .into_iter()
+            .map(|a| Artifact {
+                name: a.name,
+                mime_type: a.mime_type,
+                size: a.size as i64,
+                data: a.data,
+                path: a.path,
+            })
+            .collect(),
+    }
 }
 
 #[napi]
@@ -106,6 +314,8 @@ pub fn format_output(
         stdout: output.stdout,
         stderr: output.stderr,
         status: output.status,
+        timed_out: output.timed_out,
+        ..Default::default()
     };
     let edit = doc
         .format_output(&block_name, &tanglit_output)
@@ -141,9 +351,10 @@ pub fn preview_slides(
 pub fn tangle(raw_markdown: String, output_path: String) -> Result<u32> {
     let doc = TanglitDoc::new_from_string(&raw_markdown)
         .map_err(|e| Error::from_reason(format!("Parse error: {}", e)))?;
-    let count = doc
-        .generate_code_files(&output_path)
+    let exported_files = doc
+        .generate_code_files(&output_path, false, false)
         .map_err(|e| Error::from_reason(format!("Tangle error: {}", e)))?;
+    let count: usize = exported_files.iter().map(|f| f.blocks.len()).sum();
     Ok(count as u32)
 }
 
@@ -12,19 +12,31 @@ export function registerCommands(
   htmlPreview: HtmlPreviewManager,
   executionResults: ExecutionResultsManager
 ): void {
-  // Run a code block
+  // Run a code block, reusing the cached output of an identical execution if the
+  // tanglit.cacheOutputs setting is on, unless rerun is set
   context.subscriptions.push(
     vscode.commands.registerCommand(
       "tanglit.runBlock",
-      async (blockTag: string) => {
+      async (blockTag: string, rerun?: boolean) => {
         const editor = vscode.window.activeTextEditor;
         if (!editor) return;
 
         const text = editor.document.getText();
+        const cache =
+          !rerun &&
+          vscode.workspace.getConfiguration("tanglit").get<boolean>("cacheOutputs", false);
         try {
-          const output = tanglit.executeBlock(text, blockTag);
+          executionResults.startStreaming(blockTag);
+          const output = await tanglit.executeBlockStreaming(
+            text,
+            blockTag,
+            (chunk) => executionResults.appendChunk(chunk),
+            undefined,
+            documentPath(editor.document),
+            cache
+          );
           const blocks = tanglit.parseBlocks(text);
-          executionResults.showResult(editor, blockTag, blocks, output);
+          executionResults.showResult(editor, blockTag, blocks, output, true);
           codeLensProvider.setExecutionResult(
             blockTag,
             output,
@@ -39,12 +51,12 @@ export function registerCommands(
     )
   );
 
-  // Re-run a block
+  // Re-run a block, without taking its output from the cache
   context.subscriptions.push(
     vscode.commands.registerCommand(
       "tanglit.rerunBlock",
       async (blockTag: string) => {
-        await vscode.commands.executeCommand("tanglit.runBlock", blockTag);
+        await vscode.commands.executeCommand("tanglit.runBlock", blockTag, true);
       }
     )
   );
@@ -84,6 +96,52 @@ export function registerCommands(
     )
   );
 
+  // Restart the interpreter of a session, dropping its state
+  context.subscriptions.push(
+    vscode.commands.registerCommand("tanglit.restartSession", async () => {
+      const editor = vscode.window.activeTextEditor;
+      if (!editor) return;
+
+      const session = await pickSession(editor.document);
+      if (!session) return;
+      try {
+        tanglit.restartSession(
+          documentPath(editor.document),
+          session.language,
+          session.name
+        );
+        vscode.window.showInformationMessage(
+          `Tanglit: Restarted session "${session.name}" (${session.language})`
+        );
+      } catch (e) {
+        vscode.window.showErrorMessage(
+          `Tanglit: Error restarting session: ${e}`
+        );
+      }
+    })
+  );
+
+  // Kill the interpreter of a session
+  context.subscriptions.push(
+    vscode.commands.registerCommand("tanglit.killSession", async () => {
+      const editor = vscode.window.activeTextEditor;
+      if (!editor) return;
+
+      const session = await pickSession(editor.document);
+      if (!session) return;
+      const killed = tanglit.killSession(
+        documentPath(editor.document),
+        session.language,
+        session.name
+      );
+      vscode.window.showInformationMessage(
+        killed
+          ? `Tanglit: Killed session "${session.name}" (${session.language})`
+          : `Tanglit: Session "${session.name}" (${session.language}) is not running`
+      );
+    })
+  );
+
   // Preview slides
   context.subscriptions.push(
     vscode.commands.registerCommand("tanglit.previewSlides", () => {
@@ -341,3 +399,39 @@ async function exportSlidesHtml(): Promise<void> {
   );
 }
 
+
+// Path of the markdown file of the document, which keeps its sessions apart from
+// those of other documents
+function documentPath(document: vscode.TextDocument): string | undefined {
+  return document.uri.scheme === "file" ? document.uri.fsPath : undefined;
+}
+
+// Asks for one of the sessions declared in the document with `session=`
+async function pickSession(
+  document: vscode.TextDocument
+): Promise<{ language: string; name: string } | undefined> {
+  const sessions = new Map<string, { language: string; name: string }>();
+  for (const block of tanglit.parseBlocks(document.getText())) {
+    if (block.session && block.language) {
+      sessions.set(`${block.language}/${block.session}`, {
+        language: block.language,
+        name: block.session,
+      });
+    }
+  }
+  if (sessions.size === 0) {
+    vscode.window.showWarningMessage(
+      "Tanglit: No block of this document runs in a session"
+    );
+    return undefined;
+  }
+  const picked = await vscode.window.showQuickPick(
+    [...sessions.values()].map((session) => ({
+      label: session.name,
+      description: session.language,
+      session,
+    })),
+    { placeHolder: "Select session" }
+  );
+  return picked?.session;
+}
@@ -16,17 +16,30 @@ export class ExecutionResultsManager {
     string,
     { endLine: number; output: tanglit.ExecutionOutput }
   > = new Map();
+  // Whether the last streamed chunk left its line unfinished
+  private streamedPartialLine = false;
 
   constructor() {
     this.outputChannel =
       vscode.window.createOutputChannel("Tanglit");
   }
 
+  startStreaming(blockTag: string): void {
+    this.outputChannel.show(true);
+    this.outputChannel.appendLine(`[${blockTag}] running...`);
+  }
+
+  appendChunk(chunk: tanglit.OutputChunk): void {
+    this.outputChannel.append(chunk.text);
+    this.streamedPartialLine = !chunk.text.endsWith("\n");
+  }
+
   showResult(
     editor: vscode.TextEditor,
     blockTag: string,
     blocks: tanglit.CodeBlock[],
-    output: tanglit.ExecutionOutput
+    output: tanglit.ExecutionOutput,
+    streamed = false
   ): void {
     const block = blocks.find((b) => b.tag === blockTag);
     if (!block) return;
@@ -36,14 +49,37 @@ export class ExecutionResultsManager {
       output,
     });
 
-    // Write to output channel
-    this.outputChannel.appendLine(`[${blockTag}] stdout: ${output.stdout}`);
-    if (output.stderr) {
-      this.outputChannel.appendLine(`[${blockTag}] stderr: ${output.stderr}`);
+    // Write to output channel, unless it was already written while streaming
+    if (streamed && this.streamedPartialLine) {
+      this.outputChannel.appendLine("");
+      this.streamedPartialLine = false;
     }
+    if (!streamed) {
+      this.outputChannel.appendLine(`[${blockTag}] stdout: ${output.stdout}`);
+      if (output.stderr) {
+        this.outputChannel.appendLine(`[${blockTag}] stderr: ${output.stderr}`);
+      }
+    }
+    this.outputChannel.appendLine(
+      output.timedOut
+        ? `[${blockTag}] timed out, the output is partial`
+        : output.outputTruncated
+          ? `[${blockTag}] printed too much, the output is cut`
+          : output.signal !== null
+            ? `[${blockTag}] killed by signal ${output.signal}`
+            : `[${blockTag}] exit code: ${output.status ?? "unknown"}`
+    );
     this.outputChannel.appendLine(
-      `[${blockTag}] exit code: ${output.status ?? "unknown"}`
+      output.cached
+        ? `[${blockTag}] cached output of an identical run, which took ${output.usage.wallTimeMs} ms`
+        : `[${blockTag}] ran in ${output.usage.wallTimeMs} ms`
     );
+    for (const artifact of output.artifacts) {
+      this.outputChannel.appendLine(
+        `[${blockTag}] artifact ${artifact.name} (${artifact.mimeType}, ${artifact.size} bytes)` +
+          (artifact.path ? `, saved to ${artifact.path}` : "")
+      );
+    }
     this.outputChannel.appendLine("---");
 
     this.updateDecorations(editor);
@@ -56,8 +92,9 @@ export class ExecutionResultsManager {
       const line = endLine - 1; // VS Code 0-based
       if (line < 0 || line >= editor.document.lineCount) continue;
 
-      const summary =
-        output.status === 0
+      const summary = output.timedOut
+        ? `Timed out: ${output.stdout.split("\n")[0] || "(no output)"}`
+        : output.status === 0
           ? output.stdout.split("\n")[0] || "(no output)"
           : `Error (exit ${output.status}): ${output.stderr.split("\n")[0]}`;
 
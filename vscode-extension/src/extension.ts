@@ -80,4 +80,6 @@ function updateStatusBar(editor: vscode.TextEditor | undefined) {
   statusBarItem.show();
 }
 
-export function deactivate() {}
+export function deactivate() {
+  tanglit.killAllSessions();
+}
@@ -37,6 +37,8 @@ export interface CodeBlock {
   export: string | null;
   startLine: number;
   endLine: number;
+  headingPath: string[];
+  session: string | null;
 }
 
 export interface SlideByIndex {
@@ -47,6 +49,43 @@ export interface ExecutionOutput {
   stdout: string;
   stderr: string;
   status: number | null;
+  diagnostics: Diagnostic[];
+  timedOut: boolean;
+  workDir: string | null;
+  sandbox: "none" | "bubblewrap";
+  outputTruncated: boolean;
+  signal: number | null;
+  usage: ResourceUsage;
+  cached: boolean;
+  artifacts: Artifact[];
+}
+
+export interface Artifact {
+  name: string;
+  mimeType: string;
+  size: number;
+  data: string | null;
+  path: string | null;
+}
+
+export interface ResourceUsage {
+  wallTimeMs: number;
+  cpuTimeMs: number | null;
+  peakMemoryBytes: number | null;
+}
+
+export interface OutputChunk {
+  stream: "stdout" | "stderr";
+  text: string;
+  elapsedMs: number;
+}
+
+export interface Diagnostic {
+  file: string;
+  line: number;
+  column: number | null;
+  message: string;
+  severity: "error" | "warning" | "note";
 }
 
 export interface Edit {
@@ -69,9 +108,50 @@ export function parseSlides(rawMarkdown: string): SlideByIndex[] {
 
 export function executeBlock(
   rawMarkdown: string,
-  blockName: string
+  blockName: string,
+  stdin?: string,
+  filePath?: string,
+  cache?: boolean
 ): ExecutionOutput {
-  return native.executeBlock(rawMarkdown, blockName);
+  return native.executeBlock(rawMarkdown, blockName, stdin, filePath, cache);
+}
+
+export function executeBlockStreaming(
+  rawMarkdown: string,
+  blockName: string,
+  onOutput: (chunk: OutputChunk) => void,
+  stdin?: string,
+  filePath?: string,
+  cache?: boolean
+): Promise<ExecutionOutput> {
+  return native.executeBlockStreaming(
+    rawMarkdown,
+    blockName,
+    onOutput,
+    stdin,
+    filePath,
+    cache
+  );
+}
+
+export function restartSession(
+  filePath: string | undefined,
+  language: string,
+  name: string
+): void {
+  native.restartSession(filePath, language, name);
+}
+
+export function killSession(
+  filePath: string | undefined,
+  language: string,
+  name: string
+): boolean {
+  return native.killSession(filePath, language, name);
+}
+
+export function killAllSessions(): void {
+  native.killAllSessions();
 }
 
 export function formatOutput(